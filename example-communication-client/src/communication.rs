@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::path::Path;
 use std::sync::Arc;
 use notify::{Event, EventKind};
@@ -6,15 +6,36 @@ use slint::{spawn_local, ComponentHandle};
 use tokio::select;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Notify;
-use example_communication_common::{connect_to_server_loop, reconnect, CommandType, ConnectionInfo, ConnectionType, ControlMessage, ControlTypes, Destination, FileDefinition, FileTransferClient, Sender, Status, WebSocketMessage};
+use example_communication_common::{connect_to_server_loop, reconnect, CommandType, ConnectionInfo, ConnectionType, ControlMessage, ControlStatus, ControlTypes, Destination, Feature, FileDefinition, FileTransferClient, journaled_progress, Sender, Status, TransferKey, WebSocketMessage, PROTOCOL_VERSION};
 use crate::commands::spawn_message_box;
 use crate::settings::{ThreadSafeClientCache, ThreadSafeSettings};
 use crate::{UI};
 
+/// Control messages remembered so a resend isn't acted on twice
+const REMEMBERED_CONTROLS: usize = 64;
+
 struct ClientStatus {
     ui: UI,
     running: bool,
-    transfers: HashMap<String, FileTransferClient>
+    /// Incoming transfers by the sender and the id it picked, so files with the same name don't clash
+    transfers: HashMap<TransferKey, FileTransferClient>,
+    /// Recently handled control messages by sender and id, with what came of them
+    handled_controls: VecDeque<(String, u64, ControlStatus)>,
+}
+
+impl ClientStatus {
+    fn handled_control(&self, reply_uuid: &str, id: u64) -> Option<ControlStatus> {
+        self.handled_controls.iter()
+            .find(|(handled_uuid, handled_id, _)| handled_uuid == reply_uuid && *handled_id == id)
+            .map(|(_, _, status)| status.clone())
+    }
+
+    fn remember_control(&mut self, reply_uuid: String, id: u64, status: ControlStatus) {
+        if self.handled_controls.len() == REMEMBERED_CONTROLS {
+            self.handled_controls.pop_front();
+        }
+        self.handled_controls.push_back((reply_uuid, id, status));
+    }
 }
 
 impl Status for ClientStatus {
@@ -29,10 +50,15 @@ pub async fn communication_thread(ui: UI, client_cache: ThreadSafeClientCache, s
     let mut status = ClientStatus{
         ui,
         running: true,
-        transfers: HashMap::new()
+        transfers: HashMap::new(),
+        handled_controls: VecDeque::new(),
     };
 
-    let (to_server, mut from_server) = connect_to_server_loop(settings.clone(), connection_state_changed.clone(), &status).await;
+    let (requests, session) = {
+        let client_cache = client_cache.lock().await;
+        (client_cache.get_requests(), client_cache.get_session())
+    };
+    let (to_server, mut from_server) = connect_to_server_loop(settings.clone(), connection_state_changed.clone(), requests, session, &status).await;
 
     client_cache.lock().await.to_server = Some(to_server);
 
@@ -43,6 +69,10 @@ pub async fn communication_thread(ui: UI, client_cache: ThreadSafeClientCache, s
             message = from_server.recv() => {
                 if let Some(message) = message {
                     handle_message(message, &mut status, settings.clone(), client_cache.clone()).await;
+                } else {
+                    // The websocket loop ended, most likely because the server went away
+                    status.update_status("Connection lost".to_string());
+                    from_server = reconnect(client_cache.clone(), settings.clone(), from_server, connection_state_changed.clone(), &status).await;
                 }
             },
             _ = connection_state_changed.notified() => {
@@ -70,7 +100,7 @@ async fn notify_file_listeners(adding: bool, event: Event, client_cache: ThreadS
     for path in event.paths {
         for uuid in &client_cache.file_listeners {
 
-            client_cache.try_send(WebSocketMessage {
+            if let Err(error) = client_cache.try_send(WebSocketMessage {
                 command: CommandType::UpdateFile {
                     uuid: client_cache.uuid.clone(),
                     file: FileDefinition {
@@ -80,7 +110,12 @@ async fn notify_file_listeners(adding: bool, event: Event, client_cache: ThreadS
                     add: adding,
                 },
                 destination: Destination::Single { destination_uuid: uuid.clone(),},
-            }).expect("Failed to notify listener of file change");
+                id: None,
+                in_reply_to: None,
+                durable_ttl: None,
+            }) {
+                println!("Failed to notify listener of file change: {}", error);
+            }
         }
     }
 
@@ -89,50 +124,133 @@ async fn notify_file_listeners(adding: bool, event: Event, client_cache: ThreadS
 async fn handle_message(message: WebSocketMessage, status: &mut ClientStatus, settings: ThreadSafeSettings, client_cache: ThreadSafeClientCache) {
 
     match message.command.clone() {
-        CommandType::Welcome{ uuid } => {
+        CommandType::Welcome{ uuid, protocol_version, features, .. } => {
             println!("Server assigned ID of: {}" ,uuid);
+            if protocol_version.unwrap_or_default() < PROTOCOL_VERSION {
+                println!("Server speaks an older protocol ({:?}), newer features may not reach it", protocol_version);
+            }
+            let client_cache_handle = client_cache.clone();
             let settings = settings.lock().await;
             let mut client_cache = client_cache.lock().await;
             client_cache.uuid = uuid;
-            client_cache.try_send(WebSocketMessage{
+            if let Err(error) = client_cache.try_send(WebSocketMessage{
                 command: CommandType::SetConnectionInfo {
                     info: ConnectionInfo {
                         uuid: client_cache.uuid.to_string(),
                         name: settings.client_name.to_string(),
-                        connection_type: ConnectionType::Client,},
+                        connection_type: ConnectionType::Client,
+                        latency_ms: None,
+                        device_id: Some(settings.device_id.clone()),
+                        protocol_version: PROTOCOL_VERSION,
+                        features: Feature::SUPPORTED.to_vec(),
+                        labels: settings.labels.clone(),
+                    },
                 },
                 destination: Destination::None,
-            }).expect("Failed to send message");
+                id: None,
+                in_reply_to: None,
+                durable_ttl: None,
+            }) {
+                println!("Failed to send message: {}", error);
+            }
+
+            // Nobody may have created the group yet, and creating one that exists does nothing
+            let groups = if features.contains(&Feature::Groups) { settings.groups.as_slice() } else { &[] };
+            for name in groups {
+                for command in [CommandType::CreateGroup { name: name.clone() }, CommandType::JoinGroup { name: name.clone() }] {
+                    if let Err(error) = client_cache.try_send(WebSocketMessage {
+                        command,
+                        destination: Destination::None,
+                        id: None,
+                        in_reply_to: None,
+                        durable_ttl: None,
+                    }) {
+                        println!("Failed to send message: {}", error);
+                    }
+                }
+            }
+            drop(client_cache);
+
+            // Chunks sent while this client was away are lost, have the senders carry on from what was written
+            for (key, transfer_client) in &status.transfers {
+                send_transfer_packets(&client_cache_handle, &key.peer, vec![transfer_client.progress()]).await;
+            }
         }
         
-        CommandType::Control{ message_type } => {
-            handle_control_message(message_type, status, settings.clone(), client_cache).await;
+        CommandType::Control{ message_type, reply_uuid } => {
+            // Receipts need both someone to send them to and an id to refer to
+            let Some((reply_uuid, id)) = reply_uuid.zip(message.id) else {
+                if let Err(reason) = handle_control_message(message_type, status, settings.clone()).await {
+                    println!("Failed to act on a control message: {}", reason);
+                }
+                return;
+            };
+
+            // A resend means a receipt got lost, the message was already acted on
+            if let Some(handled) = status.handled_control(&reply_uuid, id) {
+                send_control_receipt(&client_cache, reply_uuid, id, handled).await;
+                return;
+            }
+
+            send_control_receipt(&client_cache, reply_uuid.clone(), id, ControlStatus::Received).await;
+            let result = match handle_control_message(message_type, status, settings.clone()).await {
+                Ok(()) => ControlStatus::Executed,
+                Err(reason) => ControlStatus::Failed { reason },
+            };
+            status.remember_control(reply_uuid.clone(), id, result.clone());
+            send_control_receipt(&client_cache, reply_uuid, id, result).await;
         }
         
         CommandType::RequestCapabilities { reply_uuid } => {
             let locked_cache = client_cache.lock().await;
-            locked_cache.try_send(WebSocketMessage {
+            if let Err(error) = locked_cache.try_send(WebSocketMessage {
                 command: CommandType::ProvideCapabilities {
                     sender_uuid: locked_cache.uuid.clone(),
                     list: vec![ControlTypes::Message, ControlTypes::TransferFile, ControlTypes::DeleteFile],
                 },
                 destination: Destination::Single{destination_uuid: reply_uuid},
-            }).expect("Failed to send message");
+                id: None,
+                in_reply_to: message.id,
+                durable_ttl: None,
+            }) {
+                println!("Failed to send message: {}", error);
+            }
         }
 
-        CommandType::StartFileTransfer { name, return_uuid, .. } | CommandType::FileTransferBlob { name, return_uuid, ..} => {
-            let return_packets = if let Some(transfer_client) = status.transfers.get_mut(&name) {
+        CommandType::StartFileTransfer { name, transfer_id, return_uuid, .. } | CommandType::FileTransferBlob { name, transfer_id, return_uuid, ..} => {
+            let Some(key) = message.command.transfer_key() else {
+                return;
+            };
+
+            // Transfers are refused while they're turned off, including ones already underway
+            if !settings.lock().await.accept_file_transfer {
+                let reason = "file transfers are turned off".to_string();
+                let refusal = match status.transfers.remove(&key) {
+                    Some(mut transfer_client) => transfer_client.refuse(reason).await,
+                    None => CommandType::FileTransferCancel {
+                        name,
+                        transfer_id,
+                        sender_uuid: client_cache.lock().await.uuid.clone(),
+                        reason,
+                    },
+                };
+                send_transfer_packets(&client_cache, &return_uuid, vec![refusal]).await;
+                return;
+            }
+
+            let return_packets = if let Some(transfer_client) = status.transfers.get_mut(&key) {
                 let (return_packets, data_finished) = transfer_client.handle_packet(message.command).await;
 
                 if data_finished {
                     transfer_client.close().await;
-                    status.transfers.remove(&name);
+                    status.transfers.remove(&key);
                 }
 
                 return_packets
             }
             else {
-                let mut transfer_client = FileTransferClient::new(name.clone(), settings.clone()).await;
+                let local_uuid = client_cache.lock().await.uuid.clone();
+                let mut transfer_client = FileTransferClient::new(name, local_uuid, settings.clone()).await;
                 let (return_packets, data_finished) = transfer_client.handle_packet(message.command).await;
 
 
@@ -140,47 +258,104 @@ async fn handle_message(message: WebSocketMessage, status: &mut ClientStatus, se
                     transfer_client.close().await;
                 }
                 else {
-                    status.transfers.insert(name, transfer_client);
+                    status.transfers.insert(key, transfer_client);
                 }
 
                 return_packets
             };
 
-            let locked_cache = client_cache.lock().await;
-            for packet in return_packets {
-                locked_cache.try_send(WebSocketMessage{
-                    command: packet,
-                    destination: Destination::Single{
-                        destination_uuid: return_uuid.to_string(),
-                    },
-                }).expect("Failed to send message");
+            send_transfer_packets(&client_cache, &return_uuid, return_packets).await;
+        }
+
+        CommandType::FileTransferQuery { name, transfer_id, return_uuid } => {
+            let key = TransferKey::new(&return_uuid, &transfer_id, &name);
+            let return_packets = match status.transfers.get_mut(&key) {
+                Some(transfer_client) => transfer_client.handle_packet(message.command).await.0,
+                // Nothing in memory after a restart, but part of the file may be on disk
+                None => vec![CommandType::FileTransferProgress {
+                    name,
+                    confirmed_chunks: journaled_progress(&transfer_id, settings.clone()).await,
+                    transfer_id,
+                    sender_uuid: client_cache.lock().await.uuid.clone(),
+                }],
+            };
+
+            send_transfer_packets(&client_cache, &return_uuid, return_packets).await;
+        }
+
+        CommandType::FileTransferCancel { .. } => {
+            if let Some(key) = message.command.transfer_key()
+                && let Some(mut transfer_client) = status.transfers.remove(&key) {
+                transfer_client.discard().await;
             }
         }
 
         CommandType::AddFileWatch { return_uuid } => {
-            client_cache.lock().await.register_file_listener(return_uuid.to_string());
+            client_cache.lock().await.register_file_listener(return_uuid.to_string(), message.id);
         }
 
         CommandType::NotifyDisconnect {uuid} => {
             client_cache.lock().await.deregister_file_listener(uuid.to_string());
         }
 
+        CommandType::Error { code, message, in_reply_to } => {
+            status.report_error(code, message, in_reply_to);
+        }
+
         _ => {}
     }
 }
 
-async fn handle_control_message(message: ControlMessage, status: &mut ClientStatus, settings: ThreadSafeSettings, _client_cache: ThreadSafeClientCache) {
+async fn send_transfer_packets(client_cache: &ThreadSafeClientCache, return_uuid: &str, packets: Vec<CommandType>) {
+    // Waiting for room would stop this thread taking what the websocket loop hands it, which may be what the loop is
+    // waiting on. A dropped packet goes unanswered and the sender sends it again.
+    let locked_cache = client_cache.lock().await;
+    for packet in packets {
+        if let Err(error) = locked_cache.try_send(WebSocketMessage{
+            command: packet,
+            destination: Destination::Single{
+                destination_uuid: return_uuid.to_string(),
+            },
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        }) {
+            println!("Failed to send message: {}", error);
+        }
+    }
+}
+
+async fn send_control_receipt(client_cache: &ThreadSafeClientCache, reply_uuid: String, id: u64, control_status: ControlStatus) {
+    let locked_cache = client_cache.lock().await;
+    if let Err(error) = locked_cache.try_send(WebSocketMessage {
+        command: CommandType::ControlReceipt {
+            sender_uuid: locked_cache.uuid.clone(),
+            status: control_status,
+        },
+        destination: Destination::Single { destination_uuid: reply_uuid },
+        id: None,
+        in_reply_to: Some(id),
+        durable_ttl: None,
+    }) {
+        println!("Failed to send message: {}", error);
+    }
+}
+
+/// Acts on a control message, the error says why it couldn't be
+async fn handle_control_message(message: ControlMessage, status: &mut ClientStatus, settings: ThreadSafeSettings) -> Result<(), String> {
     match message {
         ControlMessage::Default => {}
         ControlMessage::Message { text } => {
             status.ui.app_window.upgrade_in_event_loop(move |ui| {
                 spawn_local(spawn_message_box(text, ui.as_weak(), settings.clone())).expect("Failed to spawn message box");
-            }).expect("Failed to spawn message box");
+            }).map_err(|e| format!("failed to show the message: {}", e))?;
         }
         ControlMessage::TransferFile => {}
         ControlMessage::DeleteFile {path} => {
-            let path = Path::new(&settings.lock().await.file_transfer_location).join(&path);
-            std::fs::remove_file(path).expect("Failed to remove file");
+            let full_path = Path::new(&settings.lock().await.file_transfer_location).join(&path);
+            std::fs::remove_file(full_path).map_err(|e| format!("failed to delete {}: {}", path, e))?;
         }
     }
+
+    Ok(())
 }
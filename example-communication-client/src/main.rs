@@ -11,7 +11,7 @@ use communication::communication_thread;
 slint::include_modules!();
 use slint::{spawn_local, SharedString, Weak};
 use tokio::sync::Notify;
-use example_communication_common::make_thread_safe;
+use example_communication_common::{make_thread_safe, PendingRequests, SessionToken};
 use crate::settings::{ClientCache, MyConfig, ThreadSafeClientCache, ThreadSafeSettings};
 
 struct UI {
@@ -37,6 +37,8 @@ pub async fn main() {
     let mut client_cache = ClientCache{
         uuid: "".to_string(),
         to_server: None,
+        requests: PendingRequests::default(),
+        session: SessionToken::default(),
         current_directory: "".to_string(),
         file_watcher: watcher,
         file_listeners: Vec::new(),
@@ -1,10 +1,9 @@
-use std::path::Path;
-use tokio::sync::mpsc::error::SendError;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, Notify};
-use example_communication_common::{CommandType, ConnectionInfo, ConnectionSettings, ConnectionType, Destination, FileDefinition, FileTransfer, Sender, WebSocketMessage};
+use example_communication_common::{CommandType, ConnectionInfo, ConnectionSettings, ConnectionType, Destination, Feature, FileDefinition, FileTransfer, Labels, PendingRequests, QueueSender, SessionToken, Sender, WebSocketMessage, PROTOCOL_VERSION};
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
 use field_name::FieldNames;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use slint::{ModelRc, VecModel};
@@ -18,6 +17,15 @@ pub struct MyConfig {
     pub client_name: String,
     pub address: String,
     pub key: String,
+    /// Generated on first start and never changed, so peers can tell this machine apart across restarts
+    #[serde(default)]
+    pub device_id: String,
+    /// Server side groups this machine joins when it connects, e.g. its site or purpose
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Labels advertised to peers, e.g. `site = "berlin"`, which controllers can select machines by
+    #[serde(default)]
+    pub labels: Labels,
     pub play_sound: bool,
     pub sound_source: String,
     pub accept_file_transfer: bool,
@@ -28,6 +36,14 @@ impl FileTransfer for MyConfig {
     fn get_transfer_location(&self) -> String {
         self.file_transfer_location.clone()
     }
+
+    // Kept next to the config, so the journals don't show up in the watched transfer location
+    fn get_journal_location(&self) -> PathBuf {
+        match confy::get_configuration_file_path("play_with_me", None) {
+            Ok(config_path) => config_path.with_file_name("transfers"),
+            Err(_) => Path::new(&self.file_transfer_location).join(".transfers"),
+        }
+    }
 }
 
 impl ConnectionSettings for MyConfig {
@@ -46,6 +62,9 @@ impl Default for MyConfig {
             client_name: "Client".to_owned(),
             address: "ws://localhost:8080/ws".to_owned(),
             key: "".to_owned(),
+            device_id: new_device_id(),
+            groups: vec![],
+            labels: Labels::new(),
             play_sound: false,
             sound_source: "".to_string(),
             accept_file_transfer: false,
@@ -54,6 +73,10 @@ impl Default for MyConfig {
     }
 }
 
+fn new_device_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
 impl MyConfig {
     pub fn load() -> MyConfig {
         let loaded_settings = confy::load("play_with_me", None);
@@ -62,7 +85,17 @@ impl MyConfig {
             MyConfig::default()
         }
         else {
-            loaded_settings.unwrap()
+            let mut settings: MyConfig = loaded_settings.unwrap();
+
+            // Configs saved before device IDs existed get one the first time they're loaded
+            if settings.device_id.is_empty() {
+                settings.device_id = new_device_id();
+                if let Err(e) = confy::store("play_with_me", None, &settings) {
+                    println!("Failed to store the new device ID: {}", e);
+                }
+            }
+
+            settings
         }
     }
 
@@ -116,16 +149,28 @@ impl MyConfig {
                 self.client_name = new_value.clone();
                 self.save().await;
 
-                client_cache.lock().await.try_send(WebSocketMessage {
+                // The server renames the connection and tells its peers
+                let client_cache = client_cache.lock().await;
+                if let Err(error) = client_cache.try_send(WebSocketMessage {
                     command: CommandType::UpdateConnection {
                         connection_info: ConnectionInfo {
-                            uuid: client_cache.lock().await.uuid.to_string(),
+                            uuid: client_cache.uuid.to_string(),
                             name: new_value.to_string(),
                             connection_type: ConnectionType::Client,
+                            latency_ms: None,
+                            device_id: Some(self.device_id.clone()),
+                            protocol_version: PROTOCOL_VERSION,
+                            features: Feature::SUPPORTED.to_vec(),
+                            labels: self.labels.clone(),
                         },
                     },
                     destination: Destination::None,
-                }).expect("Failed to Update Connection Information");
+                    id: None,
+                    in_reply_to: None,
+                    durable_ttl: None,
+                }) {
+                    println!("Failed to Update Connection Information: {}", error);
+                }
             }
             MyConfig::ADDRESS => {
                 self.address = new_value.clone();
@@ -160,7 +205,9 @@ impl MyConfig {
 pub type ThreadSafeClientCache = Arc<Mutex<ClientCache>>;
 pub struct ClientCache {
     pub uuid: String,
-    pub to_server: Option<UnboundedSender<WebSocketMessage>>,
+    pub to_server: Option<QueueSender<WebSocketMessage>>,
+    pub requests: PendingRequests,
+    pub session: SessionToken,
     pub(crate) current_directory: String,
     pub file_watcher: RecommendedWatcher,
     pub file_listeners: Vec<String>,
@@ -185,14 +232,14 @@ impl ClientCache {
         files
     }
 
-    fn initialize_file_listener(&self, uuid: String, mut files: Option<Vec<FileDefinition>>) {
+    fn initialize_file_listener(&self, uuid: String, mut files: Option<Vec<FileDefinition>>, in_reply_to: Option<u64>) {
         if files.is_none() {
             files = Some(self.build_file_state());
         }
 
         let files = files.unwrap();
 
-        self.try_send(WebSocketMessage {
+        if let Err(error) = self.try_send(WebSocketMessage {
             command: CommandType::ProvideFiles {
                 uuid: self.uuid.clone(),
                 files,
@@ -200,11 +247,16 @@ impl ClientCache {
             destination: Destination::Single {
                 destination_uuid: uuid,
             },
-        }).expect("Failed to ProvideFiles");
+            id: None,
+            in_reply_to,
+            durable_ttl: None,
+        }) {
+            println!("Failed to ProvideFiles: {}", error);
+        }
     }
 
-    pub fn register_file_listener(&mut self, uuid: String) {
-        self.initialize_file_listener(uuid.clone(), None);
+    pub fn register_file_listener(&mut self, uuid: String, in_reply_to: Option<u64>) {
+        self.initialize_file_listener(uuid.clone(), None, in_reply_to);
         self.file_listeners.push(uuid);
     }
     
@@ -223,7 +275,7 @@ impl ClientCache {
         if self.file_listeners.len() > 0 {
             let file_state = self.build_file_state();
             for file_listener in &self.file_listeners {
-                self.initialize_file_listener(file_listener.clone(), Some(file_state.clone()));
+                self.initialize_file_listener(file_listener.clone(), Some(file_state.clone()), None);
             }
         }
     }
@@ -234,23 +286,32 @@ impl Sender for ClientCache {
         self.uuid.clone()
     }
 
-    fn try_send(&self, message: WebSocketMessage) -> Result<(), SendError<WebSocketMessage>>{
-        if let Some(to_server) = &self.to_server {
-            return to_server.send(message)
-        }
-        Ok(())
+    fn get_connection(&self) -> Option<QueueSender<WebSocketMessage>> {
+        self.to_server.clone()
     }
 
     fn drop_connection(&mut self) {
-        self.try_send(WebSocketMessage{
+        // The websocket loop may be gone already, or too far behind to take the message
+        let _ = self.try_send(WebSocketMessage{
             command: CommandType::Disconnect,
-            destination: Destination::None
-        }).expect("Failed to send message");
+            destination: Destination::None,
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        });
 
         self.to_server = None;
     }
 
-    fn set_connection(&mut self, new_sender: UnboundedSender<WebSocketMessage>) {
+    fn set_connection(&mut self, new_sender: QueueSender<WebSocketMessage>) {
         self.to_server = Some(new_sender);
     }
+
+    fn get_requests(&self) -> PendingRequests {
+        self.requests.clone()
+    }
+
+    fn get_session(&self) -> SessionToken {
+        self.session.clone()
+    }
 }
\ No newline at end of file
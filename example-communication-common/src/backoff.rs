@@ -0,0 +1,47 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Delays used between attempts to reach the server
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Exponential backoff with jitter. Each delay doubles the previous one up to `max_delay`,
+/// then a random part of up to half of it is taken off so clients don't retry in lockstep.
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self { policy, attempt: 0 }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempt);
+        let delay = self.policy.initial_delay.saturating_mul(factor).min(self.policy.max_delay);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(1.0 - random_fraction() / 2.0)
+    }
+}
+
+/// A value in `0.0..1.0`, random enough to spread out reconnecting clients
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -4,8 +4,9 @@ use std::cmp::PartialEq;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use serde::{Deserialize, Serialize};
+use crate::{Feature, Labels, Selector, WireFormat};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ConnectionType {
     Client,
     Controller
@@ -40,6 +41,21 @@ pub struct ConnectionInfo {
     pub uuid: String,
     pub name: String,
     pub connection_type: ConnectionType,
+    /// Round trip time to the server, as last measured by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Stable ID the machine generated for itself, unlike `uuid` it survives restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Protocol version the peer speaks, `LEGACY_PROTOCOL_VERSION` if it predates versioning
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// Optional parts of the protocol the peer handles, see `ConnectionInfo::supports`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<Feature>,
+    /// Free form key/value labels such as `site` or `role`, matched by `Destination::Selector`
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
 }
 
 impl Display for ConnectionInfo {
@@ -157,6 +173,118 @@ impl Default for ControlMessage {
     fn default() -> Self {ControlMessage::Default}
 }
 
+impl ControlMessage {
+    /// Every name `as_str` returns
+    pub const NAMES: &'static [&'static str] = &["Default", "Message", "TransferFile", "DeleteFile"];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ControlMessage::Default => {"Default"}
+            ControlMessage::Message { .. } => {"Message"}
+            ControlMessage::TransferFile => {"TransferFile"}
+            ControlMessage::DeleteFile { .. } => {"DeleteFile"}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    InvalidMessage,
+    UnknownDestination,
+    NotAuthorized,
+    UnexpectedCommand,
+    UuidMismatch,
+    /// The peer's protocol version is older than the server accepts
+    IncompatibleProtocol,
+    /// The recipient doesn't support the command
+    Unsupported,
+    /// No group by that name has been created
+    UnknownGroup,
+    /// The offline recipient already has as many messages waiting as the server keeps
+    MailboxFull,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorCode::InvalidMessage => {"InvalidMessage"}
+            ErrorCode::UnknownDestination => {"UnknownDestination"}
+            ErrorCode::NotAuthorized => {"NotAuthorized"}
+            ErrorCode::UnexpectedCommand => {"UnexpectedCommand"}
+            ErrorCode::UuidMismatch => {"UuidMismatch"}
+            ErrorCode::IncompatibleProtocol => {"IncompatibleProtocol"}
+            ErrorCode::Unsupported => {"Unsupported"}
+            ErrorCode::UnknownGroup => {"UnknownGroup"}
+            ErrorCode::MailboxFull => {"MailboxFull"}
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// What became of a durable message, reported in a `DeliveryReceipt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// The recipient is offline, the server holds the message until it's back or the TTL runs out
+    Queued,
+    Delivered,
+    /// The TTL ran out before the recipient came back
+    Expired,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            DeliveryStatus::Queued => {"Queued"}
+            DeliveryStatus::Delivered => {"Delivered"}
+            DeliveryStatus::Expired => {"Expired"}
+        }
+    }
+}
+
+impl Display for DeliveryStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How far a control message got, reported in a `ControlReceipt`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlStatus {
+    /// The client got the message and is about to act on it
+    Received,
+    Executed,
+    Failed { reason: String },
+}
+
+impl ControlStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ControlStatus::Received => {"Received"}
+            ControlStatus::Executed => {"Executed"}
+            ControlStatus::Failed { .. } => {"Failed"}
+        }
+    }
+
+    /// Whether the client is done with the message, nothing else is reported for it afterwards
+    pub fn is_final(&self) -> bool {
+        !matches!(self, ControlStatus::Received)
+    }
+}
+
+impl Display for ControlStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlStatus::Failed { reason } => {write!(f, "Failed: {}", reason)}
+            _ => {write!(f, "{}", self.as_str())}
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileDefinition {
     pub path: String,
@@ -167,18 +295,48 @@ pub struct FileDefinition {
 #[derive(Serialize, Deserialize, Clone)]
 pub enum CommandType {
     // Server -> Client
-    Welcome {uuid: String},
+    Welcome {
+        uuid: String,
+        /// Presented in the `X-Session-Token` header when reconnecting to keep `uuid`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_token: Option<String>,
+        /// The format the server accepts from this connection, missing if it only speaks JSON
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wire_format: Option<WireFormat>,
+        /// The server's protocol version, missing from servers that predate versioning
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// Features the server routes, commands needing any other feature are rejected
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        features: Vec<Feature>,
+    },
     ActiveConnections { users: Vec<ConnectionInfo> },
     UpdateConnection { connection_info: ConnectionInfo },
     NotifyDisconnect { uuid: String },
+    Error { code: ErrorCode, message: String, in_reply_to: Option<u64> },
+    /// Sent back for a durable message, with `in_reply_to` set to its `id`
+    DeliveryReceipt { recipient_uuid: String, status: DeliveryStatus },
     // Client -> Server
     GetConnections {reply_uuid: String},
     SetConnectionInfo { info: ConnectionInfo },
     Disconnect,
+    /// Creates a named group, if there isn't one by that name yet
+    CreateGroup { name: String },
+    /// Adds the sending connection to a group
+    JoinGroup { name: String },
+    LeaveGroup { name: String },
     // Client -> Client
     Control {
         #[serde_as(deserialize_as = "DefaultOnError")]
         message_type: ControlMessage,
+        /// Where `ControlReceipt`s for the message go, none are sent if it's missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_uuid: Option<String>,
+    },
+    /// Sent back by a client for a `Control` message with a `reply_uuid`, with `in_reply_to` set to its `id`
+    ControlReceipt {
+        sender_uuid: String,
+        status: ControlStatus,
     },
     RequestCapabilities {
         reply_uuid: String
@@ -192,28 +350,79 @@ pub enum CommandType {
     // File Transfer
     StartFileTransfer {
         name: String,
+        /// Picked by the sender, the receiver keeps its progress under it so the transfer can resume.
+        /// Empty from senders that predate resuming.
+        #[serde(default)]
+        transfer_id: String,
         chunk_count: u64,
         blob_size: usize,
+        /// Most chunks the sender wants in flight, 0 from senders that predate negotiating it
+        #[serde(default)]
+        window: usize,
         checksum: String,
         return_uuid: String
     },
     FileTransferBlob {
         name: String,
+        /// Empty from senders that predate transfer ids
+        #[serde(default)]
+        transfer_id: String,
         chunk_num: i32,
+        // Raw bytes in MessagePack, still an array of numbers in JSON
+        #[serde(with = "serde_bytes")]
         blob: Vec<u8>,
         return_uuid: String
     },
     FileTransferAck {
         name: String,
+        #[serde(default)]
+        transfer_id: String,
+        /// The receiver answering, empty from receivers that predate transfer ids
+        #[serde(default)]
+        sender_uuid: String,
         start: bool,
         chunk_num: i32,
         whole: bool,
+        /// Only when acking the start, the window the receiver agreed to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<TransferLimits>,
     },
     FileTransferNack {
         name: String,
+        #[serde(default)]
+        transfer_id: String,
+        /// The receiver answering, empty from receivers that predate transfer ids
+        #[serde(default)]
+        sender_uuid: String,
         start: bool,
         chunk_num: i32,
-        whole: bool
+        whole: bool,
+        /// Only when refusing the start, the largest chunks and window the receiver takes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limits: Option<TransferLimits>,
+    },
+    /// Asks the receiver how far it got, answered with `FileTransferProgress`
+    FileTransferQuery {
+        name: String,
+        transfer_id: String,
+        return_uuid: String,
+    },
+    /// Sent by the receiver when asked, when it resumes a transfer and when it reconnects.
+    /// The sender carries on from `confirmed_chunks`, everything before it is written.
+    FileTransferProgress {
+        name: String,
+        transfer_id: String,
+        #[serde(default)]
+        sender_uuid: String,
+        confirmed_chunks: u64,
+    },
+    /// Stops a transfer from either end. The receiver throws away what it has of the file.
+    FileTransferCancel {
+        name: String,
+        transfer_id: String,
+        /// The end cancelling
+        sender_uuid: String,
+        reason: String,
     },
     // Files
     AddFileWatch {
@@ -230,11 +439,145 @@ pub enum CommandType {
     }
 }
 
+impl CommandType {
+    /// Every name `as_str` returns
+    pub const NAMES: &'static [&'static str] = &[
+        "Welcome", "ActiveConnections", "UpdateConnection", "NotifyDisconnect", "Error", "DeliveryReceipt",
+        "GetConnections", "SetConnectionInfo", "Disconnect", "CreateGroup", "JoinGroup", "LeaveGroup",
+        "Control", "ControlReceipt", "RequestCapabilities", "ProvideCapabilities", "Ack",
+        "StartFileTransfer", "FileTransferBlob", "FileTransferAck", "FileTransferNack", "FileTransferQuery",
+        "FileTransferProgress", "FileTransferCancel", "AddFileWatch", "ProvideFiles", "UpdateFile",
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            CommandType::Welcome { .. } => {"Welcome"}
+            CommandType::ActiveConnections { .. } => {"ActiveConnections"}
+            CommandType::UpdateConnection { .. } => {"UpdateConnection"}
+            CommandType::NotifyDisconnect { .. } => {"NotifyDisconnect"}
+            CommandType::Error { .. } => {"Error"}
+            CommandType::DeliveryReceipt { .. } => {"DeliveryReceipt"}
+            CommandType::GetConnections { .. } => {"GetConnections"}
+            CommandType::SetConnectionInfo { .. } => {"SetConnectionInfo"}
+            CommandType::Disconnect => {"Disconnect"}
+            CommandType::CreateGroup { .. } => {"CreateGroup"}
+            CommandType::JoinGroup { .. } => {"JoinGroup"}
+            CommandType::LeaveGroup { .. } => {"LeaveGroup"}
+            CommandType::Control { .. } => {"Control"}
+            CommandType::ControlReceipt { .. } => {"ControlReceipt"}
+            CommandType::RequestCapabilities { .. } => {"RequestCapabilities"}
+            CommandType::ProvideCapabilities { .. } => {"ProvideCapabilities"}
+            CommandType::Ack => {"Ack"}
+            CommandType::StartFileTransfer { .. } => {"StartFileTransfer"}
+            CommandType::FileTransferBlob { .. } => {"FileTransferBlob"}
+            CommandType::FileTransferAck { .. } => {"FileTransferAck"}
+            CommandType::FileTransferNack { .. } => {"FileTransferNack"}
+            CommandType::FileTransferQuery { .. } => {"FileTransferQuery"}
+            CommandType::FileTransferProgress { .. } => {"FileTransferProgress"}
+            CommandType::FileTransferCancel { .. } => {"FileTransferCancel"}
+            CommandType::AddFileWatch { .. } => {"AddFileWatch"}
+            CommandType::ProvideFiles { .. } => {"ProvideFiles"}
+            CommandType::UpdateFile { .. } => {"UpdateFile"}
+        }
+    }
+
+    /// The UUID a command claims to be sent from or asks replies to go to, which must be the sender's own
+    pub fn sender_uuid(&self) -> Option<&str> {
+        match self {
+            CommandType::UpdateConnection { connection_info } => {Some(&connection_info.uuid)}
+            CommandType::NotifyDisconnect { uuid } => {Some(uuid)}
+            CommandType::GetConnections { reply_uuid } => {Some(reply_uuid)}
+            CommandType::SetConnectionInfo { info } => {Some(&info.uuid)}
+            CommandType::Control { reply_uuid: Some(reply_uuid), .. } => {Some(reply_uuid)}
+            CommandType::ControlReceipt { sender_uuid, .. } => {Some(sender_uuid)}
+            CommandType::RequestCapabilities { reply_uuid } => {Some(reply_uuid)}
+            CommandType::ProvideCapabilities { sender_uuid, .. } => {Some(sender_uuid)}
+            CommandType::StartFileTransfer { return_uuid, .. } => {Some(return_uuid)}
+            CommandType::FileTransferBlob { return_uuid, .. } => {Some(return_uuid)}
+            CommandType::FileTransferQuery { return_uuid, .. } => {Some(return_uuid)}
+            CommandType::FileTransferAck { sender_uuid, .. }
+                | CommandType::FileTransferNack { sender_uuid, .. }
+                | CommandType::FileTransferProgress { sender_uuid, .. } => {Some(sender_uuid)}
+            CommandType::FileTransferCancel { sender_uuid, .. } => {Some(sender_uuid)}
+            CommandType::AddFileWatch { return_uuid } => {Some(return_uuid)}
+            CommandType::ProvideFiles { uuid, .. } => {Some(uuid)}
+            CommandType::UpdateFile { uuid, .. } => {Some(uuid)}
+            _ => {None}
+        }
+    }
+
+    /// Names `uuid` as the sender of a file transfer reply that doesn't say, receivers that predate transfer ids
+    /// leave `sender_uuid` out. Returns whether the command changed.
+    pub fn fill_sender_uuid(&mut self, uuid: &str) -> bool {
+        if let CommandType::FileTransferAck { sender_uuid, .. }
+            | CommandType::FileTransferNack { sender_uuid, .. }
+            | CommandType::FileTransferProgress { sender_uuid, .. } = self && sender_uuid.is_empty() {
+            *sender_uuid = uuid.to_string();
+            return true;
+        }
+        false
+    }
+
+    /// The transfer a file transfer command belongs to, as seen by whoever receives it.
+    /// Transfers from peers that predate transfer ids are told apart by file name instead.
+    pub fn transfer_key(&self) -> Option<TransferKey> {
+        let (peer, transfer_id, name) = match self {
+            CommandType::StartFileTransfer { return_uuid, transfer_id, name, .. }
+                | CommandType::FileTransferBlob { return_uuid, transfer_id, name, .. }
+                | CommandType::FileTransferQuery { return_uuid, transfer_id, name } => (return_uuid, transfer_id, name),
+            CommandType::FileTransferAck { sender_uuid, transfer_id, name, .. }
+                | CommandType::FileTransferNack { sender_uuid, transfer_id, name, .. }
+                | CommandType::FileTransferProgress { sender_uuid, transfer_id, name, .. }
+                | CommandType::FileTransferCancel { sender_uuid, transfer_id, name, .. } => (sender_uuid, transfer_id, name),
+            _ => return None
+        };
+
+        Some(TransferKey::new(peer, transfer_id, name))
+    }
+}
+
+/// Chunk size and window of a file transfer, as far as the receiver is concerned
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TransferLimits {
+    /// In bytes
+    pub blob_size: usize,
+    /// Chunks in flight at once, the receiver holds up to this many out of order
+    pub window: usize,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        Self {
+            blob_size: 1024 * 1024,
+            window: 256,
+        }
+    }
+}
+
+/// A file transfer on either end: the connection on the other end and the id the sender picked for it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TransferKey {
+    pub peer: String,
+    pub transfer_id: String,
+}
+
+impl TransferKey {
+    /// Falls back to the file name when the peer sent no transfer id
+    pub fn new(peer: &str, transfer_id: &str, name: &str) -> Self {
+        let transfer_id = if transfer_id.is_empty() { name } else { transfer_id };
+        Self { peer: peer.to_string(), transfer_id: transfer_id.to_string() }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Destination {
     Single { destination_uuid: String},
     Multi { destination_uuids: Vec<String>},
     Type { destination_type: ConnectionType },
+    /// Every member of a group created with `CreateGroup`
+    Group { name: String },
+    /// Every connection whose labels match, e.g. `site=berlin,role!=kiosk`
+    Selector { selector: Selector },
     All,
     None
 }
@@ -246,6 +589,9 @@ impl Destination {
             Destination::Single { destination_uuid } => { *destination_uuid == connection_info.uuid }
             Destination::Multi { destination_uuids } => { destination_uuids.contains(&connection_info.uuid) }
             Destination::Type { destination_type } => { destination_type == connection_info.connection_type }
+            // Only the server knows who's in a group
+            Destination::Group { .. } => { false }
+            Destination::Selector { selector } => { selector.matches(&connection_info.labels) }
             Destination::All => { true }
             Destination::None => { false }
         }
@@ -256,6 +602,8 @@ impl Destination {
             Destination::Single { destination_uuid } => { *destination_uuid == *uuid }
             Destination::Multi { destination_uuids } => { destination_uuids.contains(uuid) }
             Destination::Type { .. } => { false }
+            Destination::Group { .. } => { false }
+            Destination::Selector { .. } => { false }
             Destination::All => { true }
             Destination::None => { false }
         }
@@ -266,5 +614,25 @@ impl Destination {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct WebSocketMessage {
     pub command: CommandType,
-    pub destination: Destination
+    pub destination: Destination,
+    /// Correlation ID, unique per sender, echoed back as `in_reply_to` by replies and `Error`s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// The `id` of the request this message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<u64>,
+    /// Seconds the server may hold a `Single` message whose recipient is offline, delivering it to the recipient's
+    /// device when it reconnects. `None` fails right away with `UnknownDestination` instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durable_ttl: Option<u64>,
+}
+
+impl WebSocketMessage {
+    /// The `id` of the request this message answers, whether it's a reply or an `Error`
+    pub fn reply_target(&self) -> Option<u64> {
+        match &self.command {
+            CommandType::Error { in_reply_to, .. } => self.in_reply_to.or(*in_reply_to),
+            _ => self.in_reply_to
+        }
+    }
 }
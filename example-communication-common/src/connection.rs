@@ -1,19 +1,17 @@
+use std::future::Future;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use futures_util::{SinkExt, StreamExt};
 use tokio::{select};
 use tokio_tungstenite::{WebSocketStream, MaybeTlsStream, connect_async};
 use tokio_tungstenite::tungstenite::{handshake::client::Response, error::Error};
 use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-pub use tokio::{
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    task::spawn
-};
-use tokio::sync::mpsc::error::SendError;
+pub use tokio::task::spawn;
 use tokio::sync::Notify;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use crate::{CommandType, Destination, ThreadSafe, WebSocketMessage};
+use crate::{bounded_queue, Backoff, CommandType, ControlMessage, ControlStatus, DeliveryStatus, Destination, EncodedMessage, ErrorCode, Heartbeat, QueueConfig, QueueError, QueueReceiver, QueueSender, ReceiptPolicy, ReconnectPolicy, PendingRequests, RequestError, SessionToken, ThreadSafe, WebSocketMessage, WireFormat, DEFAULT_REQUEST_TIMEOUT, WIRE_FORMAT_HEADER};
+use crate::requests::{follow_control, wait_for_reply};
 pub use tokio_tungstenite::tungstenite::Message;
 
 type Websocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
@@ -21,27 +19,146 @@ type Websocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 pub trait ConnectionSettings {
     fn get_url(&self) -> String;
     fn get_key(&self) -> String;
+
+    /// How often the server is pinged, and how long it may stay silent before the connection is dropped
+    fn get_heartbeat(&self) -> Heartbeat {
+        Heartbeat::default()
+    }
+
+    /// How long to wait between attempts to reach the server
+    fn get_reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy::default()
+    }
+
+    /// Size and overflow policy of the queues to and from the server
+    fn get_queue_config(&self) -> QueueConfig {
+        QueueConfig::default()
+    }
+
+    /// The wire format asked of the server, JSON is used instead if the server doesn't support it
+    fn get_wire_format(&self) -> WireFormat {
+        WireFormat::MessagePack
+    }
 }
 
 pub trait Status {
     fn update_status(&self, message: String);
+
+    /// Called with `Error` replies from the server, `in_reply_to` is the `id` of the message that caused it
+    fn report_error(&self, code: ErrorCode, message: String, in_reply_to: Option<u64>) {
+        match in_reply_to {
+            Some(id) => self.update_status(format!("{} (message {}): {}", code, id, message)),
+            None => self.update_status(format!("{}: {}", code, message)),
+        }
+    }
+
+    /// Called with `DeliveryReceipt`s for durable messages, `in_reply_to` is the `id` of the message it's about
+    fn report_delivery(&self, recipient_uuid: String, status: DeliveryStatus, in_reply_to: Option<u64>) {
+        match in_reply_to {
+            Some(id) => self.update_status(format!("message {} to {}: {}", id, recipient_uuid, status)),
+            None => self.update_status(format!("message to {}: {}", recipient_uuid, status)),
+        }
+    }
 }
 
 pub trait Sender {
     fn get_uuid(&self) -> String;
-    fn try_send(&self, message: WebSocketMessage) -> Result<(), SendError<WebSocketMessage>>;
+    /// The queue to the server, `None` while disconnected
+    fn get_connection(&self) -> Option<QueueSender<WebSocketMessage>>;
     fn drop_connection(&mut self);
-    fn set_connection(&mut self, new_sender: UnboundedSender<WebSocketMessage>);
+    fn set_connection(&mut self, new_sender: QueueSender<WebSocketMessage>);
+    fn get_requests(&self) -> PendingRequests;
+    fn get_session(&self) -> SessionToken;
+
+    /// Queues `message` for the server without waiting, messages sent while disconnected are dropped
+    // QueueError hands the unsent message back to the caller
+    #[allow(clippy::result_large_err)]
+    fn try_send(&self, message: WebSocketMessage) -> Result<(), QueueError<WebSocketMessage>> {
+        match self.get_connection() {
+            Some(connection) => connection.try_send(message),
+            None => Ok(())
+        }
+    }
+
+    /// Queues `message` for the server, waiting for room if the queue is full.
+    /// Like `request`, the lock guarding the sender doesn't need to be held while awaiting it.
+    fn send(&self, message: WebSocketMessage) -> impl Future<Output = Result<(), QueueError<WebSocketMessage>>> + Send + 'static {
+        let connection = self.get_connection();
+
+        async move {
+            match connection {
+                Some(connection) => connection.send(message).await,
+                None => Ok(())
+            }
+        }
+    }
+
+    /// Sends `message` and resolves with the reply to it, see `request_with_timeout`
+    fn request(&self, message: WebSocketMessage) -> impl Future<Output = Result<WebSocketMessage, RequestError>> + Send + 'static {
+        self.request_with_timeout(message, DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    /// Sends `message` with a fresh id and resolves with the first message that answers it.
+    /// The message is sent right away, so the lock guarding the sender doesn't need to be held while awaiting the reply.
+    fn request_with_timeout(&self, mut message: WebSocketMessage, timeout: Duration) -> impl Future<Output = Result<WebSocketMessage, RequestError>> + Send + 'static {
+        let requests = self.get_requests();
+        let id = requests.next_id();
+        message.id = Some(id);
+        let receiver = requests.register(id);
+        let sent = self.try_send(message).is_ok();
+
+        async move {
+            if !sent {
+                requests.cancel(id);
+                return Err(RequestError::SendFailed);
+            }
+
+            wait_for_reply(requests, id, receiver, timeout).await
+        }
+    }
+
+    /// Sends a control message asking for receipts and resolves with how the client reports acting on it.
+    /// The message is sent again with the same id while no receipt arrives, as set by `policy`.
+    fn send_control(&self, destination_uuid: String, message_type: ControlMessage, policy: ReceiptPolicy) -> impl Future<Output = Result<ControlStatus, RequestError>> + Send + 'static {
+        let requests = self.get_requests();
+        let id = requests.next_id();
+        let connection = self.get_connection();
+        let message = WebSocketMessage {
+            command: CommandType::Control { message_type, reply_uuid: Some(self.get_uuid()) },
+            destination: Destination::Single { destination_uuid },
+            id: Some(id),
+            in_reply_to: None,
+            durable_ttl: None,
+        };
+
+        async move {
+            match connection {
+                Some(connection) => follow_control(requests, connection, message, id, policy).await,
+                None => Err(RequestError::SendFailed)
+            }
+        }
+    }
 }
 
-async fn connect(connection_info: &impl ConnectionSettings) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error>{
+async fn connect(connection_info: &impl ConnectionSettings, session: &SessionToken) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error>{
     let mut request = connection_info.get_url().into_client_request()?;
     request.headers_mut().insert("Authorization", connection_info.get_key().parse()?);
+    if let Some(token) = session.get() {
+        request.headers_mut().insert("X-Session-Token", token.parse()?);
+    }
+    request.headers_mut().insert(WIRE_FORMAT_HEADER, connection_info.get_wire_format().as_str().parse()?);
     
     Ok(connect_async(request).await?)
 }
 
-async fn handle_packet(packet: WebSocketMessage, from_server_sender: UnboundedSender<WebSocketMessage>) -> Option<WebSocketMessage> {
+fn encode(wire_format: WireFormat, message: &WebSocketMessage) -> Message {
+    match wire_format.encode(message) {
+        EncodedMessage::Text(text) => Message::text(text),
+        EncodedMessage::Binary(bytes) => Message::binary(bytes),
+    }
+}
+
+async fn handle_packet(packet: WebSocketMessage, from_server_sender: &QueueSender<WebSocketMessage>) -> Option<WebSocketMessage> {
     match packet.command {
         // From Server
         //CommandType::Welcome { .. } => {}
@@ -52,36 +169,44 @@ async fn handle_packet(packet: WebSocketMessage, from_server_sender: UnboundedSe
         CommandType::SetConnectionInfo { .. } => {None}
         // Pass Through to Client
         _ => {
-            if from_server_sender.is_closed() {
-                return None;
-            } else {
-                from_server_sender.send(packet).unwrap();
-            }
+            // Waits while the app catches up, unless the queue's policy says otherwise
+            let _ = from_server_sender.send(packet).await;
             None
         }
     }
 }
 
-async fn websocket_loop(socket: Websocket, from_server_sender: UnboundedSender<WebSocketMessage>, to_server_receiver: UnboundedReceiver<WebSocketMessage>) {
+async fn websocket_loop(socket: Websocket, from_server_sender: QueueSender<WebSocketMessage>, mut to_server_receiver: QueueReceiver<WebSocketMessage>, requests: PendingRequests, session: SessionToken, heartbeat: Heartbeat) {
 
-    let mut to_server_stream = UnboundedReceiverStream::new(to_server_receiver);
+    // JSON until the server's Welcome says it accepts what was asked for
+    let mut wire_format = WireFormat::Json;
     let mut connected = true;
     let (mut socket_tx, mut socket_rx) = socket.split();
+    let mut ping_timer = tokio::time::interval_at((Instant::now() + heartbeat.ping_interval).into(), heartbeat.ping_interval);
+    let mut last_seen = Instant::now();
 
 
     while connected {
         select! {
-            message = to_server_stream.next() => {
+            message = to_server_receiver.recv() => {
                 // Client wants to send something out
-                if let Some(message) = message {
+                if let Some(mut message) = message {
                     if matches!(message.command, CommandType::Disconnect)
                     {
+                        // The server ends the session instead of holding it for a resume, the socket may be gone already
+                        let _ = socket_tx.send(encode(wire_format, &message)).await;
                         connected = false;
                         continue;
                     }
 
-                    let reply = serde_json::to_string(&message).expect("Failed to serialize Outgoing Message");
-                    socket_tx.send(Message::text(reply)).await.expect("Failed to send message");
+                    if message.id.is_none() {
+                        message.id = Some(requests.next_id());
+                    }
+
+                    socket_tx.send(encode(wire_format, &message)).await.expect("Failed to send message");
+                } else {
+                    // Every sender is gone, the app dropped the connection
+                    connected = false;
                 }
             }
             message = socket_rx.next() => {
@@ -96,53 +221,91 @@ async fn websocket_loop(socket: Websocket, from_server_sender: UnboundedSender<W
                     if !message.is_ok() {
                         continue
                     }
+                    last_seen = Instant::now();
                     let message = message.unwrap();
-                    if !message.is_text() {
-                        continue
-                    }
-                    let packet: serde_json::error::Result<WebSocketMessage> = serde_json::from_str(message.to_text().unwrap());
+                    let packet = match &message {
+                        Message::Text(text) => WebSocketMessage::from_text(text),
+                        Message::Binary(bytes) => WebSocketMessage::from_binary(bytes),
+                        _ => continue
+                    };
                     if let Ok(packet) = packet {
-                        let reply = handle_packet(packet, from_server_sender.clone()).await;
+                        // Replies to a pending request go straight to whoever is awaiting them
+                        let Some(packet) = requests.resolve(packet) else {
+                            continue
+                        };
+
+                        if let CommandType::Welcome { session_token, wire_format: accepted, .. } = &packet.command {
+                            session.set(session_token.clone());
+                            wire_format = accepted.unwrap_or_default();
+                        }
+
+                        let reply = handle_packet(packet, &from_server_sender).await;
                         if let Some(reply) = reply {
-                            let reply = serde_json::to_string(&reply).expect("Failed to serialize Outgoing Message");
-                            socket_tx.send(Message::text(reply)).await.expect("Failed to send message");
+                            socket_tx.send(encode(wire_format, &reply)).await.expect("Failed to send message");
                         }
                     }
+                } else {
+                    // The server closed the connection
+                    connected = false;
+                }
+            }
+            _ = ping_timer.tick() => {
+                // Any frame counts as a sign of life, the pong to our ping included
+                if last_seen.elapsed() > heartbeat.idle_timeout {
+                    connected = false;
+                    continue;
+                }
+
+                if socket_tx.send(Message::Ping(Default::default())).await.is_err() {
+                    connected = false;
                 }
             }
         }
     }
 
-    socket_tx.close().await.expect("Failed to close socket");
+    requests.disconnect_all();
+    // The socket may already be gone if the server dropped the connection
+    let _ = socket_tx.close().await;
 }
 
-async fn start_websocket_connection(connection_info: &impl ConnectionSettings) -> Result<(UnboundedSender<WebSocketMessage>, UnboundedReceiver<WebSocketMessage>), Error> {
-    let test = connect(connection_info).await;
+async fn start_websocket_connection(connection_info: &impl ConnectionSettings, requests: PendingRequests, session: SessionToken) -> Result<(QueueSender<WebSocketMessage>, QueueReceiver<WebSocketMessage>), Error> {
+    let test = connect(connection_info, &session).await;
     if test.is_err()
     {
         return Err(test.unwrap_err())
     }
 
     let (connection, _) = test?;
-    let (from_server_sender, from_server_receiver) = unbounded_channel::<WebSocketMessage>();
-    let (to_server_sender, to_server_receiver) = unbounded_channel::<WebSocketMessage>();
+    let (from_server_sender, from_server_receiver) = bounded_queue::<WebSocketMessage>(connection_info.get_queue_config());
+    let (to_server_sender, to_server_receiver) = bounded_queue::<WebSocketMessage>(connection_info.get_queue_config());
 
-    spawn(websocket_loop(connection, from_server_sender, to_server_receiver));
+    spawn(websocket_loop(connection, from_server_sender, to_server_receiver, requests, session, connection_info.get_heartbeat()));
 
     Ok((to_server_sender, from_server_receiver))
 }
 
 
-pub async fn connect_to_server_loop(connection_info: ThreadSafe<impl ConnectionSettings>, connection_state_changed: Arc<Notify>, status: &impl Status) -> (UnboundedSender<WebSocketMessage>, UnboundedReceiver<WebSocketMessage>) {
-    let mut connection: Option<(UnboundedSender<WebSocketMessage>, UnboundedReceiver<WebSocketMessage>)> = None;
+/// Keeps trying to connect until it succeeds, backing off between failed attempts.
+/// A change to the connection settings skips the remaining wait and starts over with the shortest delay.
+/// If `session` holds a token from an earlier connection, the server is asked to resume that session.
+pub async fn connect_to_server_loop(connection_info: ThreadSafe<impl ConnectionSettings>, connection_state_changed: Arc<Notify>, requests: PendingRequests, session: SessionToken, status: &impl Status) -> (QueueSender<WebSocketMessage>, QueueReceiver<WebSocketMessage>) {
+    let mut connection: Option<(QueueSender<WebSocketMessage>, QueueReceiver<WebSocketMessage>)> = None;
+    let mut backoff = Backoff::new(connection_info.lock().await.get_reconnect_policy());
 
     while connection.is_none() {
+        status.update_status("Connecting".to_string());
         let connection_state = {
-            start_websocket_connection(connection_info.lock().await.deref()).await
+            start_websocket_connection(connection_info.lock().await.deref(), requests.clone(), session.clone()).await
         };
-        if connection_state.is_err() {
-            status.update_status(connection_state.err().unwrap().to_string());
-            connection_state_changed.notified().await;
+        if let Err(e) = connection_state {
+            let delay = backoff.next_delay();
+            status.update_status(format!("{}, retrying in {}s", e, delay.as_secs().max(1)));
+            select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = connection_state_changed.notified() => {
+                    backoff = Backoff::new(connection_info.lock().await.get_reconnect_policy());
+                }
+            }
             continue;
         }
         status.update_status("Connected".to_string());
@@ -153,17 +316,25 @@ pub async fn connect_to_server_loop(connection_info: ThreadSafe<impl ConnectionS
 }
 
 
-pub async fn reconnect(sender: ThreadSafe<impl Sender>, connection_info: ThreadSafe<impl ConnectionSettings>, mut from_server: UnboundedReceiver<WebSocketMessage>, connection_state_changed: Arc<Notify>, status: &impl Status) -> UnboundedReceiver<WebSocketMessage> {
-    // Close connection and re-connect
-    sender.lock().await.try_send(WebSocketMessage{
+/// Drops the current connection, if it is still up, and connects again
+pub async fn reconnect(sender: ThreadSafe<impl Sender>, connection_info: ThreadSafe<impl ConnectionSettings>, mut from_server: QueueReceiver<WebSocketMessage>, connection_state_changed: Arc<Notify>, status: &impl Status) -> QueueReceiver<WebSocketMessage> {
+    // Close connection and re-connect. The websocket loop is already gone if the server dropped us.
+    let _ = sender.lock().await.try_send(WebSocketMessage{
         command: CommandType::Disconnect,
-        destination: Destination::None
-    }).expect("Failed to send message");
+        destination: Destination::None,
+        id: None,
+        in_reply_to: None,
+        durable_ttl: None,
+    });
 
     sender.lock().await.drop_connection();
     from_server.close();
 
-    let (to_server, new_from_server) = connect_to_server_loop(connection_info, connection_state_changed.clone(), status).await;
+    let (requests, session) = {
+        let sender = sender.lock().await;
+        (sender.get_requests(), sender.get_session())
+    };
+    let (to_server, new_from_server) = connect_to_server_loop(connection_info, connection_state_changed.clone(), requests, session, status).await;
 
     sender.lock().await.set_connection(to_server);
 
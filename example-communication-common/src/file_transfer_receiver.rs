@@ -1,97 +1,362 @@
 use std::path::{Path, PathBuf};
 use checkasum::hashing::{hash_file_path, hash_matches, HashAlgorithm};
-use tokio::fs::{create_dir_all, remove_file, File};
-use tokio::io::AsyncWriteExt;
-use crate::{CommandType, ThreadSafe};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{create_dir_all, remove_file, File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+use crate::{CommandType, ThreadSafe, TransferLimits};
+
+/// In-order chunks written between saves of the journal, a resumed transfer repeats at most this many
+const JOURNAL_INTERVAL: i32 = 64;
 
 pub struct FileTransferClient {
     pub name: String,
+    pub transfer_id: String,
+    /// `transfer_id` as parsed, the journal and partial file are named after it. `None` until the start arrives,
+    /// and for senders that predate transfer ids.
+    journal_id: Option<Uuid>,
+    /// Where acks and progress for this transfer go
+    pub return_uuid: String,
+    /// This end's own UUID, so the sender can tell which receiver answered
+    local_uuid: String,
 
     file_path: PathBuf,
-    file: File,
+    /// Where the file is written until it's complete, so transfers of files with the same name don't clash
+    partial_path: PathBuf,
+    journal_location: PathBuf,
+    // Only opened once the StartFileTransfer arrives, it says whether there's a partial file to resume
+    file: Option<File>,
     // For packets seen out-of-order so we can hold them until we're ready to write them to the file
     cached_packets: Vec<CommandType>,
     last_printed_packet: i32,
     packet_count: u64,
     expected_blob_size: usize,
     checksum: String,
+    limits: TransferLimits,
 }
 
 pub trait FileTransfer {
     fn get_transfer_location(&self) -> String;
+
+    /// Where progress of unfinished transfers is kept so they can resume after a restart
+    fn get_journal_location(&self) -> PathBuf {
+        Path::new(&self.get_transfer_location()).join(".transfers")
+    }
+
+    /// The largest chunks and window accepted from senders
+    fn get_transfer_limits(&self) -> TransferLimits {
+        TransferLimits::default()
+    }
+}
+
+/// What the receiver has written of a transfer, saved so it can pick up where it left off
+#[derive(Serialize, Deserialize)]
+struct TransferJournal {
+    transfer_id: String,
+    name: String,
+    checksum: String,
+    chunk_count: u64,
+    blob_size: usize,
+    confirmed_chunks: u64,
+}
+
+impl TransferJournal {
+    // Only ever named after a `Uuid`, a transfer id straight from the peer could point outside `journal_location`
+    fn path(journal_location: &Path, journal_id: &Uuid) -> PathBuf {
+        journal_location.join(format!("{}.json", journal_id))
+    }
+
+    fn partial_path(journal_location: &Path, journal_id: &Uuid) -> PathBuf {
+        journal_location.join(format!("{}.part", journal_id))
+    }
+
+    async fn load(path: &Path) -> Option<Self> {
+        let contents = tokio::fs::read_to_string(path).await.ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// The journal for `journal_id`, or one for the same file under another id, as left by a sender that restarted
+    async fn find(journal_location: &Path, journal_id: &Uuid, name: &str, checksum: &str) -> Option<Self> {
+        if let Some(journal) = Self::load(&Self::path(journal_location, journal_id)).await {
+            return Some(journal);
+        }
+
+        let mut entries = tokio::fs::read_dir(journal_location).await.ok()?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            // Partial files are kept alongside
+            if entry.path().extension().is_some_and(|extension| extension == "json")
+                && let Some(journal) = Self::load(&entry.path()).await
+                && journal.name == name && journal.checksum == checksum && journal.journal_id().is_some() {
+                remove_file(entry.path()).await.ok();
+                return Some(journal);
+            }
+        }
+        None
+    }
+
+    fn journal_id(&self) -> Option<Uuid> {
+        Uuid::parse_str(&self.transfer_id).ok()
+    }
+
+    async fn save(&self, journal_location: &Path, journal_id: &Uuid) {
+        if create_dir_all(journal_location).await.is_err() {
+            return;
+        }
+        let contents = serde_json::to_string(self).expect("Failed to serialize transfer journal");
+        // A journal that can't be written only costs the ability to resume
+        let _ = tokio::fs::write(Self::path(journal_location, journal_id), contents).await;
+    }
+}
+
+/// How many chunks of `transfer_id` were saved before this client last stopped, 0 if it doesn't know the transfer
+pub async fn journaled_progress(transfer_id: &str, settings: ThreadSafe<impl FileTransfer>) -> u64 {
+    let Ok(journal_id) = Uuid::parse_str(transfer_id) else {
+        return 0;
+    };
+    let journal_location = settings.lock().await.get_journal_location();
+    TransferJournal::load(&TransferJournal::path(&journal_location, &journal_id)).await
+        .map(|journal| journal.confirmed_chunks)
+        .unwrap_or_default()
 }
 
 impl FileTransferClient {
-    pub async fn new(name: String, settings: ThreadSafe<impl FileTransfer>) -> Self {
-        let destination = settings.lock().await.get_transfer_location();
+    pub async fn new(name: String, local_uuid: String, settings: ThreadSafe<impl FileTransfer>) -> Self {
+        let (destination, journal_location, limits) = {
+            let settings = settings.lock().await;
+            (settings.get_transfer_location(), settings.get_journal_location(), settings.get_transfer_limits())
+        };
         let path = Path::new(&destination).join(&name);
 
-        create_dir_all(path.parent().unwrap()).await.unwrap();
-        let file = File::create(path.clone()).await.unwrap();
-
-        Self {name, file_path: path, file, cached_packets: vec![], last_printed_packet: -1, packet_count: 0, expected_blob_size: 0, checksum: String::new()}
+        Self {name, transfer_id: String::new(), journal_id: None, return_uuid: String::new(), local_uuid, file_path: path, partial_path: PathBuf::new(), journal_location, file: None, cached_packets: vec![], last_printed_packet: -1, packet_count: 0, expected_blob_size: 0, checksum: String::new(), limits}
     }
 
     pub async fn close(&mut self) {
-        self.file.flush().await.unwrap();
+        if let Some(file) = &mut self.file {
+            file.flush().await.unwrap();
+        }
+    }
+
+    /// Chunks written in order from the first, the sender doesn't need to send these again
+    pub fn confirmed_chunks(&self) -> u64 {
+        (self.last_printed_packet + 1) as u64
+    }
+
+    /// Stops the transfer and throws away what was written of it
+    pub async fn discard(&mut self) {
+        // Dropping the file closes it, which some platforms need before it can be removed
+        self.file = None;
+        if self.partial_path.as_os_str().is_empty() {
+            return;
+        }
+        let _ = remove_file(&self.partial_path).await;
+        self.remove_journal().await;
+    }
+
+    /// Refuses the rest of the transfer, the returned command tells the sender
+    pub async fn refuse(&mut self, reason: String) -> CommandType {
+        self.discard().await;
+        CommandType::FileTransferCancel {
+            name: self.name.clone(),
+            transfer_id: self.transfer_id.clone(),
+            sender_uuid: self.local_uuid.clone(),
+            reason,
+        }
+    }
+
+    /// Tells the sender how far this transfer got
+    pub fn progress(&self) -> CommandType {
+        CommandType::FileTransferProgress {
+            name: self.name.clone(),
+            transfer_id: self.transfer_id.clone(),
+            sender_uuid: self.local_uuid.clone(),
+            confirmed_chunks: self.confirmed_chunks(),
+        }
+    }
+
+    fn ack(&self, start: bool, chunk_num: i32, whole: bool) -> CommandType {
+        CommandType::FileTransferAck {
+            name: self.name.clone(),
+            transfer_id: self.transfer_id.clone(),
+            sender_uuid: self.local_uuid.clone(),
+            start,
+            chunk_num,
+            whole,
+            limits: None,
+        }
+    }
+
+    fn nack(&self, start: bool, chunk_num: i32, whole: bool) -> CommandType {
+        CommandType::FileTransferNack {
+            name: self.name.clone(),
+            transfer_id: self.transfer_id.clone(),
+            sender_uuid: self.local_uuid.clone(),
+            start,
+            chunk_num,
+            whole,
+            limits: None,
+        }
+    }
+
+    /// Accepts the start with the window agreed on, or refuses it with what this end takes
+    fn start_reply(&self, accepted: bool, limits: TransferLimits) -> CommandType {
+        let (name, transfer_id, sender_uuid) = (self.name.clone(), self.transfer_id.clone(), self.local_uuid.clone());
+        if accepted {
+            CommandType::FileTransferAck { name, transfer_id, sender_uuid, start: true, chunk_num: 0, whole: false, limits: Some(limits) }
+        } else {
+            CommandType::FileTransferNack { name, transfer_id, sender_uuid, start: true, chunk_num: 0, whole: false, limits: Some(limits) }
+        }
+    }
+
+    /// Opens the file, picking up a partial one if a journal says how much of it was written.
+    /// `transfer_id` has to be empty or a valid `Uuid` by now.
+    async fn open(&mut self, chunk_count: u64, blob_size: usize) {
+        create_dir_all(&self.journal_location).await.unwrap();
+        self.journal_id = Uuid::parse_str(&self.transfer_id).ok();
+        // Senders that predate transfer ids can't resume, so their partial file only has to be unique
+        let partial_id = self.journal_id.unwrap_or_else(Uuid::new_v4);
+        self.partial_path = TransferJournal::partial_path(&self.journal_location, &partial_id);
+
+        let journal = match &self.journal_id {
+            Some(journal_id) => TransferJournal::find(&self.journal_location, journal_id, &self.name, &self.checksum).await
+                .filter(|journal| journal.chunk_count == chunk_count && journal.blob_size == blob_size),
+            None => None,
+        };
+        if let Some(journal) = &journal && journal.transfer_id != self.transfer_id
+            && let Some(earlier_id) = journal.journal_id() {
+            // Left by an earlier attempt at the same file, carry on with what it wrote
+            let _ = tokio::fs::rename(TransferJournal::partial_path(&self.journal_location, &earlier_id), &self.partial_path).await;
+        }
+        let resume_at = journal.map(|journal| journal.confirmed_chunks).unwrap_or_default();
+
+        let existing_length = tokio::fs::metadata(&self.partial_path).await.map(|metadata| metadata.len()).unwrap_or_default();
+        let resume_length = resume_at * blob_size as u64;
+        if resume_at > 0 && existing_length >= resume_length {
+            let mut file = OpenOptions::new().write(true).open(&self.partial_path).await.unwrap();
+            // Anything past the journal may not have been written completely
+            file.set_len(resume_length).await.unwrap();
+            file.seek(std::io::SeekFrom::End(0)).await.unwrap();
+            self.file = Some(file);
+            self.last_printed_packet = resume_at as i32 - 1;
+        } else {
+            self.file = Some(File::create(&self.partial_path).await.unwrap());
+        }
+
+        self.save_journal().await;
+    }
+
+    async fn save_journal(&mut self) {
+        let Some(journal_id) = self.journal_id else {
+            return;
+        };
+        if let Some(file) = &mut self.file {
+            // Only what reached the file may be counted as written
+            file.flush().await.unwrap();
+        }
+
+        TransferJournal {
+            transfer_id: self.transfer_id.clone(),
+            name: self.name.clone(),
+            checksum: self.checksum.clone(),
+            chunk_count: self.packet_count,
+            blob_size: self.expected_blob_size,
+            confirmed_chunks: self.confirmed_chunks(),
+        }.save(&self.journal_location, &journal_id).await;
+    }
+
+    async fn remove_journal(&self) {
+        if let Some(journal_id) = &self.journal_id {
+            let _ = remove_file(TransferJournal::path(&self.journal_location, journal_id)).await;
+        }
+    }
+
+    /// Puts the finished file in place of any file with the same name
+    async fn move_into_place(&self) -> std::io::Result<()> {
+        create_dir_all(self.file_path.parent().unwrap()).await?;
+        if tokio::fs::rename(&self.partial_path, &self.file_path).await.is_err() {
+            // The journal may be kept on another filesystem
+            tokio::fs::copy(&self.partial_path, &self.file_path).await?;
+            remove_file(&self.partial_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_blob(&mut self, blob: &[u8]) {
+        self.file.as_mut().expect("Blobs are only written after the start").write_all(blob).await.unwrap();
+        self.last_printed_packet += 1;
+
+        if (self.last_printed_packet + 1) % JOURNAL_INTERVAL == 0 {
+            self.save_journal().await;
+        }
     }
 
     pub async fn handle_packet(&mut self, new_packet: CommandType) -> (Vec<CommandType>, bool) {
         let mut return_packets: Vec<CommandType> = vec![];
 
         match new_packet.clone() {
-            CommandType::StartFileTransfer { chunk_count, blob_size, checksum, .. } => {
-                self.packet_count = chunk_count;
-                self.expected_blob_size = blob_size;
-                self.checksum = checksum;
-
-                return_packets.push(CommandType::FileTransferAck {
-                    name: self.name.clone(),
-                    start: true,
-                    chunk_num: 0,
-                    whole: false,
-                });
+            CommandType::StartFileTransfer { transfer_id, chunk_count, blob_size, window, checksum, return_uuid, .. } => {
+                self.return_uuid = return_uuid;
+                if self.file.is_none() {
+                    self.transfer_id = transfer_id;
+                }
+
+                if self.file.is_none() && !self.transfer_id.is_empty() && Uuid::parse_str(&self.transfer_id).is_err() {
+                    // Files are named after the id, so only ids that can't name another path are taken
+                    return_packets.push(self.refuse(format!("{} is not a valid transfer id", self.transfer_id)).await);
+                    return (return_packets, true);
+                }
+                else if self.file.is_none() && blob_size > self.limits.blob_size {
+                    // The sender starts over with chunks this end takes
+                    return_packets.push(self.start_reply(false, self.limits));
+                }
+                else {
+                    // The sender starts over after reconnecting, the file is already open then
+                    if self.file.is_none() {
+                        self.packet_count = chunk_count;
+                        self.expected_blob_size = blob_size;
+                        self.checksum = checksum;
+                        self.open(chunk_count, blob_size).await;
+                    }
+
+                    return_packets.push(self.start_reply(true, TransferLimits { blob_size, window: window.min(self.limits.window) }));
+
+                    // The sender assumes it starts from the first chunk unless told otherwise
+                    if self.confirmed_chunks() > 0 {
+                        return_packets.push(self.progress());
+                    }
+                }
             }
-            CommandType::FileTransferBlob { chunk_num, blob, .. } => {
+            CommandType::FileTransferBlob { transfer_id, chunk_num, blob, return_uuid, .. } => {
+                self.return_uuid = return_uuid;
 
                 if self.checksum.is_empty() {
+                    // The Nacks need to find their way back to this transfer
+                    self.transfer_id = transfer_id;
+
                     // Got a Blob first, Nack the Start
-                    return_packets.push(CommandType::FileTransferNack {
-                        name: self.name.clone(),
-                        start: true,
-                        chunk_num: 0,
-                        whole: false,
-                    });
+                    return_packets.push(self.nack(true, 0, false));
 
                     // Additionally, Nack any missed packets up until this point
                     for n in self.last_printed_packet + 1 .. chunk_num {
-                        return_packets.push(CommandType::FileTransferNack {
-                            name: self.name.clone(),
-                            start: false,
-                            chunk_num: n,
-                            whole: false,
-                        });
+                        return_packets.push(self.nack(false, n, false));
                     }
 
                     self.cached_packets.push(new_packet);
                 }
+                else if chunk_num <= self.last_printed_packet {
+                    // Written already, sent again after a resume or because its ack got lost
+                    return_packets.push(self.ack(false, chunk_num, false));
+                }
                 else if self.last_printed_packet + 1 == chunk_num {
-                    self.file.write_all(&blob).await.unwrap();
-                    return_packets.push(CommandType::FileTransferAck {
-                        name: self.name.clone(),
-                        start: false,
-                        chunk_num,
-                        whole: false,
-                    });
-                    self.last_printed_packet = chunk_num;
+                    self.write_blob(&blob).await;
+                    return_packets.push(self.ack(false, chunk_num, false));
 
-                    for packet in &self.cached_packets {
+                    let cached_packets = std::mem::take(&mut self.cached_packets);
+                    for packet in &cached_packets {
                         match packet {
                             CommandType::FileTransferBlob {chunk_num, blob, ..} => {
                                 if *chunk_num == self.last_printed_packet + 1 {
-                                    self.file.write_all(&blob).await.unwrap();
-
-                                    self.last_printed_packet += 1;
+                                    self.write_blob(blob).await;
+                                    return_packets.push(self.ack(false, *chunk_num, false));
                                     continue;
                                 }
                             }
@@ -100,6 +365,7 @@ impl FileTransferClient {
                             }
                         }
                     }
+                    self.cached_packets = cached_packets;
 
                     self.cached_packets.retain(|packet| {
                         return match packet {
@@ -128,53 +394,271 @@ impl FileTransferClient {
                         }
                     });
 
+                    // Only acked once it's written, the sender has to keep it until then in case this end restarts
                     for n in self.last_printed_packet + 1 .. chunk_num {
-                        return_packets.push(CommandType::FileTransferNack {
-                            name: self.name.clone(),
-                            start: false,
-                            chunk_num: n,
-                            whole: false,
-                        });
+                        return_packets.push(self.nack(false, n, false));
                     }
-
-                    return_packets.push(CommandType::FileTransferAck {
-                        name: self.name.clone(),
-                        start: false,
-                        chunk_num,
-                        whole: false,
-                    })
                 }
 
             }
+            CommandType::FileTransferQuery { return_uuid, .. } => {
+                self.return_uuid = return_uuid;
+                return_packets.push(self.progress());
+            }
+            CommandType::FileTransferCancel { .. } => {
+                self.discard().await;
+                return (return_packets, true);
+            }
             _ => {}
         }
 
         if self.last_printed_packet >= self.packet_count as i32 {
-            let hash = hash_file_path(&HashAlgorithm::SHA256, &*self.file_path);
+            self.close().await;
+            self.remove_journal().await;
+
+            let hash = hash_file_path(&HashAlgorithm::SHA256, &*self.partial_path);
             if let Ok(hash) = hash {
                 if !hash_matches(&*hash, self.checksum.as_str()) {
                     // File was not successfully transferred, Nack the whole file, delete it and have the sender restart
-                    return_packets.push(CommandType::FileTransferNack {
-                        name: self.name.clone(),
-                        start: false,
-                        chunk_num: 0,
-                        whole: true,
-                    });
-                    self.file.flush().await.unwrap();
-                    remove_file(self.file_path.clone()).await.unwrap();
+                    return_packets.push(self.nack(false, 0, true));
+                    remove_file(self.partial_path.clone()).await.unwrap();
+                }
+                else if self.move_into_place().await.is_err() {
+                    // Received fine but couldn't be saved under its name, have the sender try again
+                    return_packets.push(self.nack(false, 0, true));
                 }
                 else {
 
-                    return_packets.push(CommandType::FileTransferAck {
-                        name: self.name.clone(),
-                        start: false,
-                        chunk_num: 0,
-                        whole: true,
-                    });
+                    return_packets.push(self.ack(false, 0, true));
                 }
             }
         }
 
         (return_packets, self.last_printed_packet >= self.packet_count as i32)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use checkasum::hashing::{hash_file_path, HashAlgorithm};
+    use uuid::Uuid;
+    use crate::{make_thread_safe, CommandType, ThreadSafe};
+    use super::{journaled_progress, FileTransfer, FileTransferClient, JOURNAL_INTERVAL};
+
+    struct Settings(PathBuf);
+
+    impl FileTransfer for Settings {
+        fn get_transfer_location(&self) -> String {
+            self.0.to_str().unwrap().to_string()
+        }
+    }
+
+    fn start(transfer_id: &str, return_uuid: &str, checksum: &str) -> CommandType {
+        CommandType::StartFileTransfer {
+            name: "report.pdf".to_string(),
+            transfer_id: transfer_id.to_string(),
+            chunk_count: 1,
+            blob_size: 4,
+            window: 4,
+            checksum: checksum.to_string(),
+            return_uuid: return_uuid.to_string(),
+        }
+    }
+
+    fn blob(transfer_id: &str, return_uuid: &str, chunk_num: i32, blob: &[u8]) -> CommandType {
+        CommandType::FileTransferBlob {
+            name: "report.pdf".to_string(),
+            transfer_id: transfer_id.to_string(),
+            chunk_num,
+            blob: blob.to_vec(),
+            return_uuid: return_uuid.to_string(),
+        }
+    }
+
+    const ONE: &str = "00000000-0000-4000-8000-000000000001";
+    const TWO: &str = "00000000-0000-4000-8000-000000000002";
+
+    /// Bytes sent one per chunk, so the journal is saved part way through
+    const PAYLOAD_SIZE: usize = JOURNAL_INTERVAL as usize * 2 + 2;
+
+    fn location() -> PathBuf {
+        std::env::temp_dir().join(format!("transfers-{}", Uuid::new_v4()))
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..PAYLOAD_SIZE).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    fn checksum(location: &Path, contents: &[u8]) -> String {
+        std::fs::create_dir_all(location).unwrap();
+        let source = location.join("source");
+        std::fs::write(&source, contents).unwrap();
+        let checksum = hash_file_path(&HashAlgorithm::SHA256, &source).unwrap();
+        std::fs::remove_file(&source).unwrap();
+        checksum
+    }
+
+    fn start_payload(transfer_id: &str, checksum: &str) -> CommandType {
+        CommandType::StartFileTransfer {
+            name: "report.pdf".to_string(),
+            transfer_id: transfer_id.to_string(),
+            chunk_count: PAYLOAD_SIZE as u64,
+            blob_size: 1,
+            window: 4,
+            checksum: checksum.to_string(),
+            return_uuid: "controller".to_string(),
+        }
+    }
+
+    /// Sends the payload's chunks from `first` up to, not including, `last`, one byte each. The chunk after the
+    /// payload is empty, as the payload size is a multiple of the blob size.
+    async fn send_chunks(transfer_client: &mut FileTransferClient, transfer_id: &str, first: usize, last: usize) -> (Vec<CommandType>, bool) {
+        let payload = payload();
+        let mut outcome = (Vec::new(), false);
+        for chunk_num in first..last {
+            let chunk = payload.get(chunk_num..chunk_num + 1).unwrap_or_default();
+            outcome = transfer_client.handle_packet(blob(transfer_id, "controller", chunk_num as i32, chunk)).await;
+        }
+        outcome
+    }
+
+    /// Starts a transfer and writes `chunks` chunks of it before the client goes away without finishing
+    async fn interrupted(settings: ThreadSafe<Settings>, transfer_id: &str, checksum: &str, chunks: usize) {
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings).await;
+        transfer_client.handle_packet(start_payload(transfer_id, checksum)).await;
+        send_chunks(&mut transfer_client, transfer_id, 0, chunks).await;
+    }
+
+    fn confirmed(replies: &[CommandType]) -> Option<u64> {
+        replies.iter().find_map(|reply| match reply {
+            CommandType::FileTransferProgress { confirmed_chunks, .. } => Some(*confirmed_chunks),
+            _ => None
+        })
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_journal_after_a_restart() {
+        let location = location();
+        let checksum = checksum(&location, &payload());
+        let settings = make_thread_safe(Settings(location.clone()));
+        let written = JOURNAL_INTERVAL as usize + 6;
+        interrupted(settings.clone(), ONE, &checksum, written).await;
+
+        // Only what the journal vouches for counts, the chunks written after it may be incomplete
+        assert_eq!(journaled_progress(ONE, settings.clone()).await, JOURNAL_INTERVAL as u64);
+        let partial = location.join(".transfers").join(format!("{}.part", ONE));
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), written as u64);
+
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+        let (replies, _) = transfer_client.handle_packet(start_payload(ONE, &checksum)).await;
+        assert!(matches!(replies[0], CommandType::FileTransferAck { start: true, .. }));
+        assert_eq!(confirmed(&replies), Some(JOURNAL_INTERVAL as u64));
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), JOURNAL_INTERVAL as u64);
+
+        let (replies, finished) = send_chunks(&mut transfer_client, ONE, JOURNAL_INTERVAL as usize, PAYLOAD_SIZE + 1).await;
+        assert!(finished);
+        assert!(replies.iter().any(|reply| matches!(reply, CommandType::FileTransferAck { whole: true, .. })));
+        assert_eq!(std::fs::read(location.join("report.pdf")).unwrap(), payload());
+        assert_eq!(journaled_progress(ONE, settings).await, 0);
+        assert_eq!(std::fs::read_dir(location.join(".transfers")).unwrap().count(), 0);
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn picks_up_the_journal_of_an_earlier_attempt_at_the_same_file() {
+        let location = location();
+        let checksum = checksum(&location, &payload());
+        let settings = make_thread_safe(Settings(location.clone()));
+        interrupted(settings.clone(), ONE, &checksum, JOURNAL_INTERVAL as usize).await;
+
+        // The sender restarted and picked a new id for the same file
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+        let (replies, _) = transfer_client.handle_packet(start_payload(TWO, &checksum)).await;
+
+        assert_eq!(confirmed(&replies), Some(JOURNAL_INTERVAL as u64));
+        assert_eq!(journaled_progress(ONE, settings.clone()).await, 0);
+        assert_eq!(journaled_progress(TWO, settings).await, JOURNAL_INTERVAL as u64);
+        let (_, finished) = send_chunks(&mut transfer_client, TWO, JOURNAL_INTERVAL as usize, PAYLOAD_SIZE + 1).await;
+        assert!(finished);
+        assert_eq!(std::fs::read(location.join("report.pdf")).unwrap(), payload());
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_the_partial_file_is_shorter_than_the_journal() {
+        let location = location();
+        let checksum = checksum(&location, &payload());
+        let settings = make_thread_safe(Settings(location.clone()));
+        interrupted(settings.clone(), ONE, &checksum, JOURNAL_INTERVAL as usize).await;
+        let partial = location.join(".transfers").join(format!("{}.part", ONE));
+        std::fs::OpenOptions::new().write(true).open(&partial).unwrap().set_len(10).unwrap();
+
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+        let (replies, _) = transfer_client.handle_packet(start_payload(ONE, &checksum)).await;
+
+        assert_eq!(confirmed(&replies), None);
+        assert_eq!(std::fs::metadata(&partial).unwrap().len(), 0);
+        assert_eq!(journaled_progress(ONE, settings).await, 0);
+        let (_, finished) = send_chunks(&mut transfer_client, ONE, 0, PAYLOAD_SIZE + 1).await;
+        assert!(finished);
+        assert_eq!(std::fs::read(location.join("report.pdf")).unwrap(), payload());
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_ids_that_are_not_uuids_are_refused() {
+        let location = location();
+        let settings = make_thread_safe(Settings(location.join("inbox")));
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+
+        let (replies, finished) = transfer_client.handle_packet(start("../../escaped", "controller", "checksum")).await;
+
+        assert!(finished);
+        assert!(matches!(&replies[..], [CommandType::FileTransferCancel { transfer_id, .. }] if transfer_id == "../../escaped"));
+        assert!(!location.exists());
+        assert_eq!(journaled_progress("../../escaped", settings).await, 0);
+    }
+
+    #[tokio::test]
+    async fn transfers_with_the_same_name_do_not_clash() {
+        let location = location();
+        let checksum = checksum(&location, b"firstpart");
+        let settings = make_thread_safe(Settings(location.clone()));
+
+        let mut first = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+        let mut second = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings.clone()).await;
+        first.handle_packet(start(ONE, "controller-a", &checksum)).await;
+        second.handle_packet(start(TWO, "controller-b", "not the checksum")).await;
+        first.handle_packet(blob(ONE, "controller-a", 0, b"firs")).await;
+        second.handle_packet(blob(TWO, "controller-b", 0, b"junk")).await;
+        let (replies, finished) = first.handle_packet(blob(ONE, "controller-a", 1, b"tpart")).await;
+
+        assert!(finished);
+        assert!(replies.iter().any(|reply| matches!(reply, CommandType::FileTransferAck { transfer_id, sender_uuid, whole: true, .. } if transfer_id == ONE && sender_uuid == "client")));
+        assert_eq!(std::fs::read(location.join("report.pdf")).unwrap(), b"firstpart");
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_throws_away_the_partial_file() {
+        let location = location();
+        let settings = make_thread_safe(Settings(location.clone()));
+        let mut transfer_client = FileTransferClient::new("report.pdf".to_string(), "client".to_string(), settings).await;
+        transfer_client.handle_packet(start(ONE, "controller", "checksum")).await;
+        transfer_client.handle_packet(blob(ONE, "controller", 0, b"firs")).await;
+
+        let (replies, finished) = transfer_client.handle_packet(CommandType::FileTransferCancel {
+            name: "report.pdf".to_string(),
+            transfer_id: ONE.to_string(),
+            sender_uuid: "controller".to_string(),
+            reason: "cancelled by the sender".to_string(),
+        }).await;
+
+        assert!(finished);
+        assert!(replies.is_empty());
+        assert!(!location.join("report.pdf").exists());
+        assert_eq!(std::fs::read_dir(location.join(".transfers")).unwrap().count(), 0);
+        std::fs::remove_dir_all(&location).unwrap();
+    }
+}
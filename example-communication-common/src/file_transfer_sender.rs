@@ -1,16 +1,225 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::io::SeekFrom;
 use std::path::Path;
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::Instant;
 use bytes::BytesMut;
-use crate::{CommandType, Destination, Sender, ThreadSafe, WebSocketMessage};
+use crate::{bounded_queue, CommandType, Destination, QueueConfig, QueueReceiver, QueueSender, Sender, ThreadSafe, TransferKey, WebSocketMessage};
 use checkasum::hashing::{hash_file_path, HashAlgorithm};
 
-const MAX_ACTIVE_PACKETS: usize = 5;
-const BLOB_SIZE: usize = 1024;
+/// Acks taking this many times longer than the quickest one mean queues are building up on the way
+const LATENCY_GROWTH_LIMIT: u32 = 2;
+/// Each resend of a packet waits twice as long as the last, up to this many doublings
+const MAX_BACKOFF_DOUBLINGS: u32 = 5;
 
-pub async fn start_file_transfer(file_path: impl AsRef<Path> + Clone, destination_uuid: String, client_cache: ThreadSafe<impl Sender + Send + Sync + 'static>) -> Option<UnboundedSender<CommandType>>
+/// How a file is sent. The receiver may ask for smaller chunks or a smaller window.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferConfig {
+    /// In bytes
+    pub blob_size: usize,
+    /// Chunks in flight when the transfer starts
+    pub initial_window: usize,
+    /// Most chunks in flight, however well the transfer goes
+    pub max_window: usize,
+    /// How long to wait on the receiver to answer a packet before sending it again
+    pub retransmit_timeout: Duration,
+    /// Times a packet is sent again for lack of an answer before the transfer is given up
+    pub max_retries: u32,
+    /// How long the whole transfer may take
+    pub transfer_timeout: Duration,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            blob_size: 64 * 1024,
+            initial_window: 4,
+            max_window: 128,
+            retransmit_timeout: Duration::from_secs(2),
+            max_retries: 5,
+            transfer_timeout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Why a file transfer was given up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// The transfer took longer than `TransferConfig::transfer_timeout`
+    TimedOut,
+    /// The receiver didn't answer a packet however often it was sent
+    NoResponse,
+    /// The transfer stopped before it could finish, nobody was left to pass on the receiver's replies
+    Abandoned,
+    /// Cancelled through `FileTransferHandle::cancel`
+    Cancelled,
+    /// The receiver cancelled the transfer
+    Refused { reason: String },
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::TimedOut => {write!(f, "the transfer took too long")}
+            TransferError::NoResponse => {write!(f, "the receiver stopped answering")}
+            TransferError::Abandoned => {write!(f, "the transfer stopped before it finished")}
+            TransferError::Cancelled => {write!(f, "the transfer was cancelled")}
+            TransferError::Refused { reason } => {write!(f, "the receiver cancelled the transfer: {}", reason)}
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// Chunks allowed in flight. Grows by one for every window's worth of acks and halves when a chunk is lost (AIMD),
+/// but stops growing while acks come back slower than they used to.
+struct CongestionWindow {
+    size: f64,
+    max: usize,
+    min_latency: Option<Duration>,
+    /// Chunks sent before the last loss, losing more of them doesn't halve the window again
+    recovering_until: i32,
+}
+
+impl CongestionWindow {
+    fn new(initial: usize, max: usize) -> Self {
+        let max = max.max(1);
+        Self { size: initial.clamp(1, max) as f64, max, min_latency: None, recovering_until: 0 }
+    }
+
+    fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn limit(&mut self, max: usize) {
+        self.max = self.max.min(max.max(1));
+        self.size = self.size.min(self.max as f64);
+    }
+
+    /// `latency` is left out for resent chunks, as it's unclear which send the ack is for
+    fn on_ack(&mut self, latency: Option<Duration>) {
+        if let Some(latency) = latency {
+            let min_latency = self.min_latency.map_or(latency, |min_latency| min_latency.min(latency));
+            self.min_latency = Some(min_latency);
+            if latency > min_latency * LATENCY_GROWTH_LIMIT {
+                return;
+            }
+        }
+        self.size = (self.size + 1.0 / self.size).min(self.max as f64);
+    }
+
+    fn on_loss(&mut self, chunk_num: i32, next_chunk: i32) {
+        if chunk_num < self.recovering_until {
+            return;
+        }
+        self.size = (self.size / 2.0).max(1.0);
+        self.recovering_until = next_chunk;
+    }
+}
+
+/// A packet the receiver hasn't acked yet
+struct InFlight {
+    command: CommandType,
+    sent_at: Instant,
+    resent: bool,
+    /// Resends for lack of an answer
+    retries: u32,
+}
+
+impl InFlight {
+    fn new(command: CommandType) -> Self {
+        Self { command, sent_at: Instant::now(), resent: false, retries: 0 }
+    }
+
+    /// When to send the packet again if it isn't answered
+    fn deadline(&self, retransmit_timeout: Duration) -> Instant {
+        self.sent_at + retransmit_timeout * 2u32.pow(self.retries.min(MAX_BACKOFF_DOUBLINGS))
+    }
+
+    fn mark_resent(&mut self) {
+        self.sent_at = Instant::now();
+        self.resent = true;
+    }
+
+    fn chunk_num(&self) -> Option<i32> {
+        match self.command {
+            CommandType::FileTransferBlob { chunk_num, .. } => Some(chunk_num),
+            _ => None
+        }
+    }
+}
+
+/// Points the receiver's replies to `packet` at `uuid`, this end's UUID changes if the server didn't resume its session
+fn set_return_uuid(packet: &mut CommandType, uuid: &str) {
+    match packet {
+        CommandType::StartFileTransfer { return_uuid, .. } | CommandType::FileTransferBlob { return_uuid, .. } => {
+            *return_uuid = uuid.to_string();
+        }
+        _ => {}
+    }
+}
+
+enum TransferEvent {
+    /// An Ack, Nack or progress report from the receiver
+    Reply(CommandType),
+    /// Ask the receiver how far it got, as packets may have been lost while disconnected
+    Resume,
+}
+
+/// A file being sent in the background
+#[derive(Clone)]
+pub struct FileTransferHandle {
+    pub name: String,
+    pub transfer_id: String,
+    pub destination_uuid: String,
+    events: QueueSender<TransferEvent>,
+    /// Kept apart from `events`, so a transfer that's behind on replies can still be cancelled
+    cancelled: watch::Sender<bool>,
+    outcome: watch::Receiver<Option<Result<(), TransferError>>>,
+}
+
+impl FileTransferHandle {
+    /// Matches the `transfer_key` of the receiver's replies
+    pub fn key(&self) -> TransferKey {
+        TransferKey::new(&self.destination_uuid, &self.transfer_id, &self.name)
+    }
+
+    /// Passes on a reply from the receiver, waiting for room if the transfer is behind
+    pub async fn handle_reply(&self, command: CommandType) {
+        let _ = self.events.send(TransferEvent::Reply(command)).await;
+    }
+
+    /// Has the transfer check with the receiver how far it got and carry on from there, e.g. after reconnecting
+    pub fn resume(&self) {
+        let _ = self.events.try_send(TransferEvent::Resume);
+    }
+
+    /// Stops the transfer, the receiver throws away what it got so far
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.events.is_closed()
+    }
+
+    /// Waits for the receiver to have the whole file, or for the transfer to be given up
+    pub async fn finished(&self) -> Result<(), TransferError> {
+        let mut outcome = self.outcome.clone();
+        match outcome.wait_for(|outcome| outcome.is_some()).await {
+            Ok(outcome) => outcome.clone().unwrap_or(Err(TransferError::Abandoned)),
+            // The transfer task went away without saying how it ended
+            Err(_) => Err(TransferError::Abandoned),
+        }
+    }
+}
+
+/// Starts sending the file in the background. The returned handle takes the receiver's replies, queued as set by `queue_config`.
+pub async fn start_file_transfer(file_path: impl AsRef<Path> + Clone, destination_uuid: String, client_cache: ThreadSafe<impl Sender + Send + Sync + 'static>, config: TransferConfig, queue_config: QueueConfig) -> Option<FileTransferHandle>
 {
     if !file_path.as_ref().is_file() {
         return None;
@@ -23,133 +232,550 @@ pub async fn start_file_transfer(file_path: impl AsRef<Path> + Clone, destinatio
         let hash = hash_file_path(&HashAlgorithm::SHA256, file_path.as_ref());
         if hash.is_ok()
         {
-            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<CommandType>();
-            tokio::spawn(file_transfer_loop(file, hash.unwrap(), destination_uuid, file_path.as_ref().file_name().unwrap().to_str().unwrap().to_string(), receiver, client_cache));
-            return Some(sender);
+            let name = file_path.as_ref().file_name().unwrap().to_str().unwrap().to_string();
+            let transfer_id = uuid::Uuid::new_v4().to_string();
+            let (events, receiver) = bounded_queue::<TransferEvent>(queue_config);
+            let (cancelled, cancellation) = watch::channel(false);
+            let (outcome_sender, outcome) = watch::channel(None);
+            let transfer = file_transfer_loop(file, hash.unwrap(), destination_uuid.clone(), name.clone(), transfer_id.clone(), config, receiver, cancellation, client_cache);
+            tokio::spawn(async move {
+                outcome_sender.send_replace(Some(transfer.await));
+            });
+            return Some(FileTransferHandle { name, transfer_id, destination_uuid, events, cancelled, outcome });
         }
     }
     None
 }
 
-async fn read_packet(file: &mut File) -> BytesMut {
-    let mut buffer = BytesMut::with_capacity(BLOB_SIZE);
+async fn read_packet(file: &mut File, blob_size: usize) -> BytesMut {
+    let mut buffer = BytesMut::with_capacity(blob_size);
 
-    file.read_buf(&mut buffer).await.unwrap();
+    // A single read may come up short of a whole chunk
+    while buffer.len() < blob_size {
+        if file.read_buf(&mut buffer).await.unwrap() == 0 {
+            break;
+        }
+    }
 
     buffer
 }
 
-async fn file_transfer_loop(mut file: File, checksum: String, destination_uuid: String, file_name: String, mut receiver: UnboundedReceiver<CommandType>, client_cache: ThreadSafe<impl Sender>) {
-    let mut active_packets: Vec<CommandType> = Vec::new();
+async fn send_packet(client_cache: &ThreadSafe<impl Sender>, destination_uuid: &str, command: CommandType) {
+    let sent = client_cache.lock().await.send(WebSocketMessage {
+        command,
+        destination: Destination::Single { destination_uuid: destination_uuid.to_string() },
+        id: None,
+        in_reply_to: None,
+        durable_ttl: None,
+    });
+    // A packet that can't be queued, e.g. while the connection is being replaced, counts as lost on the way and is
+    // sent again once it's due
+    let _ = sent.await;
+}
+
+/// Resolves with true once a handle cancels the transfer, or with false if all of them went away without doing so
+async fn cancelled(cancellation: &mut watch::Receiver<bool>) -> bool {
+    cancellation.wait_for(|cancelled| *cancelled).await.is_ok()
+}
+
+/// Tells the receiver to throw away what it got, as the transfer ended with `error`
+async fn give_up(client_cache: &ThreadSafe<impl Sender>, destination_uuid: &str, name: &str, transfer_id: &str, sender_uuid: &str, error: TransferError) -> Result<(), TransferError> {
+    send_packet(client_cache, destination_uuid, CommandType::FileTransferCancel {
+        name: name.to_string(),
+        transfer_id: transfer_id.to_string(),
+        sender_uuid: sender_uuid.to_string(),
+        reason: error.to_string(),
+    }).await;
+    Err(error)
+}
+
+// Everything the transfer needs is handed over once, when it is spawned
+#[allow(clippy::too_many_arguments)]
+async fn file_transfer_loop(mut file: File, checksum: String, destination_uuid: String, file_name: String, transfer_id: String, config: TransferConfig, mut receiver: QueueReceiver<TransferEvent>, mut cancellation: watch::Receiver<bool>, client_cache: ThreadSafe<impl Sender>) -> Result<(), TransferError> {
+    let transfer_deadline = Instant::now() + config.transfer_timeout;
+    let mut active_packets: Vec<InFlight> = Vec::new();
     let filesize = file.metadata().await.unwrap().len();
-    let blob_count = filesize / BLOB_SIZE as u64;
-    let mut chunk_num = 0;
-    let return_uuid = client_cache.lock().await.get_uuid();
+    let mut blob_size = config.blob_size.max(1);
+    let mut window = CongestionWindow::new(config.initial_window, config.max_window);
+    let mut next_chunk = 0;
+    let mut return_uuid = client_cache.lock().await.get_uuid();
 
-    // Generate the file's checksum
     'finish: loop {
-        let opening_packet = CommandType::StartFileTransfer {
+        let blob_count = filesize / blob_size as u64;
+        let mut opening_packet = CommandType::StartFileTransfer {
             name: file_name.clone(),
+            transfer_id: transfer_id.clone(),
             chunk_count: blob_count,
-            blob_size: BLOB_SIZE,
+            blob_size,
+            window: config.max_window,
             checksum: checksum.clone(),
             return_uuid: return_uuid.clone(),
         };
 
-        active_packets.push(opening_packet.clone());
-
-        client_cache.lock().await.try_send(WebSocketMessage {
-            command: opening_packet,
-            destination: Destination::Single { destination_uuid: destination_uuid.clone() },
-        }).expect("Error sending command to the destination");
+        active_packets.push(InFlight::new(opening_packet.clone()));
+        send_packet(&client_cache, &destination_uuid, opening_packet.clone()).await;
+        // Chunks only follow once the receiver agreed on their size
+        let mut started = false;
 
         loop {
 
-            // fill out active_packets until == MAX_ACTIVE_PACKETS are not Acked
-            if file.stream_position().await.unwrap() < filesize {
-                while active_packets.len() < MAX_ACTIVE_PACKETS {
-                    // generate & send packets
-                    let new_packet = CommandType::FileTransferBlob {
-                        name: file_name.clone(),
-                        chunk_num: chunk_num.clone(),
-                        blob: Vec::from(read_packet(&mut file).await),
-                        return_uuid: return_uuid.clone(),
-                    };
-                    chunk_num += 1;
-
-                    active_packets.push(new_packet.clone());
+            // fill out active_packets until the window is full of packets that are not Acked.
+            // The last chunk is short, or empty when the file size is a multiple of the blob size.
+            while started && active_packets.len() < window.size() && next_chunk as u64 <= blob_count {
+                // generate & send packets
+                let new_packet = CommandType::FileTransferBlob {
+                    name: file_name.clone(),
+                    transfer_id: transfer_id.clone(),
+                    chunk_num: next_chunk,
+                    blob: Vec::from(read_packet(&mut file, blob_size).await),
+                    return_uuid: return_uuid.clone(),
+                };
+                next_chunk += 1;
 
-                    client_cache.lock().await.try_send(WebSocketMessage {
-                        command: new_packet,
-                        destination: Destination::Single { destination_uuid: destination_uuid.clone() },
-                    }).expect("Error sending command to the destination");
-                }
+                active_packets.push(InFlight::new(new_packet.clone()));
+                send_packet(&client_cache, &destination_uuid, new_packet).await;
             }
 
-            // wait for receiver to get Ack and Nack packets to either resend or remove & send new packets to destination
-            let command = receiver.recv().await;
+            // wait for receiver to get Ack and Nack packets to either resend or remove & send new packets to destination,
+            // or for the oldest unanswered packet to be due again
+            let deadline = active_packets.iter()
+                .map(|packet| packet.deadline(config.retransmit_timeout))
+                .min()
+                .map_or(transfer_deadline, |deadline| deadline.min(transfer_deadline));
+            let waited = tokio::select! {
+                biased;
+                true = cancelled(&mut cancellation) => {
+                    return give_up(&client_cache, &destination_uuid, &file_name, &transfer_id, &return_uuid, TransferError::Cancelled).await;
+                }
+                waited = tokio::time::timeout_at(deadline, receiver.recv()) => waited,
+            };
+            let event = match waited {
+                Ok(event) => event,
+                Err(_) if Instant::now() >= transfer_deadline => {
+                    return give_up(&client_cache, &destination_uuid, &file_name, &transfer_id, &return_uuid, TransferError::TimedOut).await;
+                }
+                Err(_) => {
+                    // Either the packets or their answers got lost on the way
+                    let now = Instant::now();
+                    let mut lost = None;
+                    for packet in active_packets.iter_mut().filter(|packet| packet.deadline(config.retransmit_timeout) <= now) {
+                        if packet.retries >= config.max_retries {
+                            return give_up(&client_cache, &destination_uuid, &file_name, &transfer_id, &return_uuid, TransferError::NoResponse).await;
+                        }
+                        packet.retries += 1;
+                        packet.mark_resent();
+                        lost = lost.or(packet.chunk_num());
+                        send_packet(&client_cache, &destination_uuid, packet.command.clone()).await;
+                    }
+                    if let Some(chunk_num) = lost {
+                        window.on_loss(chunk_num, next_chunk);
+                    }
+                    continue;
+                }
+            };
+            let command = match event {
+                Some(TransferEvent::Reply(command)) => Some(command),
+                Some(TransferEvent::Resume) => {
+                    // Reconnecting may have given this end a new UUID
+                    return_uuid = client_cache.lock().await.get_uuid();
+                    set_return_uuid(&mut opening_packet, &return_uuid);
+                    // Time spent disconnected doesn't count against the packets
+                    for packet in active_packets.iter_mut() {
+                        packet.retries = 0;
+                        set_return_uuid(&mut packet.command, &return_uuid);
+                    }
+                    send_packet(&client_cache, &destination_uuid, CommandType::FileTransferQuery {
+                        name: file_name.clone(),
+                        transfer_id: transfer_id.clone(),
+                        return_uuid: return_uuid.clone(),
+                    }).await;
+                    None
+                }
+                // Nobody is left to pass on the receiver's replies
+                None => return Err(TransferError::Abandoned)
+            };
             if let Some(command) = command {
                 match command {
-                    CommandType::FileTransferAck { start, chunk_num, whole, .. } => {
+                    CommandType::FileTransferAck { start, chunk_num, whole, limits, .. } => {
                         if whole {
-                            file.seek(SeekFrom::Start(0)).await.unwrap();
-                            break 'finish;
+                            return Ok(());
                         }
 
                         if start {
-                            active_packets.retain(|command| {
-                                match command {
-                                    CommandType::StartFileTransfer { .. } => false,
-                                    _ => true
-                                }
-                            })
-                        } else {
-                            let ack_chunk_num = chunk_num;
-                            active_packets.retain(|command| {
-                                match command {
-                                    CommandType::FileTransferBlob { chunk_num, .. } => {
-                                        ack_chunk_num != *chunk_num
-                                    }
-                                    _ => true
-                                }
-                            })
+                            started = true;
+                            // Receivers that predate negotiating take whatever they're sent
+                            if let Some(limits) = limits {
+                                window.limit(limits.window);
+                            }
+                            active_packets.retain(|packet| packet.chunk_num().is_some());
+                        } else if let Some(position) = active_packets.iter().position(|packet| packet.chunk_num() == Some(chunk_num)) {
+                            let acked = active_packets.remove(position);
+                            window.on_ack((!acked.resent).then(|| acked.sent_at.elapsed()));
                         }
                     }
-                    CommandType::FileTransferNack { start, chunk_num, whole, .. } => {
+                    CommandType::FileTransferNack { start, chunk_num, whole, limits, .. } => {
                         if whole {
                             file.seek(SeekFrom::Start(0)).await.unwrap();
+                            active_packets.clear();
+                            next_chunk = 0;
+                            continue 'finish;
+                        }
+
+                        if start && let Some(limits) = limits {
+                            // The receiver won't take chunks this big, start over with ones it will
+                            blob_size = limits.blob_size.clamp(1, blob_size);
+                            window.limit(limits.window);
+                            file.seek(SeekFrom::Start(0)).await.unwrap();
+                            active_packets.clear();
+                            next_chunk = 0;
                             continue 'finish;
                         }
-                        'local: for packet in active_packets.iter() {
-                            let ack_chunk_num = chunk_num;
-                            match packet {
-                                CommandType::StartFileTransfer { .. } => {
-                                    if start {
-                                        client_cache.lock().await.try_send(WebSocketMessage {
-                                            command: packet.clone(),
-                                            destination: Destination::Single { destination_uuid: destination_uuid.clone() },
-                                        }).expect("Error sending command to the destination");
-
-                                        break 'local
-                                    }
-                                }
-                                CommandType::FileTransferBlob { chunk_num, .. } => {
-                                    if *chunk_num == ack_chunk_num {
-                                        client_cache.lock().await.try_send(WebSocketMessage {
-                                            command: packet.clone(),
-                                            destination: Destination::Single { destination_uuid: destination_uuid.clone() },
-                                        }).expect("Error sending command to the destination");
-
-                                        break 'local
-                                    }
-                                }
-                                _ => {}
+
+                        if !start {
+                            window.on_loss(chunk_num, next_chunk);
+                        }
+                        let resend = active_packets.iter_mut().find(|packet| {
+                            match packet.command {
+                                CommandType::StartFileTransfer { .. } => start,
+                                CommandType::FileTransferBlob { chunk_num: packet_chunk_num, .. } => !start && packet_chunk_num == chunk_num,
+                                _ => false
                             }
+                        });
+                        if let Some(packet) = resend {
+                            packet.mark_resent();
+                            send_packet(&client_cache, &destination_uuid, packet.command.clone()).await;
+                        } else if start {
+                            // The start was dropped once progress showed the receiver had it, but the receiver lost it
+                            // since, e.g. by restarting, and needs it again before taking more chunks
+                            active_packets.push(InFlight::new(opening_packet.clone()));
+                            send_packet(&client_cache, &destination_uuid, opening_packet.clone()).await;
                         }
                     }
+                    CommandType::FileTransferCancel { reason, .. } => {
+                        return Err(TransferError::Refused { reason });
+                    }
+                    CommandType::FileTransferProgress { confirmed_chunks, .. } => {
+                        // Everything before the confirmed chunks is written, the start included. Anything after them
+                        // may have been lost on the way, or with the receiver restarting since it was acked, or was
+                        // left over from an earlier attempt, so the transfer carries on from there.
+                        started = true;
+                        file.seek(SeekFrom::Start(confirmed_chunks * blob_size as u64)).await.unwrap();
+                        next_chunk = confirmed_chunks as i32;
+                        active_packets.clear();
+                    }
                     _ => {}
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::Instant;
+    use crate::{bounded_queue, make_thread_safe, CommandType, FileTransfer, FileTransferClient, PendingRequests, QueueConfig, QueueReceiver, QueueSender, Sender, SessionToken, WebSocketMessage};
+    use super::{start_file_transfer, CongestionWindow, TransferConfig, TransferError};
+
+    /// Senders from before the window was negotiated
+    const FIXED: TransferConfig = TransferConfig {
+        blob_size: 1024,
+        initial_window: 5,
+        max_window: 5,
+        retransmit_timeout: Duration::from_secs(2),
+        max_retries: 5,
+        transfer_timeout: Duration::from_secs(60),
+    };
+
+    /// Quick to notice lost packets, for a loopback that loses them
+    const IMPATIENT: TransferConfig = TransferConfig {
+        blob_size: 1024,
+        initial_window: 4,
+        max_window: 16,
+        retransmit_timeout: Duration::from_millis(20),
+        max_retries: 3,
+        transfer_timeout: Duration::from_secs(10),
+    };
+
+    struct Loopback {
+        connection: QueueSender<WebSocketMessage>,
+    }
+
+    impl Sender for Loopback {
+        fn get_uuid(&self) -> String {
+            "controller".to_string()
+        }
+        fn get_connection(&self) -> Option<QueueSender<WebSocketMessage>> {
+            Some(self.connection.clone())
+        }
+        fn drop_connection(&mut self) {}
+        fn set_connection(&mut self, _new_sender: QueueSender<WebSocketMessage>) {}
+        fn get_requests(&self) -> PendingRequests {
+            PendingRequests::default()
+        }
+        fn get_session(&self) -> SessionToken {
+            SessionToken::default()
+        }
+    }
+
+    struct Settings(PathBuf);
+
+    impl FileTransfer for Settings {
+        fn get_transfer_location(&self) -> String {
+            self.0.to_str().unwrap().to_string()
+        }
+    }
+
+    fn source_file(size: usize) -> (PathBuf, Vec<u8>) {
+        let location = std::env::temp_dir().join(format!("loopback-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&location).unwrap();
+        let source = location.join("payload.bin");
+        let payload: Vec<u8> = (0..size).map(|index| (index * 31 % 251) as u8).collect();
+        std::fs::write(&source, &payload).unwrap();
+        (source, payload)
+    }
+
+    /// Sends `size` bytes through an in-process stand-in for the server that delays every message by `latency`,
+    /// returning how long it took for the receiver to have the whole file
+    async fn send_over_loopback(size: usize, config: TransferConfig, latency: Duration) -> Duration {
+        send_over_lossy_loopback(size, config, latency, |_| false, None).await
+    }
+
+    /// Like `send_over_loopback`, but drops the messages from the sender for which `lost` holds. The receiver starts
+    /// over from its journal after handling `restart_after` messages, like a client that was restarted.
+    async fn send_over_lossy_loopback(size: usize, config: TransferConfig, latency: Duration, lost: impl Fn(usize) -> bool + Send + 'static, restart_after: Option<usize>) -> Duration {
+        let (source, payload) = source_file(size);
+        let location = source.parent().unwrap().to_path_buf();
+
+        let (to_server, mut from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let loopback = make_thread_safe(Loopback { connection: to_server });
+        let started_at = Instant::now();
+        let handle = start_file_transfer(source, "client".to_string(), loopback, config, QueueConfig::default()).await.unwrap();
+
+        // Stamped on the way in so messages keep their order and arrive `latency` later
+        let (to_receiver, mut at_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut index = 0;
+            while let Some(message) = from_sender.recv().await {
+                if !lost(index) {
+                    let _ = to_receiver.send((Instant::now() + latency, message.command));
+                }
+                index += 1;
+            }
+        });
+
+        let (to_sender, mut at_sender) = mpsc::unbounded_channel();
+        let replies = handle.clone();
+        tokio::spawn(async move {
+            while let Some((deliver_at, reply)) = at_sender.recv().await {
+                tokio::time::sleep_until(deliver_at).await;
+                replies.handle_reply(reply).await;
+            }
+        });
+
+        let (finished, received) = oneshot::channel();
+        let settings = make_thread_safe(Settings(location.join("received")));
+        tokio::spawn(async move {
+            let mut transfer_client = FileTransferClient::new("payload.bin".to_string(), "client".to_string(), settings.clone()).await;
+            let mut handled = 0;
+            while let Some((deliver_at, command)) = at_receiver.recv().await {
+                tokio::time::sleep_until(deliver_at).await;
+                if restart_after == Some(handled) {
+                    transfer_client = FileTransferClient::new("payload.bin".to_string(), "client".to_string(), settings.clone()).await;
+                }
+                handled += 1;
+                let (return_packets, data_finished) = transfer_client.handle_packet(command).await;
+                for packet in return_packets {
+                    let _ = to_sender.send((Instant::now() + latency, packet));
+                }
+                if data_finished {
+                    let _ = finished.send(started_at.elapsed());
+                    break;
+                }
+            }
+        });
+
+        let elapsed = tokio::time::timeout(Duration::from_secs(30), received).await.expect("Transfer didn't finish").unwrap();
+        assert_eq!(handle.finished().await, Ok(()));
+        assert_eq!(std::fs::read(location.join("received").join("payload.bin")).unwrap(), payload);
+        std::fs::remove_dir_all(&location).unwrap();
+        elapsed
+    }
+
+    /// Everything the transfer sent, once it's over
+    async fn sent_commands(mut from_sender: QueueReceiver<WebSocketMessage>) -> Vec<CommandType> {
+        let mut commands = Vec::new();
+        while let Some(message) = from_sender.recv().await {
+            commands.push(message.command);
+        }
+        commands
+    }
+
+    #[test]
+    fn window_grows_additively_and_halves_once_per_loss() {
+        let mut window = CongestionWindow::new(4, 64);
+        // A window's worth of acks, and one of the next
+        for _ in 0..5 {
+            window.on_ack(Some(Duration::from_millis(10)));
+        }
+        assert_eq!(window.size(), 5);
+
+        window.on_loss(10, 20);
+        window.on_loss(12, 20);
+        assert_eq!(window.size(), 2);
+
+        window.on_loss(20, 24);
+        assert_eq!(window.size(), 1);
+    }
+
+    #[test]
+    fn window_stops_growing_while_acks_slow_down() {
+        let mut window = CongestionWindow::new(4, 64);
+        window.on_ack(Some(Duration::from_millis(10)));
+        let size = window.size;
+
+        for _ in 0..8 {
+            window.on_ack(Some(Duration::from_millis(50)));
+        }
+
+        assert_eq!(window.size, size);
+    }
+
+    #[test]
+    fn window_keeps_to_the_agreed_limit() {
+        let mut window = CongestionWindow::new(4, 64);
+        window.limit(2);
+        for _ in 0..16 {
+            window.on_ack(None);
+        }
+        assert_eq!(window.size(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark, run with --ignored --nocapture to compare the windows"]
+    async fn benchmark_over_loopback() {
+        let size = 512 * 1024;
+        let latency = Duration::from_millis(2);
+
+        let fixed = send_over_loopback(size, FIXED, latency).await;
+        let adaptive = send_over_loopback(size, TransferConfig::default(), latency).await;
+
+        let throughput = |elapsed: Duration| size as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64();
+        println!("fixed window: {:?} ({:.1} MiB/s), adaptive window: {:?} ({:.1} MiB/s)", fixed, throughput(fixed), adaptive, throughput(adaptive));
+    }
+
+    #[tokio::test]
+    async fn receiver_limits_the_chunk_size() {
+        // The receiver takes at most 1 MiB chunks, the sender has to start over with those
+        let config = TransferConfig { blob_size: 4 * 1024 * 1024, ..TransferConfig::default() };
+        send_over_loopback(3 * 1024 * 1024, config, Duration::ZERO).await;
+    }
+
+    #[tokio::test]
+    async fn lost_packets_are_sent_again() {
+        // Every fifth message never arrives, the start included
+        send_over_lossy_loopback(64 * 1024, IMPATIENT, Duration::from_millis(1), |index| index % 5 == 0, None).await;
+    }
+
+    #[tokio::test]
+    async fn carries_on_after_the_receiver_restarts() {
+        // The receiver's journal is a few dozen chunks behind what it already acked when it restarts
+        send_over_lossy_loopback(256 * 1024, IMPATIENT, Duration::from_millis(1), |_| false, Some(100)).await;
+    }
+
+    #[tokio::test]
+    async fn carries_on_after_a_lossy_receiver_restarts() {
+        // Chunks held out of order are lost with the restart too
+        send_over_lossy_loopback(256 * 1024, IMPATIENT, Duration::from_millis(1), |index| index % 7 == 3, Some(100)).await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_receiver_that_never_answers() {
+        let (source, _) = source_file(4096);
+        let (to_server, from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let sent = tokio::spawn(sent_commands(from_sender));
+        let started_at = Instant::now();
+
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), IMPATIENT, QueueConfig::default()).await.unwrap();
+
+        assert_eq!(handle.finished().await, Err(TransferError::NoResponse));
+        // 20ms, then 40, 80 and 160 more before the last resend goes unanswered
+        assert!(started_at.elapsed() >= Duration::from_millis(300));
+        assert!(matches!(sent.await.unwrap().last(), Some(CommandType::FileTransferCancel { reason, .. }) if *reason == TransferError::NoResponse.to_string()));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_closed_connection_counts_as_lost_packets() {
+        let (source, _) = source_file(4096);
+        let (to_server, from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        drop(from_sender);
+
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), IMPATIENT, QueueConfig::default()).await.unwrap();
+
+        assert_eq!(handle.finished().await, Err(TransferError::NoResponse));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_transfer_takes_too_long() {
+        let (source, _) = source_file(4096);
+        let (to_server, from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let sent = tokio::spawn(sent_commands(from_sender));
+        let config = TransferConfig { transfer_timeout: Duration::from_millis(50), ..IMPATIENT };
+
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), config, QueueConfig::default()).await.unwrap();
+
+        assert_eq!(handle.finished().await, Err(TransferError::TimedOut));
+        assert!(matches!(sent.await.unwrap().last(), Some(CommandType::FileTransferCancel { reason, .. }) if *reason == TransferError::TimedOut.to_string()));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_tells_the_receiver() {
+        let (source, _) = source_file(4096);
+        let (to_server, from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), IMPATIENT, QueueConfig::default()).await.unwrap();
+
+        handle.cancel();
+
+        assert_eq!(handle.finished().await, Err(TransferError::Cancelled));
+        assert!(matches!(sent_commands(from_sender).await.last(), Some(CommandType::FileTransferCancel { .. })));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_gets_through_a_full_reply_queue() {
+        let (source, _) = source_file(4096);
+        let (to_server, from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let events = QueueConfig { capacity: 1, ..QueueConfig::default() };
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), IMPATIENT, events).await.unwrap();
+
+        // The transfer hasn't had a chance to take anything off its queue yet
+        handle.resume();
+        handle.cancel();
+
+        assert_eq!(handle.finished().await, Err(TransferError::Cancelled));
+        assert!(matches!(sent_commands(from_sender).await.last(), Some(CommandType::FileTransferCancel { .. })));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_receiver_can_refuse() {
+        let (source, _) = source_file(4096);
+        let (to_server, mut from_sender) = bounded_queue::<WebSocketMessage>(QueueConfig::default());
+        let handle = start_file_transfer(source.clone(), "client".to_string(), make_thread_safe(Loopback { connection: to_server }), IMPATIENT, QueueConfig::default()).await.unwrap();
+
+        let replies = handle.clone();
+        tokio::spawn(async move {
+            if let Some(CommandType::StartFileTransfer { name, transfer_id, .. }) = from_sender.recv().await.map(|message| message.command) {
+                replies.handle_reply(CommandType::FileTransferCancel { name, transfer_id, sender_uuid: "client".to_string(), reason: "disk full".to_string() }).await;
+            }
+        });
+
+        assert_eq!(handle.finished().await, Err(TransferError::Refused { reason: "disk full".to_string() }));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -7,6 +7,42 @@ pub use connection::*;
 mod communication;
 pub use communication::*;
 
+mod heartbeat;
+pub use heartbeat::*;
+
+mod wire;
+pub use wire::*;
+
+mod protocol;
+pub use protocol::*;
+
+mod selector;
+pub use selector::*;
+
+#[cfg(any(feature = "client", feature = "server"))]
+mod queue;
+
+#[cfg(any(feature = "client", feature = "server"))]
+pub use queue::*;
+
+#[cfg(feature = "client")]
+mod backoff;
+
+#[cfg(feature = "client")]
+pub use backoff::*;
+
+#[cfg(feature = "client")]
+mod requests;
+
+#[cfg(feature = "client")]
+pub use requests::*;
+
+#[cfg(feature = "client")]
+mod session;
+
+#[cfg(feature = "client")]
+pub use session::*;
+
 #[cfg(feature = "client")]
 mod threading;
 
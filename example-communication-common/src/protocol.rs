@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use crate::{CommandType, ConnectionInfo, ControlTypes, Destination, WebSocketMessage};
+
+/// Version of the protocol this build speaks. Bump it whenever a change would break older peers,
+/// like a new `CommandType` variant or a new required field.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Version assumed for peers that don't send one, they predate versioning
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Optional parts of the protocol a peer can handle. Commands needing a feature are only routed to peers that list it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Feature {
+    /// `Control`, `RequestCapabilities` and `ProvideCapabilities`
+    Control,
+    /// `StartFileTransfer`, `FileTransferBlob`, `FileTransferAck` and `FileTransferNack`
+    FileTransfer,
+    /// `AddFileWatch`, `ProvideFiles` and `UpdateFile`
+    FileWatch,
+    /// `CreateGroup`, `JoinGroup`, `LeaveGroup` and `Destination::Group`
+    Groups,
+    /// `ConnectionInfo::labels` and `Destination::Selector`
+    Labels,
+    /// `WebSocketMessage::durable_ttl` and `DeliveryReceipt`
+    DurableMessages,
+    /// `Control::reply_uuid` and `ControlReceipt`
+    ControlReceipts,
+    /// `StartFileTransfer::transfer_id`, `FileTransferQuery` and `FileTransferProgress`
+    ResumableTransfers,
+    /// `FileTransferCancel`
+    TransferCancellation,
+    /// A feature from a newer build, kept so the rest of the list still parses
+    #[serde(other)]
+    Unknown,
+}
+
+impl Feature {
+    /// Everything this build handles
+    pub const SUPPORTED: &'static [Feature] = &[Feature::Control, Feature::FileTransfer, Feature::FileWatch, Feature::Groups, Feature::Labels, Feature::DurableMessages, Feature::ControlReceipts, Feature::ResumableTransfers, Feature::TransferCancellation];
+
+    /// What peers from before versioning handled
+    pub const LEGACY: &'static [Feature] = &[Feature::Control, Feature::FileTransfer, Feature::FileWatch];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Feature::Control => {"Control"}
+            Feature::FileTransfer => {"FileTransfer"}
+            Feature::FileWatch => {"FileWatch"}
+            Feature::Groups => {"Groups"}
+            Feature::Labels => {"Labels"}
+            Feature::DurableMessages => {"DurableMessages"}
+            Feature::ControlReceipts => {"ControlReceipts"}
+            Feature::ResumableTransfers => {"ResumableTransfers"}
+            Feature::TransferCancellation => {"TransferCancellation"}
+            Feature::Unknown => {"Unknown"}
+        }
+    }
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl CommandType {
+    /// The feature a peer has to support to be sent this command, `None` for the core protocol
+    pub fn required_feature(&self) -> Option<Feature> {
+        match self {
+            CommandType::Control { .. } | CommandType::RequestCapabilities { .. } | CommandType::ProvideCapabilities { .. } => {Some(Feature::Control)}
+            CommandType::StartFileTransfer { .. } | CommandType::FileTransferBlob { .. }
+                | CommandType::FileTransferAck { .. } | CommandType::FileTransferNack { .. } => {Some(Feature::FileTransfer)}
+            CommandType::AddFileWatch { .. } | CommandType::ProvideFiles { .. } | CommandType::UpdateFile { .. } => {Some(Feature::FileWatch)}
+            CommandType::DeliveryReceipt { .. } => {Some(Feature::DurableMessages)}
+            CommandType::ControlReceipt { .. } => {Some(Feature::ControlReceipts)}
+            CommandType::FileTransferQuery { .. } | CommandType::FileTransferProgress { .. } => {Some(Feature::ResumableTransfers)}
+            CommandType::FileTransferCancel { .. } => {Some(Feature::TransferCancellation)}
+            _ => {None}
+        }
+    }
+}
+
+impl Destination {
+    /// The feature a peer has to support to parse this destination, `None` for the core protocol
+    pub fn required_feature(&self) -> Option<Feature> {
+        match self {
+            Destination::Group { .. } => {Some(Feature::Groups)}
+            Destination::Selector { .. } => {Some(Feature::Labels)}
+            _ => {None}
+        }
+    }
+}
+
+impl ControlTypes {
+    /// The feature a client needs for this control to be offered
+    pub fn required_feature(&self) -> Feature {
+        match self {
+            ControlTypes::Default | ControlTypes::Message => {Feature::Control}
+            ControlTypes::TransferFile => {Feature::FileTransfer}
+            // The file to delete is picked from the files the client reports
+            ControlTypes::DeleteFile => {Feature::FileWatch}
+        }
+    }
+}
+
+impl ConnectionInfo {
+    pub fn supports(&self, feature: Feature) -> bool {
+        if self.protocol_version == LEGACY_PROTOCOL_VERSION {
+            return Feature::LEGACY.contains(&feature);
+        }
+        feature != Feature::Unknown && self.features.contains(&feature)
+    }
+
+    /// Whether this peer can be sent `message`, both its command and its destination
+    pub fn accepts(&self, message: &WebSocketMessage) -> bool {
+        [message.command.required_feature(), message.destination.required_feature()].into_iter()
+            .flatten()
+            .all(|feature| self.supports(feature))
+    }
+
+    /// Caps the peer at what this build speaks, dropping features it doesn't know
+    pub fn downgrade(&mut self) {
+        self.protocol_version = self.protocol_version.min(PROTOCOL_VERSION);
+        self.features.retain(|feature| *feature != Feature::Unknown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::{CommandType, ConnectionInfo, ControlMessage, Destination, WebSocketMessage};
+    use super::{Feature, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    fn peer(value: serde_json::Value) -> ConnectionInfo {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn message(command: CommandType, destination: Destination) -> WebSocketMessage {
+        WebSocketMessage { command, destination, id: None, in_reply_to: None, durable_ttl: None }
+    }
+
+    fn control() -> CommandType {
+        CommandType::Control { message_type: ControlMessage::Message { text: "hello".to_string() }, reply_uuid: None }
+    }
+
+    #[test]
+    fn legacy_peers_support_what_predates_versioning() {
+        let legacy = peer(json!({ "uuid": "old", "name": "Old", "connection_type": "Client" }));
+
+        assert_eq!(legacy.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert!(legacy.features.is_empty());
+        for feature in Feature::LEGACY {
+            assert!(legacy.supports(*feature), "{}", feature);
+        }
+        assert!(!legacy.supports(Feature::Groups));
+        assert!(!legacy.supports(Feature::TransferCancellation));
+        assert!(legacy.accepts(&message(control(), Destination::All)));
+        assert!(!legacy.accepts(&message(control(), Destination::Group { name: "lab".to_string() })));
+    }
+
+    #[test]
+    fn features_from_newer_builds_parse_as_unknown() {
+        let newer = peer(json!({ "uuid": "new", "name": "New", "connection_type": "Client", "protocol_version": PROTOCOL_VERSION + 1, "features": ["Control", "Teleport"] }));
+
+        assert_eq!(newer.features, vec![Feature::Control, Feature::Unknown]);
+        assert!(newer.supports(Feature::Control));
+        assert!(!newer.supports(Feature::Unknown));
+        assert!(!newer.supports(Feature::FileTransfer));
+    }
+
+    #[test]
+    fn accepts_needs_the_command_and_destination_features() {
+        let info = peer(json!({ "uuid": "peer", "name": "Peer", "connection_type": "Client", "protocol_version": PROTOCOL_VERSION, "features": ["Control", "Labels"] }));
+
+        assert!(info.accepts(&message(CommandType::Ack, Destination::All)));
+        assert!(info.accepts(&message(control(), Destination::Selector { selector: "site=berlin".parse().unwrap() })));
+        assert!(!info.accepts(&message(control(), Destination::Group { name: "lab".to_string() })));
+        assert!(!info.accepts(&message(CommandType::AddFileWatch { return_uuid: "controller".to_string() }, Destination::All)));
+    }
+
+    #[test]
+    fn downgrade_caps_newer_peers() {
+        let mut newer = peer(json!({ "uuid": "new", "name": "New", "connection_type": "Client", "protocol_version": PROTOCOL_VERSION + 1, "features": ["Control", "Teleport"] }));
+        newer.downgrade();
+        assert_eq!(newer.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(newer.features, vec![Feature::Control]);
+
+        let mut legacy = peer(json!({ "uuid": "old", "name": "Old", "connection_type": "Client" }));
+        legacy.downgrade();
+        assert_eq!(legacy.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert!(legacy.supports(Feature::FileWatch));
+    }
+}
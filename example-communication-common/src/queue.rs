@@ -0,0 +1,438 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// What a full queue does with the next message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// `send` waits for room, `try_send` fails with `QueueError::Full`
+    #[default]
+    Block,
+    /// The oldest queued message is dropped to make room
+    DropOldest,
+    /// The queue is closed, cutting off the consumer that fell behind
+    Disconnect,
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::Block => {write!(f, "block")}
+            OverflowPolicy::DropOldest => {write!(f, "drop_oldest")}
+            OverflowPolicy::Disconnect => {write!(f, "disconnect")}
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("`{}` is not an overflow policy, expected block, drop_oldest or disconnect", s))
+        }
+    }
+}
+
+/// How many messages a queue holds, and what happens once it's full
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueMetrics {
+    /// Messages waiting right now
+    pub depth: usize,
+    pub capacity: usize,
+    /// Deepest the queue has been
+    pub high_water_mark: usize,
+    /// Messages thrown away by `DropOldest`, or cut off by `Disconnect`
+    pub dropped: u64,
+}
+
+/// Hands the message that couldn't be queued back to the caller
+pub enum QueueError<T> {
+    /// The receiver is gone, or the queue was closed because it overflowed
+    Closed(T),
+    /// The queue is full and its policy is `Block`
+    Full(T),
+}
+
+impl<T> QueueError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            QueueError::Closed(value) | QueueError::Full(value) => value
+        }
+    }
+}
+
+// Like tokio's `SendError`, doesn't require the message itself to be `Debug`
+impl<T> Debug for QueueError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Closed(_) => {write!(f, "Closed(..)")}
+            QueueError::Full(_) => {write!(f, "Full(..)")}
+        }
+    }
+}
+
+impl<T> Display for QueueError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Closed(_) => {write!(f, "queue is closed")}
+            QueueError::Full(_) => {write!(f, "queue is full")}
+        }
+    }
+}
+
+impl<T> std::error::Error for QueueError<T> {}
+
+struct State<T> {
+    messages: VecDeque<T>,
+    closed: bool,
+    high_water_mark: usize,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    config: QueueConfig,
+    senders: AtomicUsize,
+    /// Wakes the receiver when a message arrives or the queue closes
+    readable: Notify,
+    /// Wakes blocked senders when room frees up or the queue closes
+    writable: Notify,
+}
+
+impl<T> Shared<T> {
+    fn metrics(&self) -> QueueMetrics {
+        let state = self.state.lock().unwrap();
+        QueueMetrics {
+            depth: state.messages.len(),
+            capacity: self.config.capacity,
+            high_water_mark: state.high_water_mark,
+            dropped: state.dropped,
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+/// Creates a queue holding at most `config.capacity` messages, see `OverflowPolicy` for what happens past that
+pub fn bounded_queue<T>(config: QueueConfig) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::with_capacity(config.capacity),
+            closed: false,
+            high_water_mark: 0,
+            dropped: 0,
+        }),
+        config: QueueConfig { capacity: config.capacity.max(1), ..config },
+        senders: AtomicUsize::new(1),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `message` without waiting. Whether a full queue drops, fails or closes depends on its policy.
+    pub fn try_send(&self, message: T) -> Result<(), QueueError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueError::Closed(message));
+        }
+
+        if state.messages.len() >= self.shared.config.capacity {
+            match self.shared.config.overflow {
+                OverflowPolicy::Block => return Err(QueueError::Full(message)),
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.messages.len() as u64 + 1;
+                    state.messages.clear();
+                    drop(state);
+                    self.shared.close();
+                    return Err(QueueError::Closed(message));
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        state.high_water_mark = state.high_water_mark.max(state.messages.len());
+        drop(state);
+
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queues `message`, waiting for room if the queue is full and its policy is `Block`.
+    /// Only fails once the queue is closed.
+    pub async fn send(&self, mut message: T) -> Result<(), QueueError<T>> {
+        loop {
+            let notified = self.shared.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.try_send(message) {
+                Err(QueueError::Full(returned)) => message = returned,
+                result => return result
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Like `send`, but gives up with `QueueError::Full` if no room frees up within `timeout`
+    pub async fn send_timeout(&self, mut message: T, timeout: Duration) -> Result<(), QueueError<T>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let notified = self.shared.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.try_send(message) {
+                Err(QueueError::Full(returned)) => message = returned,
+                result => return result
+            }
+
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return self.try_send(message);
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    pub fn same_channel(&self, other: &QueueSender<T>) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        // The receiver has to wake up to notice there's nobody left to send
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl<T> Debug for QueueSender<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueSender").field("metrics", &self.metrics()).finish()
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next message. Returns `None` once the queue is closed, or empty with every sender gone.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let notified = self.shared.readable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    drop(state);
+                    self.shared.writable.notify_waiters();
+                    return Some(message);
+                }
+                if state.closed || self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Stops accepting messages, those already queued can still be received
+    pub fn close(&mut self) {
+        self.shared.close();
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{bounded_queue, OverflowPolicy, QueueConfig, QueueError};
+
+    fn config(capacity: usize, overflow: OverflowPolicy) -> QueueConfig {
+        QueueConfig { capacity, overflow }
+    }
+
+    #[tokio::test]
+    async fn block_fails_try_send_and_waits_in_send() {
+        let (sender, mut receiver) = bounded_queue(config(2, OverflowPolicy::Block));
+        sender.try_send(1).unwrap();
+        sender.try_send(2).unwrap();
+
+        assert!(matches!(sender.try_send(3), Err(QueueError::Full(3))));
+
+        let waiting = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send(3).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        assert_eq!(receiver.recv().await, Some(1));
+        waiting.await.unwrap().unwrap();
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(receiver.metrics().dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn send_timeout_gives_up_on_a_full_queue() {
+        let (sender, mut receiver) = bounded_queue(config(1, OverflowPolicy::Block));
+        sender.try_send(1).unwrap();
+
+        assert!(matches!(sender.send_timeout(2, Duration::from_millis(20)).await, Err(QueueError::Full(2))));
+
+        let waiting = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send_timeout(2, Duration::from_secs(10)).await }
+        });
+        assert_eq!(receiver.recv().await, Some(1));
+        waiting.await.unwrap().unwrap();
+        assert_eq!(receiver.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let (sender, mut receiver) = bounded_queue(config(2, OverflowPolicy::DropOldest));
+        for message in 1..=4 {
+            sender.try_send(message).unwrap();
+        }
+
+        let metrics = sender.metrics();
+        assert_eq!(metrics.depth, 2);
+        assert_eq!(metrics.dropped, 2);
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(receiver.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue_on_overflow() {
+        let (sender, mut receiver) = bounded_queue(config(2, OverflowPolicy::Disconnect));
+        sender.try_send(1).unwrap();
+        sender.try_send(2).unwrap();
+
+        assert!(matches!(sender.try_send(3), Err(QueueError::Closed(3))));
+        assert!(sender.is_closed());
+        assert_eq!(sender.metrics().dropped, 3);
+        assert!(matches!(sender.send(4).await, Err(QueueError::Closed(4))));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_keeps_queued_messages_and_wakes_blocked_senders() {
+        let (sender, mut receiver) = bounded_queue(config(1, OverflowPolicy::Block));
+        sender.try_send(1).unwrap();
+        let waiting = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send(2).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        receiver.close();
+
+        assert!(matches!(waiting.await.unwrap(), Err(QueueError::Closed(2))));
+        assert!(matches!(sender.try_send(3), Err(QueueError::Closed(3))));
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn receiver_ends_once_every_sender_is_gone() {
+        let (sender, mut receiver) = bounded_queue(config(4, OverflowPolicy::Block));
+        let other_sender = sender.clone();
+        sender.try_send(1).unwrap();
+        drop(sender);
+        drop(other_sender);
+
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn metrics_track_depth_and_high_water_mark() {
+        let (sender, mut receiver) = bounded_queue(config(4, OverflowPolicy::Block));
+        for message in 0..3 {
+            sender.try_send(message).unwrap();
+        }
+        receiver.recv().await;
+        receiver.recv().await;
+
+        let metrics = receiver.metrics();
+        assert_eq!(metrics.depth, 1);
+        assert_eq!(metrics.capacity, 4);
+        assert_eq!(metrics.high_water_mark, 3);
+        assert_eq!(metrics.dropped, 0);
+    }
+}
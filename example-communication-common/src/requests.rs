@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::{CommandType, ControlStatus, ErrorCode, QueueSender, WebSocketMessage};
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait on a client to confirm a control message, and how often to send it again meanwhile
+#[derive(Debug, Clone, Copy)]
+pub struct ReceiptPolicy {
+    /// How long to wait for the `Received` receipt before sending the message again
+    pub receipt_timeout: Duration,
+    /// Times the message is sent before giving up
+    pub attempts: u32,
+    /// How long the client may take to act on the message once it got it
+    pub execution_timeout: Duration,
+}
+
+impl Default for ReceiptPolicy {
+    fn default() -> Self {
+        Self {
+            receipt_timeout: Duration::from_secs(5),
+            attempts: 3,
+            execution_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The message could not be queued for the server
+    SendFailed,
+    /// The connection closed before a reply arrived
+    Disconnected,
+    /// No reply arrived within the timeout
+    TimedOut,
+    /// The server or peer answered with an `Error`
+    Rejected { code: ErrorCode, message: String },
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::SendFailed => {write!(f, "failed to send request")}
+            RequestError::Disconnected => {write!(f, "connection closed before a reply arrived")}
+            RequestError::TimedOut => {write!(f, "timed out waiting for a reply")}
+            RequestError::Rejected { code, message } => {write!(f, "{}: {}", code, message)}
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+enum Waiting {
+    Reply(oneshot::Sender<WebSocketMessage>),
+    /// Keeps taking replies until cancelled
+    Replies(mpsc::UnboundedSender<WebSocketMessage>),
+}
+
+/// Hands out message ids and keeps track of requests that are waiting on a reply.
+/// Clones share the same state, so one instance can live in the app's `Sender` and in the websocket loop.
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    next_id: Arc<AtomicU64>,
+    waiting: Arc<Mutex<HashMap<u64, Waiting>>>,
+}
+
+impl PendingRequests {
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Registers a request with the given id, the receiver completes once its reply is resolved
+    pub fn register(&self, id: u64) -> oneshot::Receiver<WebSocketMessage> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id, Waiting::Reply(sender));
+        receiver
+    }
+
+    /// Registers a request that may be answered more than once, every reply is passed on until it's cancelled
+    pub fn register_replies(&self, id: u64) -> mpsc::UnboundedReceiver<WebSocketMessage> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.waiting.lock().unwrap().insert(id, Waiting::Replies(sender));
+        receiver
+    }
+
+    pub fn cancel(&self, id: u64) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    /// Hands `message` to the request it answers. Returns the message back if nothing is waiting on it.
+    pub fn resolve(&self, message: WebSocketMessage) -> Option<WebSocketMessage> {
+        let Some(id) = message.reply_target() else {
+            return Some(message);
+        };
+
+        let mut waiting = self.waiting.lock().unwrap();
+        match waiting.remove(&id) {
+            Some(Waiting::Reply(sender)) => {
+                // The requester may have given up already, in which case the reply is dropped
+                let _ = sender.send(message);
+                None
+            }
+            Some(Waiting::Replies(sender)) => {
+                if sender.send(message).is_ok() {
+                    waiting.insert(id, Waiting::Replies(sender));
+                }
+                None
+            }
+            None => Some(message)
+        }
+    }
+
+    /// Fails every outstanding request with `RequestError::Disconnected`
+    pub fn disconnect_all(&self) {
+        self.waiting.lock().unwrap().clear();
+    }
+}
+
+pub(crate) async fn wait_for_reply(requests: PendingRequests, id: u64, receiver: oneshot::Receiver<WebSocketMessage>, timeout: Duration) -> Result<WebSocketMessage, RequestError> {
+    let reply = match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(_)) => return Err(RequestError::Disconnected),
+        Err(_) => {
+            requests.cancel(id);
+            return Err(RequestError::TimedOut);
+        }
+    };
+
+    match reply.command {
+        CommandType::Error { code, message, .. } => Err(RequestError::Rejected { code, message }),
+        _ => Ok(reply)
+    }
+}
+
+/// Sends `message` until the client confirms it got it, then waits for the client to report how acting on it went
+pub(crate) async fn follow_control(requests: PendingRequests, connection: QueueSender<WebSocketMessage>, message: WebSocketMessage, id: u64, policy: ReceiptPolicy) -> Result<ControlStatus, RequestError> {
+    let mut receipts = requests.register_replies(id);
+    let status = follow_receipts(&connection, message, &mut receipts, policy).await;
+    requests.cancel(id);
+    status
+}
+
+async fn follow_receipts(connection: &QueueSender<WebSocketMessage>, message: WebSocketMessage, receipts: &mut mpsc::UnboundedReceiver<WebSocketMessage>, policy: ReceiptPolicy) -> Result<ControlStatus, RequestError> {
+    for _ in 0..policy.attempts {
+        // Resends keep the id, so the client knows not to act on the message twice
+        connection.try_send(message.clone()).map_err(|_| RequestError::SendFailed)?;
+
+        if let Some(status) = next_status(receipts, Instant::now() + policy.receipt_timeout).await? {
+            if status.is_final() {
+                return Ok(status);
+            }
+
+            let deadline = Instant::now() + policy.execution_timeout;
+            loop {
+                match next_status(receipts, deadline).await? {
+                    Some(status) if status.is_final() => return Ok(status),
+                    // Receipts for an earlier attempt
+                    Some(_) => continue,
+                    None => return Err(RequestError::TimedOut),
+                }
+            }
+        }
+    }
+
+    Err(RequestError::TimedOut)
+}
+
+/// The next status reported before `deadline`, `None` if there was none
+async fn next_status(receipts: &mut mpsc::UnboundedReceiver<WebSocketMessage>, deadline: Instant) -> Result<Option<ControlStatus>, RequestError> {
+    loop {
+        let reply = match tokio::time::timeout_at(deadline, receipts.recv()).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return Err(RequestError::Disconnected),
+            Err(_) => return Ok(None),
+        };
+
+        match reply.command {
+            CommandType::ControlReceipt { status, .. } => return Ok(Some(status)),
+            CommandType::Error { code, message, .. } => return Err(RequestError::Rejected { code, message }),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::{bounded_queue, CommandType, ControlMessage, ControlStatus, Destination, ErrorCode, QueueConfig, QueueReceiver, QueueSender, Sender, SessionToken, WebSocketMessage};
+    use super::{follow_control, PendingRequests, ReceiptPolicy, RequestError};
+
+    const POLICY: ReceiptPolicy = ReceiptPolicy {
+        receipt_timeout: Duration::from_millis(50),
+        attempts: 3,
+        execution_timeout: Duration::from_millis(200),
+    };
+
+    fn control(id: u64) -> WebSocketMessage {
+        WebSocketMessage {
+            command: CommandType::Control { message_type: ControlMessage::Message { text: "hello".to_string() }, reply_uuid: Some("controller".to_string()) },
+            destination: Destination::Single { destination_uuid: "client".to_string() },
+            id: Some(id),
+            in_reply_to: None,
+            durable_ttl: None,
+        }
+    }
+
+    fn receipt(id: u64, status: ControlStatus) -> WebSocketMessage {
+        WebSocketMessage {
+            command: CommandType::ControlReceipt { sender_uuid: "client".to_string(), status },
+            destination: Destination::Single { destination_uuid: "controller".to_string() },
+            id: None,
+            in_reply_to: Some(id),
+            durable_ttl: None,
+        }
+    }
+
+    struct Controller {
+        connection: QueueSender<WebSocketMessage>,
+        requests: PendingRequests,
+    }
+
+    impl Sender for Controller {
+        fn get_uuid(&self) -> String {
+            "controller".to_string()
+        }
+        fn get_connection(&self) -> Option<QueueSender<WebSocketMessage>> {
+            Some(self.connection.clone())
+        }
+        fn drop_connection(&mut self) {}
+        fn set_connection(&mut self, _new_sender: QueueSender<WebSocketMessage>) {}
+        fn get_requests(&self) -> PendingRequests {
+            self.requests.clone()
+        }
+        fn get_session(&self) -> SessionToken {
+            SessionToken::default()
+        }
+    }
+
+    fn get_connections() -> WebSocketMessage {
+        WebSocketMessage {
+            command: CommandType::GetConnections { reply_uuid: "controller".to_string() },
+            destination: Destination::None,
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        }
+    }
+
+    /// Stands in for the server, answering the first request it sees with `answer`
+    async fn server(requests: PendingRequests, mut from_controller: QueueReceiver<WebSocketMessage>, answer: impl FnOnce(u64) -> WebSocketMessage) {
+        let request = from_controller.recv().await.unwrap();
+        requests.resolve(answer(request.id.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn request_resolves_with_its_reply() {
+        let requests = PendingRequests::default();
+        let (connection, from_controller) = bounded_queue(QueueConfig::default());
+        let controller = Controller { connection, requests: requests.clone() };
+        let server = tokio::spawn(server(requests.clone(), from_controller, |id| WebSocketMessage {
+            command: CommandType::ActiveConnections { users: vec![] },
+            destination: Destination::Single { destination_uuid: "controller".to_string() },
+            id: None,
+            in_reply_to: Some(id),
+            durable_ttl: None,
+        }));
+
+        let reply = controller.request(get_connections()).await.unwrap();
+        server.await.unwrap();
+
+        assert!(matches!(reply.command, CommandType::ActiveConnections { users } if users.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn request_fails_with_the_error_reply() {
+        let requests = PendingRequests::default();
+        let (connection, from_controller) = bounded_queue(QueueConfig::default());
+        let controller = Controller { connection, requests: requests.clone() };
+        let server = tokio::spawn(server(requests.clone(), from_controller, |id| WebSocketMessage {
+            command: CommandType::Error { code: ErrorCode::NotAuthorized, message: "no".to_string(), in_reply_to: Some(id) },
+            destination: Destination::Single { destination_uuid: "controller".to_string() },
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        }));
+
+        let reply = controller.request(get_connections()).await;
+        server.await.unwrap();
+
+        assert!(matches!(reply, Err(RequestError::Rejected { code: ErrorCode::NotAuthorized, .. })));
+    }
+
+    #[tokio::test]
+    async fn request_times_out_without_a_reply() {
+        let requests = PendingRequests::default();
+        let (connection, _from_controller) = bounded_queue(QueueConfig::default());
+        let controller = Controller { connection, requests: requests.clone() };
+
+        let reply = controller.request_with_timeout(get_connections(), Duration::from_millis(20)).await;
+
+        assert!(matches!(reply, Err(RequestError::TimedOut)));
+        // A late reply is handed back instead of going to the abandoned request
+        let late = WebSocketMessage { in_reply_to: Some(0), ..get_connections() };
+        assert!(requests.resolve(late).is_some());
+    }
+
+    /// Answers every send after the first `ignored` with `statuses`, returning how many sends it saw
+    async fn client(requests: PendingRequests, mut from_controller: QueueReceiver<WebSocketMessage>, ignored: usize, statuses: Vec<ControlStatus>) -> usize {
+        let mut sends = 0;
+        while let Some(message) = from_controller.recv().await {
+            sends += 1;
+            if sends > ignored {
+                for status in &statuses {
+                    requests.resolve(receipt(message.id.unwrap(), status.clone()));
+                }
+            }
+        }
+        sends
+    }
+
+    #[tokio::test]
+    async fn resends_until_the_client_confirms() {
+        let requests = PendingRequests::default();
+        let (to_client, from_controller) = bounded_queue(QueueConfig::default());
+        let client = tokio::spawn(client(requests.clone(), from_controller, 1, vec![ControlStatus::Received, ControlStatus::Executed]));
+
+        let status = follow_control(requests, to_client, control(7), 7, POLICY).await;
+
+        assert_eq!(status.unwrap(), ControlStatus::Executed);
+        assert_eq!(client.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_failures() {
+        let requests = PendingRequests::default();
+        let (to_client, from_controller) = bounded_queue(QueueConfig::default());
+        let failed = ControlStatus::Failed { reason: "no such file".to_string() };
+        let client = tokio::spawn(client(requests.clone(), from_controller, 0, vec![ControlStatus::Received, failed.clone()]));
+
+        let status = follow_control(requests, to_client, control(7), 7, POLICY).await;
+
+        assert_eq!(status.unwrap(), failed);
+        assert_eq!(client.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let requests = PendingRequests::default();
+        let (to_client, from_controller) = bounded_queue(QueueConfig::default());
+        let client = tokio::spawn(client(requests.clone(), from_controller, usize::MAX, vec![]));
+
+        let status = follow_control(requests.clone(), to_client, control(7), 7, POLICY).await;
+
+        assert!(matches!(status, Err(RequestError::TimedOut)));
+        assert_eq!(client.await.unwrap(), 3);
+        // The request no longer takes replies
+        assert!(requests.resolve(receipt(7, ControlStatus::Executed)).is_some());
+    }
+}
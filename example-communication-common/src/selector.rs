@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+/// Labels a connection advertises about itself, such as `os`, `site` or `role`
+pub type Labels = BTreeMap<String, String>;
+
+/// A comma separated list of label requirements, all of which have to hold, e.g. `site=berlin,role!=kiosk`.
+/// `key` requires the label to be present and `!key` requires it to be missing. An empty selector matches everything.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    /// Also holds when the label is missing
+    NotEquals(String, String),
+    Exists(String),
+    Missing(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::Missing(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Requirement::Equals(key, value) => {write!(f, "{}={}", key, value)}
+            Requirement::NotEquals(key, value) => {write!(f, "{}!={}", key, value)}
+            Requirement::Exists(key) => {write!(f, "{}", key)}
+            Requirement::Missing(key) => {write!(f, "!{}", key)}
+        }
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let requirement = if let Some((key, value)) = s.split_once("!=") {
+            Requirement::NotEquals(label_key(key)?, value.trim().to_string())
+        } else if let Some((key, value)) = s.split_once('=') {
+            Requirement::Equals(label_key(key)?, value.trim().to_string())
+        } else if let Some(key) = s.strip_prefix('!') {
+            Requirement::Missing(label_key(key)?)
+        } else {
+            Requirement::Exists(label_key(s)?)
+        };
+
+        Ok(requirement)
+    }
+}
+
+fn label_key(key: &str) -> Result<String, SelectorError> {
+    let key = key.trim();
+    if key.is_empty() || key.contains(['=', '!']) {
+        return Err(SelectorError(key.to_string()));
+    }
+    Ok(key.to_string())
+}
+
+impl Selector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.requirements.iter().all(|requirement| requirement.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let requirements: Vec<String> = self.requirements.iter().map(|requirement| requirement.to_string()).collect();
+        write!(f, "{}", requirements.join(","))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(Selector::default());
+        }
+
+        let requirements = s.split(',').map(Requirement::from_str).collect::<Result<Vec<_>, _>>()?;
+        Ok(Selector { requirements })
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = SelectorError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Selector> for String {
+    fn from(selector: Selector) -> Self {
+        selector.to_string()
+    }
+}
+
+/// Names the label key that couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError(pub String);
+
+impl Display for SelectorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is not a label key, expected something like `site=berlin`, `role!=kiosk`, `gpu` or `!gpu`", self.0)
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Labels, Selector};
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn every_requirement_has_to_hold() {
+        let selector: Selector = "site=berlin, role!=kiosk".parse().unwrap();
+
+        assert!(selector.matches(&labels(&[("site", "berlin"), ("role", "lab")])));
+        assert!(selector.matches(&labels(&[("site", "berlin")])));
+        assert!(!selector.matches(&labels(&[("site", "berlin"), ("role", "kiosk")])));
+        assert!(!selector.matches(&labels(&[("site", "paris"), ("role", "lab")])));
+    }
+
+    #[test]
+    fn presence_requirements() {
+        let selector: Selector = "gpu,!retired".parse().unwrap();
+
+        assert!(selector.matches(&labels(&[("gpu", "")])));
+        assert!(!selector.matches(&labels(&[("gpu", "nvidia"), ("retired", "yes")])));
+        assert!(!selector.matches(&labels(&[])));
+    }
+
+    #[test]
+    fn empty_selector_matches_everything() {
+        let selector: Selector = " ".parse().unwrap();
+
+        assert!(selector.is_empty());
+        assert!(selector.matches(&labels(&[])));
+    }
+
+    #[test]
+    fn round_trips_through_its_string_form() {
+        let selector: Selector = "site = berlin,role!=kiosk,gpu,!retired".parse().unwrap();
+
+        assert_eq!(selector.to_string(), "site=berlin,role!=kiosk,gpu,!retired");
+        assert_eq!(selector.to_string().parse::<Selector>().unwrap(), selector);
+    }
+
+    #[test]
+    fn rejects_missing_keys() {
+        assert!("=berlin".parse::<Selector>().is_err());
+        assert!("site=berlin,".parse::<Selector>().is_err());
+        assert!("!".parse::<Selector>().is_err());
+    }
+}
@@ -0,0 +1,18 @@
+use std::sync::{Arc, Mutex};
+
+/// The session token handed out in the last `Welcome`. It is presented when reconnecting so the
+/// server can give the connection back its previous UUID. Clones share the same token.
+#[derive(Clone, Default)]
+pub struct SessionToken {
+    token: Arc<Mutex<Option<String>>>,
+}
+
+impl SessionToken {
+    pub fn get(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, token: Option<String>) {
+        *self.token.lock().unwrap() = token;
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::WebSocketMessage;
+
+/// Header a client lists the wire format it wants in when connecting
+pub const WIRE_FORMAT_HEADER: &str = "X-Wire-Format";
+
+/// How `WebSocketMessage`s are encoded. JSON goes in text frames and MessagePack in binary frames,
+/// so either side can always tell what it received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum WireFormat {
+    /// Readable, and the fallback when the other side doesn't support anything else
+    #[default]
+    Json,
+    /// Compact, and sends file transfer blobs as raw bytes
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn as_str(&self) -> &str {
+        match self {
+            WireFormat::Json => {"json"}
+            WireFormat::MessagePack => {"msgpack"}
+        }
+    }
+
+    pub fn encode(&self, message: &WebSocketMessage) -> EncodedMessage {
+        match self {
+            WireFormat::Json => EncodedMessage::Text(serde_json::to_string(message).expect("Failed to serialize WebSocketMessage")),
+            WireFormat::MessagePack => EncodedMessage::Binary(rmp_serde::to_vec_named(message).expect("Failed to serialize WebSocketMessage")),
+        }
+    }
+}
+
+impl Display for WireFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WireFormat::Json),
+            "msgpack" => Ok(WireFormat::MessagePack),
+            _ => Err(format!("`{}` is not a wire format, expected json or msgpack", s))
+        }
+    }
+}
+
+/// A message ready to go out in a text or binary frame
+pub enum EncodedMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => {write!(f, "{}", e)}
+            DecodeError::MessagePack(e) => {write!(f, "{}", e)}
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl WebSocketMessage {
+    /// Decodes the payload of a text frame
+    pub fn from_text(text: &str) -> Result<Self, DecodeError> {
+        serde_json::from_str(text).map_err(DecodeError::Json)
+    }
+
+    /// Decodes the payload of a binary frame
+    pub fn from_binary(bytes: &[u8]) -> Result<Self, DecodeError> {
+        rmp_serde::from_slice(bytes).map_err(DecodeError::MessagePack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::{CommandType, ControlMessage, ControlTypes, Destination, Selector, WebSocketMessage};
+    use super::{EncodedMessage, WireFormat};
+
+    const FORMATS: [WireFormat; 2] = [WireFormat::Json, WireFormat::MessagePack];
+
+    fn round_trip(wire_format: WireFormat, message: &WebSocketMessage) -> WebSocketMessage {
+        match wire_format.encode(message) {
+            EncodedMessage::Text(text) => WebSocketMessage::from_text(&text).unwrap(),
+            EncodedMessage::Binary(bytes) => WebSocketMessage::from_binary(&bytes).unwrap(),
+        }
+    }
+
+    /// Decodes `value` as it would arrive in `wire_format`, for messages this version couldn't have sent itself
+    fn decode(wire_format: WireFormat, value: &serde_json::Value) -> WebSocketMessage {
+        match wire_format {
+            WireFormat::Json => WebSocketMessage::from_text(&value.to_string()).unwrap(),
+            WireFormat::MessagePack => WebSocketMessage::from_binary(&rmp_serde::to_vec_named(value).unwrap()).unwrap(),
+        }
+    }
+
+    fn message(command: CommandType, destination: Destination) -> WebSocketMessage {
+        WebSocketMessage { command, destination, id: Some(3), in_reply_to: None, durable_ttl: None }
+    }
+
+    #[test]
+    fn blobs_round_trip() {
+        let blob: Vec<u8> = (0..=255).collect();
+        let packet = message(CommandType::FileTransferBlob {
+            name: "payload.bin".to_string(),
+            transfer_id: "transfer".to_string(),
+            chunk_num: 4,
+            blob: blob.clone(),
+            return_uuid: "controller".to_string(),
+        }, Destination::Single { destination_uuid: "client".to_string() });
+
+        for wire_format in FORMATS {
+            let decoded = round_trip(wire_format, &packet);
+            assert!(matches!(decoded.command, CommandType::FileTransferBlob { chunk_num: 4, blob: ref decoded_blob, .. } if *decoded_blob == blob), "{}", wire_format);
+            assert_eq!(decoded.id, Some(3));
+        }
+
+        // Raw bytes in MessagePack rather than an array of numbers
+        let EncodedMessage::Binary(bytes) = WireFormat::MessagePack.encode(&packet) else {
+            panic!("MessagePack goes in binary frames");
+        };
+        assert!(bytes.windows(blob.len()).any(|window| window == blob.as_slice()));
+    }
+
+    #[test]
+    fn selectors_round_trip_as_strings() {
+        let selector: Selector = "site=berlin,role!=kiosk".parse().unwrap();
+        let packet = message(CommandType::Ack, Destination::Selector { selector: selector.clone() });
+
+        for wire_format in FORMATS {
+            let decoded = round_trip(wire_format, &packet);
+            assert!(matches!(decoded.destination, Destination::Selector { selector: ref decoded_selector } if *decoded_selector == selector), "{}", wire_format);
+        }
+
+        let EncodedMessage::Text(text) = WireFormat::Json.encode(&packet) else {
+            panic!("JSON goes in text frames");
+        };
+        assert!(text.contains(&format!("\"selector\":\"{}\"", selector)));
+    }
+
+    #[test]
+    fn invalid_selectors_are_rejected() {
+        let value = json!({ "command": "Ack", "destination": { "Selector": { "selector": "=berlin" } } });
+
+        assert!(WebSocketMessage::from_text(&value.to_string()).is_err());
+        assert!(WebSocketMessage::from_binary(&rmp_serde::to_vec_named(&value).unwrap()).is_err());
+    }
+
+    #[test]
+    fn unknown_control_messages_fall_back_to_default() {
+        let value = json!({
+            "command": { "Control": { "message_type": { "Reboot": { "delay": 5 } }, "reply_uuid": "controller" } },
+            "destination": { "Single": { "destination_uuid": "client" } },
+        });
+
+        for wire_format in FORMATS {
+            let decoded = decode(wire_format, &value);
+            assert!(matches!(decoded.command, CommandType::Control { message_type: ControlMessage::Default, reply_uuid: Some(ref reply_uuid) } if reply_uuid == "controller"), "{}", wire_format);
+        }
+    }
+
+    #[test]
+    fn unknown_capabilities_fall_back_to_default() {
+        let value = json!({
+            "command": { "ProvideCapabilities": { "sender_uuid": "client", "list": ["Message", "Reboot"] } },
+            "destination": { "Single": { "destination_uuid": "controller" } },
+        });
+
+        for wire_format in FORMATS {
+            let decoded = decode(wire_format, &value);
+            assert!(matches!(decoded.command, CommandType::ProvideCapabilities { ref list, .. } if list.is_empty()), "{}", wire_format);
+        }
+
+        let known = message(CommandType::ProvideCapabilities { sender_uuid: "client".to_string(), list: vec![ControlTypes::Message] }, Destination::None);
+        for wire_format in FORMATS {
+            let decoded = round_trip(wire_format, &known);
+            assert!(matches!(decoded.command, CommandType::ProvideCapabilities { ref list, .. } if matches!(list[..], [ControlTypes::Message])), "{}", wire_format);
+        }
+    }
+
+    #[test]
+    fn wire_formats_parse_from_their_names() {
+        for wire_format in FORMATS {
+            assert_eq!(wire_format.as_str().parse::<WireFormat>().unwrap(), wire_format);
+        }
+        assert!("xml".parse::<WireFormat>().is_err());
+    }
+}
@@ -1,10 +1,10 @@
 use std::sync::Arc;
-use slint::{spawn_local};
+use slint::{spawn_local, Weak};
 use tokio::select;
 use tokio::sync::Notify;
-use example_communication_common::{connect_to_server_loop, reconnect, CommandType, ConnectionInfo, ConnectionType, Destination, Sender, Status, WebSocketMessage};
+use example_communication_common::{connect_to_server_loop, reconnect, CommandType, ConnectionInfo, ConnectionType, Destination, Feature, Labels, Sender, Status, WebSocketMessage, PROTOCOL_VERSION};
 use crate::settings::{ThreadSafeClientCache, ThreadSafeSettings};
-use crate::{update_connection_info, UI};
+use crate::{update_connection_info, AppWindow, UI};
 
 struct ControllerStatus {
     ui: UI,
@@ -25,7 +25,11 @@ pub async fn communication_thread(ui: UI, client_cache: ThreadSafeClientCache, s
         running: true,
     };
 
-    let (to_server, mut from_server) = connect_to_server_loop(settings.clone(), connection_state_changed.clone(), &status).await;
+    let (requests, session) = {
+        let client_cache = client_cache.lock().await;
+        (client_cache.get_requests(), client_cache.get_session())
+    };
+    let (to_server, mut from_server) = connect_to_server_loop(settings.clone(), connection_state_changed.clone(), requests, session, &status).await;
 
     client_cache.lock().await.to_server = Some(to_server);
 
@@ -34,6 +38,10 @@ pub async fn communication_thread(ui: UI, client_cache: ThreadSafeClientCache, s
             message = from_server.recv() => {
                 if let Some(message) = message {
                     handle_message(message, &mut status, settings.clone(), client_cache.clone()).await;
+                } else {
+                    // The websocket loop ended, most likely because the server went away
+                    status.update_status("Connection lost".to_string());
+                    from_server = reconnect(client_cache.clone(), settings.clone(), from_server, connection_state_changed.clone(), &status).await;
                 }
             },
             _ = connection_state_changed.notified() => {
@@ -44,25 +52,60 @@ pub async fn communication_thread(ui: UI, client_cache: ThreadSafeClientCache, s
 }
 async fn handle_message(message: WebSocketMessage, status: &mut ControllerStatus, settings: ThreadSafeSettings, client_cache: ThreadSafeClientCache) {
     match message.command.clone() {
-        CommandType::Welcome{ uuid } => {
+        CommandType::Welcome{ uuid, .. } => {
+            let shared_cache = client_cache.clone();
             let settings = settings.lock().await;
             let mut client_cache = client_cache.lock().await;
             client_cache.local_uuid = uuid;
-            client_cache.try_send(WebSocketMessage{
+            if let Err(error) = client_cache.try_send(WebSocketMessage{
                 command: CommandType::SetConnectionInfo {
                     info: ConnectionInfo {
                         uuid: client_cache.local_uuid.to_string(),
                         name: settings.client_name.to_string(),
-                        connection_type: ConnectionType::Client,},
+                        connection_type: ConnectionType::Controller,
+                        latency_ms: None,
+                        device_id: Some(settings.device_id.clone()),
+                        protocol_version: PROTOCOL_VERSION,
+                        features: Feature::SUPPORTED.to_vec(),
+                        labels: Labels::new(),
+                    },
                 },
                 destination: Destination::None,
-            }).expect("Failed to send message");
-            client_cache.try_send(WebSocketMessage{
+                id: None,
+                in_reply_to: None,
+                durable_ttl: None,
+            }) {
+                println!("Failed to send message: {}", error);
+            }
+            let connections = client_cache.request(WebSocketMessage{
                 command: CommandType::GetConnections {
                     reply_uuid: client_cache.local_uuid.clone(),
                 },
-                destination: Destination::None
-            }).expect("Failed to send message");
+                destination: Destination::None,
+                id: None,
+                in_reply_to: None,
+                durable_ttl: None,
+            });
+            // The reply is awaited off the message loop, which has to keep running for it to arrive
+            let app_window = status.ui.app_window.clone();
+            tokio::spawn(async move {
+                match connections.await {
+                    Ok(WebSocketMessage { command: CommandType::ActiveConnections { users }, .. }) => {
+                        show_connections(users, &app_window, shared_cache).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let status = ControllerStatus { ui: UI { app_window }, running: true };
+                        status.update_status(format!("Failed to get connections: {}", e));
+                    }
+                }
+            });
+
+            // Chunks in flight when the connection dropped are lost, carry on from what the receivers wrote
+            client_cache.file_transfer_threads.retain(|_, thread| !thread.is_finished());
+            for thread in client_cache.file_transfer_threads.values() {
+                thread.resume();
+            }
         }
 
         CommandType::UpdateConnection{ connection_info } =>{
@@ -78,18 +121,7 @@ async fn handle_message(message: WebSocketMessage, status: &mut ControllerStatus
         }
 
         CommandType::ActiveConnections{ users } => {
-            let client_cache_clone = client_cache.clone();
-            
-            let mut client_cache = client_cache.lock().await;
-            for user in users {
-                if user.uuid != client_cache.local_uuid {
-                    client_cache.add_or_update_connection(user);
-                }
-            }
-
-            status.ui.app_window.upgrade_in_event_loop(|ui| {
-                spawn_local(update_connection_info(ui, client_cache_clone)).expect("Failed to update_connection");
-            }).expect("Failed to update connection status");
+            show_connections(users, &status.ui.app_window, client_cache).await;
         }
 
         CommandType::ProvideCapabilities { sender_uuid, list } => {
@@ -108,17 +140,31 @@ async fn handle_message(message: WebSocketMessage, status: &mut ControllerStatus
             }).expect("Failed to update connection status");
         }
 
-        CommandType::FileTransferAck { name, .. } | CommandType::FileTransferNack { name, .. } => {
-
-            let mut locked_cache = client_cache.lock().await;
-            
-            if let Some(thread) = locked_cache.file_transfer_threads.get(&name) {
-                if !thread.is_closed() {
-                    thread.send(message.command).expect("Failed to send message to file transfer thread");
+        CommandType::FileTransferAck { name, transfer_id, .. } | CommandType::FileTransferNack { name, transfer_id, .. }
+            | CommandType::FileTransferProgress { name, transfer_id, .. } | CommandType::FileTransferCancel { name, transfer_id, .. } => {
+            let Some(mut key) = message.command.transfer_key() else {
+                return;
+            };
+
+            let thread = {
+                let mut locked_cache = client_cache.lock().await;
+                // The server names the peer, only which of its transfers is meant may be missing
+                if transfer_id.is_empty() && let Some(legacy_key) = locked_cache.legacy_transfer(&key.peer, &name) {
+                    key = legacy_key;
                 }
-                else {
-                    locked_cache.file_transfer_threads.remove(&name);
+
+                match locked_cache.file_transfer_threads.get(&key) {
+                    Some(thread) if thread.is_finished() => {
+                        locked_cache.file_transfer_threads.remove(&key);
+                        None
+                    }
+                    thread => thread.cloned()
                 }
+            };
+
+            // The transfer thread needs the cache to send, so it can't stay locked while waiting for room
+            if let Some(thread) = thread {
+                thread.handle_reply(message.command).await;
             }
         }
 
@@ -130,6 +176,14 @@ async fn handle_message(message: WebSocketMessage, status: &mut ControllerStatus
             }).expect("Failed to update connection status");
         }
 
+        CommandType::Error { code, message, in_reply_to } => {
+            status.report_error(code, message, in_reply_to);
+        }
+
+        CommandType::DeliveryReceipt { recipient_uuid, status: delivery_status } => {
+            status.report_delivery(recipient_uuid, delivery_status, message.in_reply_to);
+        }
+
         CommandType::UpdateFile { uuid, file, add} => {
             client_cache.lock().await.update_file(uuid, file, add);
             let client_cache_clone = client_cache.clone();
@@ -140,4 +194,20 @@ async fn handle_message(message: WebSocketMessage, status: &mut ControllerStatus
 
         _ => {}
     }
-}
\ No newline at end of file
+}
+
+/// Adds the connections the server listed to the cache and refreshes the UI
+async fn show_connections(users: Vec<ConnectionInfo>, app_window: &Weak<AppWindow>, client_cache: ThreadSafeClientCache) {
+    {
+        let mut locked_cache = client_cache.lock().await;
+        for user in users {
+            if user.uuid != locked_cache.local_uuid {
+                locked_cache.add_or_update_connection(user);
+            }
+        }
+    }
+
+    app_window.upgrade_in_event_loop(|ui| {
+        spawn_local(update_connection_info(ui, client_cache)).expect("Failed to update_connection");
+    }).expect("Failed to update connection status");
+}
@@ -1,11 +1,11 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::collections::HashMap;
-use std::path::{Path};
+use std::path::Path;
 use std::sync::Arc;
 use slint::{spawn_local, Model, ModelRc, SharedString, VecModel, Weak};
 use tokio::sync::Notify;
-use example_communication_common::{Destination, Sender, make_thread_safe, CommandType, ControlMessage, WebSocketMessage, start_file_transfer};
+use example_communication_common::{Destination, Sender, make_thread_safe, CommandType, ControlMessage, Feature, PendingRequests, QueueConfig, ReceiptPolicy, Selector, SessionToken, TransferKey, WebSocketMessage, start_file_transfer};
 use crate::communication::communication_thread;
 use crate::settings::{ClientCache, MyConfig, ThreadSafeClientCache, ThreadSafeSettings};
 
@@ -18,8 +18,13 @@ struct UI {
     app_window: Weak<AppWindow>,
 }
 
-async fn on_setting_edited(settings: ThreadSafeSettings, setting_name: String, new_value: String, client_cache: ThreadSafeClientCache, connection_state_changed: Arc<Notify>) {
+async fn on_setting_edited(app: Weak<AppWindow>, settings: ThreadSafeSettings, setting_name: String, new_value: String, client_cache: ThreadSafeClientCache, connection_state_changed: Arc<Notify>) {
     settings.lock().await.on_setting_edited(setting_name.to_string(), new_value.to_string(), client_cache.clone(), connection_state_changed.clone()).await;
+
+    // The connection filter may have changed which connections are listed
+    if let Some(app) = app.upgrade() {
+        update_connection_info(app, client_cache).await;
+    }
 }
 
 pub async fn update_connection_info(app: AppWindow, client_cache: ThreadSafeClientCache) {
@@ -27,7 +32,7 @@ pub async fn update_connection_info(app: AppWindow, client_cache: ThreadSafeClie
     app.set_connections(connections_model);
 }
 
-pub async fn run_command(client_cache: ThreadSafeClientCache, destination_uuid: SharedString, command_name: SharedString, options: ModelRc<UIOption>) {
+pub async fn run_command(app: Weak<AppWindow>, client_cache: ThreadSafeClientCache, destination_uuid: SharedString, command_name: SharedString, options: ModelRc<UIOption>) {
     let vec_options = options.as_any().downcast_ref::<VecModel<UIOption>>().expect("We know we set a VecModel earlier");
     let mut hashed_options: HashMap<String, UIOption> = HashMap::new();
 
@@ -40,23 +45,44 @@ pub async fn run_command(client_cache: ThreadSafeClientCache, destination_uuid:
             CommandType::Control {
                 message_type: ControlMessage::Message {
                     text: hashed_options["Text"].value.to_string(),
-                }
+                },
+                reply_uuid: None,
             }
         }
         "TransferFile" => {
-            let sender = start_file_transfer(hashed_options["File"].value.to_string(), destination_uuid.to_string().clone(), client_cache.clone()).await;
-
-            if let Some(sender) = sender {
-                client_cache.lock().await.file_transfer_threads.insert(Path::new(&hashed_options["File"].value.to_string()).file_name().unwrap().to_str().unwrap().to_string(), sender);
+            let path = hashed_options["File"].value.to_string();
+            let (transfer_config, already_sending) = {
+                let locked_cache = client_cache.lock().await;
+                let name = Path::new(&path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                (locked_cache.transfer_config, locked_cache.legacy_transfer(&destination_uuid, &name).is_some())
+            };
+            if already_sending {
+                // Replies from clients that predate transfer ids can't tell two transfers of the same file apart
+                show_command_result(&app, &client_cache, &destination_uuid, format!("TransferFile failed: {} is already being sent", path)).await;
+                return;
             }
-
-            CommandType::Ack {}
+            let Some(handle) = start_file_transfer(path.clone(), destination_uuid.to_string().clone(), client_cache.clone(), transfer_config, QueueConfig::default()).await else {
+                show_command_result(&app, &client_cache, &destination_uuid, format!("TransferFile failed: couldn't read {}", path)).await;
+                return;
+            };
+
+            client_cache.lock().await.file_transfer_threads.insert(handle.key(), handle.clone());
+            show_command_result(&app, &client_cache, &destination_uuid, format!("TransferFile: sending {}", handle.name)).await;
+            let result = match handle.finished().await {
+                Ok(()) => format!("TransferFile: sent {}", handle.name),
+                Err(e) => format!("TransferFile failed: {}", e),
+            };
+            // Takes it off the list of transfers that can be cancelled
+            client_cache.lock().await.file_transfer_threads.remove(&handle.key());
+            show_command_result(&app, &client_cache, &destination_uuid, result).await;
+            return;
         }
         "DeleteFile" => {
             CommandType::Control {
                 message_type: ControlMessage::DeleteFile {
                     path: hashed_options["File"].value.to_string(),
-                }
+                },
+                reply_uuid: None,
             }
         }
         _ => {
@@ -64,10 +90,75 @@ pub async fn run_command(client_cache: ThreadSafeClientCache, destination_uuid:
         }
     };
 
-    let _ = client_cache.lock().await.try_send(WebSocketMessage{
-        command,
-        destination: Destination::Single{destination_uuid: destination_uuid.to_string()},
+    let supports_receipts = client_cache.lock().await.connected_clients.iter()
+        .find(|connection| connection.uuid == destination_uuid.as_str())
+        .is_some_and(|connection| connection.supports(Feature::ControlReceipts));
+
+    match command {
+        CommandType::Control { message_type, .. } if supports_receipts => {
+            let name = message_type.as_str().to_string();
+            show_command_result(&app, &client_cache, &destination_uuid, format!("{}: waiting for the client", name)).await;
+
+            let status = client_cache.lock().await.send_control(destination_uuid.to_string(), message_type, ReceiptPolicy::default());
+            let result = match status.await {
+                Ok(status) => format!("{}: {}", name, status),
+                Err(e) => format!("{} failed: {}", name, e),
+            };
+            show_command_result(&app, &client_cache, &destination_uuid, result).await;
+        }
+        command => {
+            // Older clients can't confirm anything
+            let _ = client_cache.lock().await.try_send(WebSocketMessage{
+                command,
+                destination: Destination::Single{destination_uuid: destination_uuid.to_string()},
+                id: None,
+                in_reply_to: None,
+                durable_ttl: None,
+            });
+        }
+    }
+}
+
+/// Stops a transfer started with `TransferFile`, `run_command` reports how it ended
+pub async fn cancel_transfer(client_cache: ThreadSafeClientCache, destination_uuid: SharedString, transfer_id: SharedString) {
+    let key = TransferKey { peer: destination_uuid.to_string(), transfer_id: transfer_id.to_string() };
+    if let Some(thread) = client_cache.lock().await.file_transfer_threads.get(&key) {
+        thread.cancel();
+    }
+}
+
+/// Sends `text` to every member of the group, the server answers with `UnknownGroup` if there is no such group
+pub async fn message_group(app: Weak<AppWindow>, client_cache: ThreadSafeClientCache, group_name: SharedString, text: SharedString) {
+    let locked_cache = client_cache.lock().await;
+    // Sending while disconnected drops the message without saying so
+    let connected = locked_cache.get_connection().is_some();
+    let sent = locked_cache.try_send(WebSocketMessage{
+        command: CommandType::Control {
+            message_type: ControlMessage::Message { text: text.to_string() },
+            reply_uuid: None,
+        },
+        destination: Destination::Group { name: group_name.to_string() },
+        id: None,
+        in_reply_to: None,
+        durable_ttl: None,
     });
+    drop(locked_cache);
+
+    let result = match sent {
+        Ok(()) if !connected => "Message failed: not connected".to_string(),
+        Ok(()) => format!("Message: sent to {}", group_name),
+        Err(e) => format!("Message failed: {}", e),
+    };
+    if let Some(app) = app.upgrade() {
+        app.set_group_result(result.into());
+    }
+}
+
+async fn show_command_result(app: &Weak<AppWindow>, client_cache: &ThreadSafeClientCache, uuid: &str, result: String) {
+    client_cache.lock().await.command_results.insert(uuid.to_string(), result);
+    if let Some(app) = app.upgrade() {
+        update_connection_info(app, client_cache.clone()).await;
+    }
 }
 
 #[tokio::main]
@@ -79,13 +170,25 @@ async fn main() {
     let options_model = ModelRc::new(VecModel::from(settings.fill_data_model()));
     app.set_options(options_model.clone().into());
 
+    let connection_filter = match settings.connection_filter.parse::<Selector>() {
+        Ok(selector) => selector,
+        Err(e) => {
+            println!("Ignoring connection filter: {}", e);
+            Selector::default()
+        }
+    };
     let client_cache = make_thread_safe(ClientCache{
         local_uuid: "".to_string(),
         to_server: None,
+        requests: PendingRequests::default(),
+        session: SessionToken::default(),
         connected_clients: Vec::new(),
         client_capabilities: HashMap::new(),
+        connection_filter,
+        transfer_config: settings.transfer_config(),
         file_transfer_threads: HashMap::new(),
         client_files: HashMap::new(),
+        command_results: HashMap::new(),
     });
     let settings = make_thread_safe(settings);
 
@@ -94,13 +197,26 @@ async fn main() {
     let settings_clone = settings.clone();
     let client_cache_clone = client_cache.clone();
     let connection_state_changed_sender = connection_data_changed.clone();
+    let app_weak = app.as_weak();
     app.on_option_edited(move |option_name: SharedString, new_value: SharedString| {
-        spawn_local(on_setting_edited(settings_clone.clone(), option_name.to_string(), new_value.to_string(), client_cache_clone.clone(), connection_state_changed_sender.clone())).expect("Failed to update settings");
+        spawn_local(on_setting_edited(app_weak.clone(), settings_clone.clone(), option_name.to_string(), new_value.to_string(), client_cache_clone.clone(), connection_state_changed_sender.clone())).expect("Failed to update settings");
     });
 
     let client_cache_clone = client_cache.clone();
+    let app_weak = app.as_weak();
     app.on_capability_ran(move |client_name, capability_name, selected_options| {
-        spawn_local(run_command(client_cache_clone.clone(), client_name, capability_name, selected_options)).expect("Failed to Run Command");
+        spawn_local(run_command(app_weak.clone(), client_cache_clone.clone(), client_name, capability_name, selected_options)).expect("Failed to Run Command");
+    });
+
+    let client_cache_clone = client_cache.clone();
+    app.on_transfer_cancelled(move |client_name, transfer_id| {
+        spawn_local(cancel_transfer(client_cache_clone.clone(), client_name, transfer_id)).expect("Failed to Cancel Transfer");
+    });
+
+    let client_cache_clone = client_cache.clone();
+    let app_weak = app.as_weak();
+    app.on_group_messaged(move |group_name, text| {
+        spawn_local(message_group(app_weak.clone(), client_cache_clone.clone(), group_name, text)).expect("Failed to Message Group");
     });
 
     let ui = UI {
@@ -1,14 +1,13 @@
 use crate::UIType;
 use std::collections::HashMap;
-use tokio::sync::mpsc::error::SendError;
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Notify};
-use example_communication_common::{CommandType, ConnectionInfo, ConnectionSettings, ConnectionType, ControlTypes, Destination, FileDefinition, Sender, ThreadSafe, UITypes, WebSocketMessage};
+use example_communication_common::{CommandType, ConnectionInfo, ConnectionSettings, ConnectionType, ControlTypes, Destination, Feature, FileDefinition, FileTransferHandle, Labels, PendingRequests, QueueSender, Selector, SessionToken, Sender, ThreadSafe, TransferConfig, TransferKey, UITypes, WebSocketMessage, PROTOCOL_VERSION};
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
 use field_name::FieldNames;
 use slint::{ModelRc, SharedString, VecModel};
-use crate::{ClientCapability, ClientConnection, UIOption};
+use crate::{ActiveTransfer, ClientCapability, ClientConnection, UIOption};
 
 pub type ThreadSafeSettings = ThreadSafe<MyConfig>;
 #[derive(FieldNames, Serialize, Deserialize)]
@@ -17,6 +16,26 @@ pub struct MyConfig {
     pub client_name: String,
     pub address: String,
     pub key: String,
+    /// Generated on first start and never changed, so peers can tell this machine apart across restarts
+    #[serde(default)]
+    pub device_id: String,
+    /// Label selector the connection list is narrowed to, e.g. `site=berlin,role!=kiosk`
+    #[serde(default)]
+    pub connection_filter: String,
+    /// Size of the chunks files are sent in, receivers may ask for smaller ones
+    #[serde(default = "default_transfer_blob_size")]
+    pub transfer_blob_size: usize,
+    /// Most chunks of a file in flight at once
+    #[serde(default = "default_transfer_max_window")]
+    pub transfer_max_window: usize,
+}
+
+fn default_transfer_blob_size() -> usize {
+    TransferConfig::default().blob_size
+}
+
+fn default_transfer_max_window() -> usize {
+    TransferConfig::default().max_window
 }
 
 impl ConnectionSettings for MyConfig {
@@ -35,10 +54,18 @@ impl Default for MyConfig {
             client_name: "Controller".to_owned(),
             address: "ws://localhost:8080/ws".to_owned(),
             key: "".to_owned(),
+            device_id: new_device_id(),
+            connection_filter: "".to_owned(),
+            transfer_blob_size: default_transfer_blob_size(),
+            transfer_max_window: default_transfer_max_window(),
         }
     }
 }
 
+fn new_device_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
 impl MyConfig {
     pub fn load() -> MyConfig {
         println!("Loading MyConfig from {:?}", confy::get_configuration_file_path("play_with_me_controller", None));
@@ -48,7 +75,25 @@ impl MyConfig {
             MyConfig::default()
         }
         else {
-            loaded_settings.unwrap()
+            let mut settings: MyConfig = loaded_settings.unwrap();
+
+            // Configs saved before device IDs existed get one the first time they're loaded
+            if settings.device_id.is_empty() {
+                settings.device_id = new_device_id();
+                if let Err(e) = confy::store("play_with_me_controller", None, &settings) {
+                    println!("Failed to store the new device ID: {}", e);
+                }
+            }
+
+            settings
+        }
+    }
+
+    pub fn transfer_config(&self) -> TransferConfig {
+        TransferConfig {
+            blob_size: self.transfer_blob_size,
+            max_window: self.transfer_max_window,
+            ..TransferConfig::default()
         }
     }
 
@@ -78,6 +123,13 @@ impl MyConfig {
                 r#type: UIType::Text,
                 value: self.key.clone().into(),
                 options: ModelRc::new(VecModel::default()),
+            },
+            UIOption{
+                display: "Connection Filter".into(),
+                name: MyConfig::CONNECTION_FILTER.into(),
+                r#type: UIType::Text,
+                value: self.connection_filter.clone().into(),
+                options: ModelRc::new(VecModel::default()),
             }
         )
     }
@@ -88,16 +140,28 @@ impl MyConfig {
                 self.client_name = new_value.clone();
                 self.save().await;
 
-                client_cache.lock().await.try_send(WebSocketMessage {
+                // The server renames the connection and tells its peers
+                let client_cache = client_cache.lock().await;
+                if let Err(error) = client_cache.try_send(WebSocketMessage {
                     command: CommandType::UpdateConnection {
                         connection_info: ConnectionInfo {
-                            uuid: client_cache.lock().await.local_uuid.to_string(),
+                            uuid: client_cache.local_uuid.to_string(),
                             name: new_value.to_string(),
-                            connection_type: ConnectionType::Client,
+                            connection_type: ConnectionType::Controller,
+                            latency_ms: None,
+                            device_id: Some(self.device_id.clone()),
+                            protocol_version: PROTOCOL_VERSION,
+                            features: Feature::SUPPORTED.to_vec(),
+                            labels: Labels::new(),
                         },
                     },
                     destination: Destination::None,
-                }).expect("Failed to Update Connection Information");
+                    id: None,
+                    in_reply_to: None,
+                    durable_ttl: None,
+                }) {
+                    println!("Failed to Update Connection Information: {}", error);
+                }
             }
             MyConfig::ADDRESS => {
                 self.address = new_value.clone();
@@ -109,6 +173,17 @@ impl MyConfig {
                 self.save().await;
                 connection_state_changed.notify_one();
             }
+            MyConfig::CONNECTION_FILTER => {
+                // A filter that doesn't parse is left out until it's fixed, rather than hiding every connection
+                match new_value.parse::<Selector>() {
+                    Ok(selector) => {
+                        self.connection_filter = new_value.clone();
+                        self.save().await;
+                        client_cache.lock().await.connection_filter = selector;
+                    }
+                    Err(e) => println!("Ignoring connection filter: {}", e),
+                }
+            }
             _ => {
 
             }
@@ -119,75 +194,141 @@ impl MyConfig {
 pub type ThreadSafeClientCache = ThreadSafe<ClientCache>;
 pub struct ClientCache {
     pub local_uuid: String,
-    pub to_server: Option<UnboundedSender<WebSocketMessage>>,
+    pub to_server: Option<QueueSender<WebSocketMessage>>,
+    pub requests: PendingRequests,
+    pub session: SessionToken,
     pub connected_clients: Vec<ConnectionInfo>,
     pub client_capabilities: HashMap<String, Vec<ControlTypes>>,
+    /// Only connections whose labels match are listed
+    pub connection_filter: Selector,
     pub client_files: HashMap<String, HashMap<String, Vec<String>>>,
-    pub file_transfer_threads: HashMap<String, UnboundedSender<CommandType>>
+    /// What came of the last command run on each connection, shown next to it
+    pub command_results: HashMap<String, String>,
+    /// How files are sent, from the settings
+    pub transfer_config: TransferConfig,
+    pub file_transfer_threads: HashMap<TransferKey, FileTransferHandle>
 }
 
 impl Sender for ClientCache {
     fn get_uuid(&self) -> String {
         self.local_uuid.clone()
     }
-    fn try_send(&self, message: WebSocketMessage) -> Result<(), SendError<WebSocketMessage>>{
-        if let Some(to_server) = &self.to_server {
-            return to_server.send(message)
-        }
-        Ok(())
+    fn get_connection(&self) -> Option<QueueSender<WebSocketMessage>> {
+        self.to_server.clone()
     }
 
     fn drop_connection(&mut self) {
-        self.try_send(WebSocketMessage{
+        // The websocket loop may be gone already, or too far behind to take the message
+        let _ = self.try_send(WebSocketMessage{
             command: CommandType::Disconnect,
-            destination: Destination::None
-        }).expect("Failed to send message");
+            destination: Destination::None,
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        });
 
         self.to_server = None;
     }
 
-    fn set_connection(&mut self, new_sender: UnboundedSender<WebSocketMessage>) {
+    fn set_connection(&mut self, new_sender: QueueSender<WebSocketMessage>) {
         self.to_server = Some(new_sender);
     }
+
+    fn get_requests(&self) -> PendingRequests {
+        self.requests.clone()
+    }
+
+    fn get_session(&self) -> SessionToken {
+        self.session.clone()
+    }
 }
 
 impl ClientCache {
     pub fn remove_connection(&mut self, uuid: String) {
         self.connected_clients.retain(|c| c.uuid != uuid);
         self.client_capabilities.remove(uuid.as_str());
+        self.command_results.remove(uuid.as_str());
     }
 
     pub fn add_or_update_connection(&mut self, connection_info: ConnectionInfo) {
+        // A machine that restarted comes back with a new UUID, drop what was known under the old one
+        if let Some(device_id) = &connection_info.device_id {
+            let stale_uuids: Vec<String> = self.connected_clients.iter()
+                .filter(|c| c.device_id.as_ref() == Some(device_id) && c.uuid != connection_info.uuid)
+                .map(|c| c.uuid.clone())
+                .collect();
+            for uuid in stale_uuids {
+                self.client_files.remove(&uuid);
+                self.remove_connection(uuid);
+            }
+        }
+
         let found = self.connected_clients.iter_mut().find(|c| c.uuid == connection_info.uuid);
 
         if let Some(connection) = found {
             connection.name = connection_info.name;
+            connection.device_id = connection_info.device_id;
+            connection.protocol_version = connection_info.protocol_version;
+            connection.features = connection_info.features;
+            connection.labels = connection_info.labels;
+            // Only latency reports carry it, a rename doesn't mean the latency is gone
+            connection.latency_ms = connection_info.latency_ms.or(connection.latency_ms);
         }
         else {
-            self.try_send(WebSocketMessage {
-                command: CommandType::RequestCapabilities {
-                    reply_uuid: self.local_uuid.clone(),
-                },
-                destination: Destination::Single{destination_uuid: connection_info.uuid.clone()},
-            }).expect("Failed to Send Message");
-
-            self.try_send(WebSocketMessage {
-                command: CommandType::AddFileWatch {
-                    return_uuid: self.local_uuid.clone()
-                },
-                destination: Destination::Single{destination_uuid: connection_info.uuid.clone()},
-            }).expect("Failed to Send Message");
+            // Only ask for what the client's version can answer, the server wouldn't route the rest anyway
+            if connection_info.supports(Feature::Control) {
+                if let Err(error) = self.try_send(WebSocketMessage {
+                    command: CommandType::RequestCapabilities {
+                        reply_uuid: self.local_uuid.clone(),
+                    },
+                    destination: Destination::Single{destination_uuid: connection_info.uuid.clone()},
+                    id: None,
+                    in_reply_to: None,
+                    durable_ttl: None,
+                }) {
+                    println!("Failed to Send Message: {}", error);
+                }
+            }
+
+            if connection_info.supports(Feature::FileWatch) {
+                if let Err(error) = self.try_send(WebSocketMessage {
+                    command: CommandType::AddFileWatch {
+                        return_uuid: self.local_uuid.clone()
+                    },
+                    destination: Destination::Single{destination_uuid: connection_info.uuid.clone()},
+                    id: None,
+                    in_reply_to: None,
+                    durable_ttl: None,
+                }) {
+                    println!("Failed to Send Message: {}", error);
+                }
+            }
 
             self.connected_clients.push(connection_info);
         }
     }
 
-    fn fill_capability_model(&self, uuid: String) -> Vec<ClientCapability> {
+    /// The transfer of `name` to `peer` if the peer predates transfer ids, its replies only say which file they mean
+    pub fn legacy_transfer(&self, peer: &str, name: &str) -> Option<TransferKey> {
+        let legacy = self.connected_clients.iter()
+            .find(|connection| connection.uuid == peer)
+            .is_some_and(|connection| !connection.supports(Feature::ResumableTransfers));
+        if !legacy {
+            return None;
+        }
+        self.file_transfer_threads.iter()
+            .find(|(key, thread)| key.peer == peer && thread.name == name)
+            .map(|(key, _)| key.clone())
+    }
+
+    fn fill_capability_model(&self, connection: &ConnectionInfo) -> Vec<ClientCapability> {
         let mut capability_definitions = Vec::new();
+        let uuid = connection.uuid.clone();
 
         let capabilities = self.client_capabilities.get(&uuid);
         if let Some(capabilities) = capabilities {
-            for capability in capabilities {
+            // Capabilities the client's protocol version can't carry out are hidden
+            for capability in capabilities.iter().filter(|capability| connection.supports(capability.required_feature())) {
                 let definition = capability.to_definition();
 
                 let mut definition_options = Vec::new();
@@ -245,13 +386,20 @@ impl ClientCache {
     pub fn fill_data_model(& self) -> Vec<ClientConnection> {
         let mut rv = Vec::new();
 
-        for connected_client in &self.connected_clients {
-            let capabilities_model = ModelRc::new(VecModel::from(self.fill_capability_model(connected_client.uuid.clone())));
+        for connected_client in self.connected_clients.iter().filter(|c| self.connection_filter.matches(&c.labels)) {
+            let capabilities_model = ModelRc::new(VecModel::from(self.fill_capability_model(connected_client)));
+            let transfers: Vec<ActiveTransfer> = self.file_transfer_threads.iter()
+                .filter(|(key, thread)| key.peer == connected_client.uuid && !thread.is_finished())
+                .map(|(_, thread)| ActiveTransfer { id: thread.transfer_id.clone().into(), name: thread.name.clone().into() })
+                .collect();
             rv.push(
                 ClientConnection {
                     capabilities: capabilities_model,
                     display_name: connected_client.name.clone().into(),
+                    latency: connected_client.latency_ms.map(|latency_ms| format!("{} ms", latency_ms)).unwrap_or_default().into(),
                     name: connected_client.uuid.clone().into(),
+                    last_result: self.command_results.get(&connected_client.uuid).cloned().unwrap_or_default().into(),
+                    transfers: ModelRc::new(VecModel::from(transfers)),
                 });
         }
 
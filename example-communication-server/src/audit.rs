@@ -0,0 +1,332 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use clap::Parser;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use example_communication_common::{CommandType, Destination, WebSocketMessage};
+
+/// Where the audit log goes and how much of it is kept
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// Size the log may grow to before it's rotated
+    pub max_bytes: u64,
+    /// Rotated files kept next to the current one, `audit.log.1` being the newest
+    pub max_files: usize,
+}
+
+/// One received message, as written to the log
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub sender_uuid: String,
+    pub command: String,
+    pub destination: Destination,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<u64>,
+    /// The command's fields, with file transfer payloads replaced by their size
+    pub details: Value,
+}
+
+impl AuditEntry {
+    pub fn new(sender_uuid: &str, message: &WebSocketMessage) -> Self {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or_default();
+
+        // Serializing the blob would build a JSON value for every byte just to throw it away
+        let details = match &message.command {
+            CommandType::FileTransferBlob { name, transfer_id, chunk_num, blob, return_uuid } => json!({
+                "FileTransferBlob": {
+                    "name": name,
+                    "transfer_id": transfer_id,
+                    "chunk_num": chunk_num,
+                    "blob": format!("<{} bytes>", blob.len()),
+                    "return_uuid": return_uuid,
+                }
+            }),
+            command => serde_json::to_value(command).unwrap_or(Value::Null),
+        };
+
+        Self {
+            timestamp_ms,
+            sender_uuid: sender_uuid.to_string(),
+            command: message.command.as_str().to_string(),
+            destination: message.destination.clone(),
+            id: message.id,
+            in_reply_to: message.in_reply_to,
+            details,
+        }
+    }
+
+    /// Whether `uuid` sent the message or was named as its recipient
+    fn involves(&self, uuid: &str) -> bool {
+        self.sender_uuid == uuid || match &self.destination {
+            Destination::Single { destination_uuid } => destination_uuid == uuid,
+            Destination::Multi { destination_uuids } => destination_uuids.iter().any(|destination_uuid| destination_uuid == uuid),
+            _ => false
+        }
+    }
+}
+
+/// Entries waiting for the writer thread, past this they're dropped rather than held in memory
+const AUDIT_QUEUE_CAPACITY: usize = 4096;
+
+/// Append-only JSON lines log of every message the server receives, written on its own thread so logging never holds
+/// up routing. Logs nothing if it wasn't opened.
+#[derive(Default)]
+pub struct AuditLog {
+    entries: Option<mpsc::SyncSender<AuditEntry>>,
+    /// Set while entries are being dropped because the writer can't keep up
+    behind: AtomicBool,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, failing right away if it can't be written
+    pub fn open(config: AuditConfig) -> std::io::Result<Self> {
+        let writer = AuditWriter::open(config)?;
+        let (entries, receiver) = mpsc::sync_channel(AUDIT_QUEUE_CAPACITY);
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self { entries: Some(entries), behind: AtomicBool::new(false) })
+    }
+
+    pub fn record(&self, sender_uuid: &str, message: &WebSocketMessage) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+
+        match entries.try_send(AuditEntry::new(sender_uuid, message)) {
+            Ok(()) => {
+                if self.behind.swap(false, Ordering::Relaxed) {
+                    info!("audit log caught up");
+                }
+            }
+            Err(mpsc::TrySendError::Full(entry)) => {
+                if !self.behind.swap(true, Ordering::Relaxed) {
+                    warn!("audit log fell {} entries behind, dropping entries until it catches up, starting with {} from {}", AUDIT_QUEUE_CAPACITY, entry.command, entry.sender_uuid);
+                }
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+struct AuditWriter {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditWriter {
+    fn open(config: AuditConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { config, file, size })
+    }
+
+    fn run(mut self, entries: mpsc::Receiver<AuditEntry>) {
+        for entry in entries {
+            if let Err(e) = self.write(&entry) {
+                warn!("failed to write to the audit log {}: {}", self.config.path.display(), e);
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &AuditEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `audit.log.1` to `audit.log.2` and so on, dropping the oldest, and starts a fresh `audit.log`
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.config.max_files == 0 {
+            std::fs::remove_file(&self.config.path)?;
+        } else {
+            for index in (1..self.config.max_files).rev() {
+                let from = rotated_path(&self.config.path, index);
+                if from.exists() {
+                    std::fs::rename(from, rotated_path(&self.config.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.config.path, rotated_path(&self.config.path, 1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Prints the entries of an audit log matching every given filter, oldest first, as JSON lines
+#[derive(Parser, Debug, Default)]
+pub struct AuditQuery {
+    /// The audit log, rotated files next to it are read too
+    pub path: PathBuf,
+    /// Only messages this UUID sent or was named as a recipient of
+    #[arg(long)]
+    pub client: Option<String>,
+    /// Only this command type, e.g. `Control`, may be given multiple times
+    #[arg(long = "command")]
+    pub commands: Vec<String>,
+    /// Only messages from this time on, as Unix seconds or how long ago such as `90s`, `15m`, `2h` or `1d`
+    #[arg(long, value_parser = parse_time)]
+    pub since: Option<u64>,
+    /// Only messages up to this time, in the same format as `--since`
+    #[arg(long, value_parser = parse_time)]
+    pub until: Option<u64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.client.as_ref().is_none_or(|client| entry.involves(client))
+            && (self.commands.is_empty() || self.commands.contains(&entry.command))
+            && self.since.is_none_or(|since| entry.timestamp_ms >= since)
+            && self.until.is_none_or(|until| entry.timestamp_ms <= until)
+    }
+
+    /// Reads the log and its rotated files, oldest first, and writes out every matching entry
+    pub fn run(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut paths: Vec<PathBuf> = (1..).map(|index| rotated_path(&self.path, index)).take_while(|path| path.exists()).collect();
+        paths.reverse();
+        paths.push(self.path.clone());
+
+        for path in paths {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                match serde_json::from_str::<AuditEntry>(&line) {
+                    Ok(entry) if self.matches(&entry) => writeln!(out, "{}", line)?,
+                    Ok(_) => {}
+                    // A line cut short by a crash shouldn't hide the rest of the log
+                    Err(e) => eprintln!("Skipping unreadable line in {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a time as Unix seconds or as a duration before now, in milliseconds since the epoch
+fn parse_time(value: &str) -> Result<u64, String> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds * 1000);
+    }
+
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| format!("`{}` is neither Unix seconds nor a duration like `15m`", value))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}` in `{}`, expected s, m, h or d", unit, value)),
+    };
+
+    let ago = Duration::from_secs(amount * unit_secs);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(now.saturating_sub(ago).as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use example_communication_common::{CommandType, ControlMessage, Destination, WebSocketMessage};
+    use super::{rotated_path, AuditConfig, AuditEntry, AuditQuery, AuditWriter};
+
+    fn message(command: CommandType, destination_uuid: &str) -> WebSocketMessage {
+        WebSocketMessage {
+            command,
+            destination: Destination::Single { destination_uuid: destination_uuid.to_string() },
+            id: None,
+            in_reply_to: None,
+            durable_ttl: None,
+        }
+    }
+
+    fn control(destination_uuid: &str) -> WebSocketMessage {
+        message(CommandType::Control { message_type: ControlMessage::Message { text: "hello".to_string() }, reply_uuid: None }, destination_uuid)
+    }
+
+    fn log_path() -> PathBuf {
+        std::env::temp_dir().join(format!("audit-{}.log", uuid::Uuid::new_v4()))
+    }
+
+    fn query(path: &Path, query: AuditQuery) -> Vec<AuditEntry> {
+        let mut out = Vec::new();
+        AuditQuery { path: path.to_path_buf(), ..query }.run(&mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    fn remove_logs(path: &Path) {
+        for index in 0..10 {
+            let path = if index == 0 { path.to_path_buf() } else { rotated_path(path, index) };
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn blobs_are_elided() {
+        let blob = message(CommandType::FileTransferBlob { name: "a.txt".to_string(), transfer_id: "transfer".to_string(), chunk_num: 0, blob: vec![0; 1024], return_uuid: "sender".to_string() }, "receiver");
+
+        let entry = AuditEntry::new("sender", &blob);
+
+        assert_eq!(entry.command, "FileTransferBlob");
+        assert_eq!(entry.details["FileTransferBlob"]["blob"], "<1024 bytes>");
+        assert_eq!(entry.details["FileTransferBlob"]["name"], "a.txt");
+        assert_eq!(entry.details["FileTransferBlob"]["chunk_num"], 0);
+        assert_eq!(entry.details["FileTransferBlob"]["return_uuid"], "sender");
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_files() {
+        let path = log_path();
+        let mut writer = AuditWriter::open(AuditConfig { path: path.clone(), max_bytes: 1, max_files: 2 }).unwrap();
+
+        for sender in ["first", "second", "third", "fourth"] {
+            writer.write(&AuditEntry::new(sender, &control("receiver"))).unwrap();
+        }
+
+        assert!(!rotated_path(&path, 3).exists());
+        let senders: Vec<String> = query(&path, AuditQuery::default()).into_iter().map(|entry| entry.sender_uuid).collect();
+        assert_eq!(senders, vec!["second", "third", "fourth"]);
+        remove_logs(&path);
+    }
+
+    #[test]
+    fn query_filters_by_client_and_command() {
+        let path = log_path();
+        let mut writer = AuditWriter::open(AuditConfig { path: path.clone(), max_bytes: u64::MAX, max_files: 1 }).unwrap();
+        writer.write(&AuditEntry::new("controller", &control("lab-pc"))).unwrap();
+        writer.write(&AuditEntry::new("controller", &control("kiosk"))).unwrap();
+        writer.write(&AuditEntry::new("lab-pc", &message(CommandType::Ack, "controller"))).unwrap();
+
+        assert_eq!(query(&path, AuditQuery { client: Some("lab-pc".to_string()), ..AuditQuery::default() }).len(), 2);
+        assert_eq!(query(&path, AuditQuery { commands: vec!["Ack".to_string()], ..AuditQuery::default() }).len(), 1);
+        assert!(query(&path, AuditQuery { since: Some(u64::MAX), ..AuditQuery::default() }).is_empty());
+        remove_logs(&path);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use serde::Deserialize;
+use example_communication_common::{CommandType, ConnectionType, ControlMessage};
+
+/// Grants a set of commands, optionally narrowed to specific control messages and recipient types.
+/// Empty lists place no restriction, and `*` matches any command or control message.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Permission {
+    pub commands: Vec<String>,
+    pub controls: Vec<String>,
+    pub to: Vec<ConnectionType>,
+}
+
+impl Permission {
+    /// The first command or control message this permission names that doesn't exist, a typo would grant nothing
+    pub fn unknown_name(&self) -> Option<&str> {
+        let known = |name: &str, names: &[&str]| name == "*" || names.contains(&name);
+        self.commands.iter().find(|name| !known(name, CommandType::NAMES))
+            .or_else(|| self.controls.iter().find(|name| !known(name, ControlMessage::NAMES)))
+            .map(String::as_str)
+    }
+
+    fn matches_command(&self, command: &CommandType) -> bool {
+        let command_allowed = self.commands.is_empty() || self.commands.iter().any(|name| name == "*" || name == command.as_str());
+        if !command_allowed {
+            return false;
+        }
+
+        match command {
+            CommandType::Control { message_type, .. } => {
+                self.controls.is_empty() || self.controls.iter().any(|name| name == "*" || name == message_type.as_str())
+            }
+            _ => true
+        }
+    }
+
+    fn matches_recipient(&self, recipient: Option<&ConnectionType>) -> bool {
+        if self.to.is_empty() {
+            return true;
+        }
+
+        match recipient {
+            Some(recipient) => self.to.contains(recipient),
+            None => false
+        }
+    }
+}
+
+/// A named API key. Keys without a `connection_type` may identify as either type,
+/// and keys without `permissions` may send any command to anyone.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    #[serde(default)]
+    pub connection_type: Option<ConnectionType>,
+    #[serde(default)]
+    pub permissions: Option<Vec<Permission>>,
+}
+
+impl ApiKey {
+    pub fn unrestricted(name: String, key: String) -> Self {
+        Self { name, key, connection_type: None, permissions: None }
+    }
+
+    pub fn allows_connection_type(&self, connection_type: &ConnectionType) -> bool {
+        match &self.connection_type {
+            Some(allowed) => allowed == *connection_type,
+            None => true
+        }
+    }
+
+    /// Whether this key may send `command` to any recipient at all
+    pub fn allows_command(&self, command: &CommandType) -> bool {
+        match &self.permissions {
+            Some(permissions) => permissions.iter().any(|permission| permission.matches_command(command)),
+            None => true
+        }
+    }
+
+    /// Whether this key may send `command` to a peer of the given type. Peers that have not identified yet have no type.
+    pub fn allows_recipient(&self, command: &CommandType, recipient: Option<&ConnectionType>) -> bool {
+        match &self.permissions {
+            Some(permissions) => permissions.iter().any(|permission| permission.matches_command(command) && permission.matches_recipient(recipient)),
+            None => true
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyStore {
+    keys: Vec<Arc<ApiKey>>,
+}
+
+impl KeyStore {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Self { keys: keys.into_iter().map(Arc::new).collect() }
+    }
+
+    pub fn find(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.iter().find(|api_key| api_key.key == key).cloned()
+    }
+}
@@ -1,16 +1,93 @@
 use std::collections::HashMap;
+use std::ops::Deref;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::time::Duration;
 use warp::Rejection;
 use warp::ws::Message;
-use example_communication_common::ConnectionInfo;
+use log::debug;
+use example_communication_common::{ConnectionInfo, EncodedMessage, QueueError, QueueSender, WebSocketMessage, WireFormat};
+use crate::router::Router;
 
 pub type Result<T> = std::result::Result<T, Rejection>;
-pub type ClientSendChannel = mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>;
+
+/// A connection's send queue, along with the wire format it asked for
+#[derive(Debug, Clone)]
+pub struct ClientSendChannel {
+    pub queue: QueueSender<Message>,
+    pub wire_format: WireFormat,
+}
+
+/// How long routing waits for room in a connection's queue under the `block` policy before dropping the message,
+/// so a connection that stopped reading can't hold up messages to everyone else
+pub const ROUTING_SEND_TIMEOUT: Duration = Duration::from_millis(250);
+
+impl ClientSendChannel {
+    /// Encodes `packet` in this connection's wire format
+    pub fn frame(&self, packet: &WebSocketMessage) -> Message {
+        encode_frame(self.wire_format, packet)
+    }
+
+    /// Queues `frame`, waiting at most `ROUTING_SEND_TIMEOUT` for room.
+    /// Fails once the queue is closed, or right away if it overflows under the disconnect policy.
+    pub async fn route(&self, frame: Message) -> std::result::Result<(), QueueError<Message>> {
+        let result = self.queue.send_timeout(frame, ROUTING_SEND_TIMEOUT).await;
+        if let Err(QueueError::Full(_)) = &result {
+            debug!("dropped a message for a connection that stayed full for {:?}", ROUTING_SEND_TIMEOUT);
+        }
+        result
+    }
+}
+
+impl Deref for ClientSendChannel {
+    type Target = QueueSender<Message>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+pub fn encode_frame(wire_format: WireFormat, packet: &WebSocketMessage) -> Message {
+    match wire_format.encode(packet) {
+        EncodedMessage::Text(text) => Message::text(text),
+        EncodedMessage::Binary(bytes) => Message::binary(bytes),
+    }
+}
+
+/// A packet encoded lazily, at most once per wire format
+pub struct Frames<'a> {
+    packet: &'a WebSocketMessage,
+    encoded: HashMap<WireFormat, Message>,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(packet: &'a WebSocketMessage) -> Self {
+        Self { packet, encoded: HashMap::new() }
+    }
+
+    /// Reuses the frame the packet arrived in for connections using the same format
+    pub fn with_original(packet: &'a WebSocketMessage, wire_format: WireFormat, frame: Message) -> Self {
+        Self { packet, encoded: HashMap::from([(wire_format, frame)]) }
+    }
+
+    pub fn get(&mut self, wire_format: WireFormat) -> Message {
+        self.encoded.entry(wire_format).or_insert_with(|| encode_frame(wire_format, self.packet)).clone()
+    }
+}
+
+/// Sends the same packet to each of `senders`, serializing it only once per wire format
+pub async fn fan_out(senders: Vec<ClientSendChannel>, frames: &mut Frames<'_>) {
+    // Each recipient gets its own bounded wait, so a full queue doesn't delay the ones after it
+    let sends = senders.iter().map(|sender| sender.route(frames.get(sender.wire_format)));
+    futures::future::join_all(sends).await;
+}
 
 #[derive(Debug, Clone)]
 pub struct Client {
     pub client_id: Option<ConnectionInfo>,
     pub sender: ClientSendChannel,
+    /// Round trip time of the most recent ping
+    pub latency: Option<Duration>,
+    /// Round trip time the controllers were last told about
+    pub reported_latency: Option<Duration>,
 }
-pub type ClientMap = Arc<Mutex<HashMap<String, Client>>>;
\ No newline at end of file
+pub type ClientMap = Arc<Router>;
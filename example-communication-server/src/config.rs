@@ -0,0 +1,480 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use serde::Deserialize;
+use thiserror::Error;
+use example_communication_common::{Heartbeat, OverflowPolicy, QueueConfig, DEFAULT_QUEUE_CAPACITY, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::audit::{AuditConfig, AuditQuery};
+use crate::auth::{ApiKey, KeyStore};
+use crate::mailbox::MailboxConfig;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("failed to parse config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("port must not be 0")]
+    InvalidPort,
+    #[error("route `{0}` is invalid, expected one or more path segments such as `ws` or `api/ws`")]
+    InvalidRoute(String),
+    #[error("no API keys configured, add `[[keys]]` or `api_keys` to the config file, pass --api-key or set API_KEY")]
+    NoApiKeys,
+    #[error("API key `{0}` is empty")]
+    EmptyApiKey(String),
+    #[error("API key names must not be empty")]
+    EmptyKeyName,
+    #[error("API key name `{0}` is used more than once")]
+    DuplicateKeyName(String),
+    #[error("API keys `{0}` and `{1}` share the same key")]
+    DuplicateKey(String, String),
+    #[error("API key `{key}` has a permission for `{name}`, which is neither a command nor a control message")]
+    UnknownPermission { key: String, name: String },
+    #[error("CORS origin `{0}` is invalid, expected `*` or an origin such as `https://example.com`")]
+    InvalidCorsOrigin(String),
+    #[error("TLS needs both a certificate and a private key, pass --tls-cert and --tls-key or set both in the `[tls]` section")]
+    IncompleteTls,
+    #[error("ping interval must be at least 1 second")]
+    InvalidPingInterval,
+    #[error("idle timeout ({idle_timeout}s) must be longer than the ping interval ({ping_interval}s)")]
+    InvalidIdleTimeout { ping_interval: u64, idle_timeout: u64 },
+    #[error("send queue capacity must be at least 1")]
+    InvalidSendQueueCapacity,
+    #[error("minimum protocol version {0} is newer than the {PROTOCOL_VERSION} this server speaks")]
+    InvalidMinProtocolVersion(u32),
+    #[error("mailbox capacity must be at least 1")]
+    InvalidMailboxCapacity,
+    #[error("audit log size must be at least 1 byte")]
+    InvalidAuditLogSize,
+    #[error("log level `{0}` is invalid, expected one of off, error, warn, info, debug, trace")]
+    InvalidLogLevel(String),
+}
+
+/// Command line flags, each of which can also be set through the listed environment variable.
+/// Anything set here overrides the value loaded from the config file.
+#[derive(Parser, Debug)]
+#[command(version, about = "Example-Communication websocket server")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Path to a TOML config file
+    #[arg(short, long, env = "SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to bind the server to
+    #[arg(long, env = "SERVER_BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    /// Port to listen on
+    #[arg(short, long, env = "SERVER_PORT")]
+    pub port: Option<u16>,
+    /// Path of the websocket route, e.g. `ws`
+    #[arg(long, env = "SERVER_ROUTE")]
+    pub route: Option<String>,
+    /// Accepted API key, may be given multiple times or comma separated
+    #[arg(long = "api-key", env = "API_KEY", value_delimiter = ',')]
+    pub api_keys: Vec<String>,
+    /// Allowed CORS origin, may be given multiple times or comma separated. `*` allows any origin
+    #[arg(long = "cors-origin", env = "SERVER_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+    /// Seconds between pings sent to each connection
+    #[arg(long, env = "SERVER_PING_INTERVAL")]
+    pub ping_interval: Option<u64>,
+    /// Seconds a connection may stay silent before it is dropped
+    #[arg(long, env = "SERVER_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+    /// Seconds a disconnected client may take to reconnect and resume its session before peers are told it left
+    #[arg(long, env = "SERVER_SESSION_TIMEOUT")]
+    pub session_timeout: Option<u64>,
+    /// Messages queued for each connection before `--send-queue-overflow` kicks in
+    #[arg(long, env = "SERVER_SEND_QUEUE_CAPACITY")]
+    pub send_queue_capacity: Option<usize>,
+    /// What to do when a connection's queue is full (block, drop_oldest, disconnect)
+    #[arg(long, env = "SERVER_SEND_QUEUE_OVERFLOW")]
+    pub send_queue_overflow: Option<OverflowPolicy>,
+    /// Oldest protocol version a connection may identify with, 0 accepts clients that predate versioning
+    #[arg(long, env = "SERVER_MIN_PROTOCOL_VERSION")]
+    pub min_protocol_version: Option<u32>,
+    /// File durable messages for offline devices are saved to, kept in memory only if not set
+    #[arg(long, env = "SERVER_MAILBOX_PATH")]
+    pub mailbox_path: Option<PathBuf>,
+    /// Durable messages held for each offline device
+    #[arg(long, env = "SERVER_MAILBOX_CAPACITY")]
+    pub mailbox_capacity: Option<usize>,
+    /// Longest a durable message is held in seconds, whatever TTL its sender asks for
+    #[arg(long, env = "SERVER_MAX_DURABLE_TTL")]
+    pub max_durable_ttl: Option<u64>,
+    /// Append-only JSON lines log of every message received, no audit log is written if not set
+    #[arg(long, env = "SERVER_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+    /// Size in bytes the audit log may grow to before it's rotated
+    #[arg(long, env = "SERVER_AUDIT_LOG_MAX_BYTES")]
+    pub audit_log_max_bytes: Option<u64>,
+    /// Rotated audit log files kept
+    #[arg(long, env = "SERVER_AUDIT_LOG_MAX_FILES")]
+    pub audit_log_max_files: Option<usize>,
+    /// Logging level (off, error, warn, info, debug, trace)
+    #[arg(long, env = "SERVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// PEM encoded certificate chain, enables wss:// when set together with --tls-key
+    #[arg(long, env = "SERVER_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key for --tls-cert
+    #[arg(long, env = "SERVER_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Queries an audit log instead of running the server
+    Audit(AuditQuery),
+}
+
+/// Certificate and key used to terminate TLS. Both files are watched and reloaded when they change.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Settings every connection is handled with
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    pub heartbeat: Heartbeat,
+    /// How long a disconnected client may take to resume its session
+    pub session_timeout: Duration,
+    pub send_queue: QueueConfig,
+    /// Connections identifying with an older protocol version are turned away
+    pub min_protocol_version: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub route: String,
+    /// Unrestricted keys, kept for configs that predate named keys
+    pub api_keys: Vec<String>,
+    pub keys: Vec<ApiKey>,
+    pub cors_origins: Vec<String>,
+    /// Seconds between pings sent to each connection
+    pub ping_interval: u64,
+    /// Seconds a connection may stay silent before it is dropped
+    pub idle_timeout: u64,
+    /// Seconds a disconnected client may take to reconnect and resume its session
+    pub session_timeout: u64,
+    /// Messages queued for each connection before `send_queue_overflow` kicks in
+    pub send_queue_capacity: usize,
+    pub send_queue_overflow: OverflowPolicy,
+    /// Oldest protocol version a connection may identify with
+    pub min_protocol_version: u32,
+    /// File durable messages are saved to, in memory only if `None`
+    pub mailbox_path: Option<PathBuf>,
+    /// Durable messages held for each offline device
+    pub mailbox_capacity: usize,
+    /// Longest a durable message is held in seconds
+    pub max_durable_ttl: u64,
+    /// JSON lines log of every message received, none is written if `None`
+    pub audit_log: Option<PathBuf>,
+    /// Size in bytes the audit log may grow to before it's rotated
+    pub audit_log_max_bytes: u64,
+    /// Rotated audit log files kept
+    pub audit_log_max_files: usize,
+    pub log_level: String,
+    pub tls: Option<TlsSettings>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            route: "ws".to_string(),
+            api_keys: vec![],
+            keys: vec![],
+            cors_origins: vec!["*".to_string()],
+            ping_interval: Heartbeat::default().ping_interval.as_secs(),
+            idle_timeout: Heartbeat::default().idle_timeout.as_secs(),
+            session_timeout: 60,
+            send_queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            send_queue_overflow: OverflowPolicy::Disconnect,
+            min_protocol_version: LEGACY_PROTOCOL_VERSION,
+            mailbox_path: None,
+            mailbox_capacity: MailboxConfig::default().capacity,
+            max_durable_ttl: MailboxConfig::default().max_ttl.as_secs(),
+            audit_log: None,
+            audit_log_max_bytes: 10 * 1024 * 1024,
+            audit_log_max_files: 5,
+            log_level: "info".to_string(),
+            tls: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the command line, the environment and the optional config file, then validates it
+    pub fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path.clone())?,
+            None => Self::default(),
+        };
+
+        config.apply_overrides(args)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(source) => return Err(ConfigError::Read { path, source }),
+        };
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse { path, source })
+    }
+
+    fn apply_overrides(&mut self, args: Args) -> Result<(), ConfigError> {
+        if let Some(bind_address) = args.bind_address {
+            self.bind_address = bind_address;
+        }
+        if let Some(port) = args.port {
+            self.port = port;
+        }
+        if let Some(route) = args.route {
+            self.route = route;
+        }
+        if !args.api_keys.is_empty() {
+            self.api_keys = args.api_keys;
+        }
+        if !args.cors_origins.is_empty() {
+            self.cors_origins = args.cors_origins;
+        }
+        if let Some(ping_interval) = args.ping_interval {
+            self.ping_interval = ping_interval;
+        }
+        if let Some(idle_timeout) = args.idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+        if let Some(session_timeout) = args.session_timeout {
+            self.session_timeout = session_timeout;
+        }
+        if let Some(send_queue_capacity) = args.send_queue_capacity {
+            self.send_queue_capacity = send_queue_capacity;
+        }
+        if let Some(send_queue_overflow) = args.send_queue_overflow {
+            self.send_queue_overflow = send_queue_overflow;
+        }
+        if let Some(min_protocol_version) = args.min_protocol_version {
+            self.min_protocol_version = min_protocol_version;
+        }
+        if let Some(mailbox_path) = args.mailbox_path {
+            self.mailbox_path = Some(mailbox_path);
+        }
+        if let Some(mailbox_capacity) = args.mailbox_capacity {
+            self.mailbox_capacity = mailbox_capacity;
+        }
+        if let Some(max_durable_ttl) = args.max_durable_ttl {
+            self.max_durable_ttl = max_durable_ttl;
+        }
+        if let Some(audit_log) = args.audit_log {
+            self.audit_log = Some(audit_log);
+        }
+        if let Some(audit_log_max_bytes) = args.audit_log_max_bytes {
+            self.audit_log_max_bytes = audit_log_max_bytes;
+        }
+        if let Some(audit_log_max_files) = args.audit_log_max_files {
+            self.audit_log_max_files = audit_log_max_files;
+        }
+        if let Some(log_level) = args.log_level {
+            self.log_level = log_level;
+        }
+        if args.tls_cert.is_some() || args.tls_key.is_some() {
+            let current = self.tls.take();
+            let cert_path = args.tls_cert.or(current.as_ref().map(|tls| tls.cert_path.clone()));
+            let key_path = args.tls_key.or(current.map(|tls| tls.key_path));
+            match (cert_path, key_path) {
+                (Some(cert_path), Some(key_path)) => self.tls = Some(TlsSettings { cert_path, key_path }),
+                _ => return Err(ConfigError::IncompleteTls),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::InvalidPort);
+        }
+
+        if self.route_segments().is_empty() || self.route_segments().iter().any(|segment| segment.is_empty()) {
+            return Err(ConfigError::InvalidRoute(self.route.clone()));
+        }
+
+        let api_keys = self.api_key_definitions();
+        if api_keys.is_empty() {
+            return Err(ConfigError::NoApiKeys);
+        }
+        for (index, api_key) in api_keys.iter().enumerate() {
+            if api_key.name.is_empty() {
+                return Err(ConfigError::EmptyKeyName);
+            }
+            if api_key.key.is_empty() {
+                return Err(ConfigError::EmptyApiKey(api_key.name.clone()));
+            }
+            let unknown_name = api_key.permissions.iter().flatten().find_map(|permission| permission.unknown_name());
+            if let Some(name) = unknown_name {
+                return Err(ConfigError::UnknownPermission { key: api_key.name.clone(), name: name.to_string() });
+            }
+            for other in &api_keys[..index] {
+                if other.name == api_key.name {
+                    return Err(ConfigError::DuplicateKeyName(api_key.name.clone()));
+                }
+                if other.key == api_key.key {
+                    return Err(ConfigError::DuplicateKey(other.name.clone(), api_key.name.clone()));
+                }
+            }
+        }
+
+        for origin in &self.cors_origins {
+            if origin != "*" && !is_valid_origin(origin) {
+                return Err(ConfigError::InvalidCorsOrigin(origin.clone()));
+            }
+        }
+
+        if self.ping_interval == 0 {
+            return Err(ConfigError::InvalidPingInterval);
+        }
+        if self.idle_timeout <= self.ping_interval {
+            return Err(ConfigError::InvalidIdleTimeout { ping_interval: self.ping_interval, idle_timeout: self.idle_timeout });
+        }
+
+        if self.send_queue_capacity == 0 {
+            return Err(ConfigError::InvalidSendQueueCapacity);
+        }
+
+        if self.min_protocol_version > PROTOCOL_VERSION {
+            return Err(ConfigError::InvalidMinProtocolVersion(self.min_protocol_version));
+        }
+
+        if self.mailbox_capacity == 0 {
+            return Err(ConfigError::InvalidMailboxCapacity);
+        }
+
+        if self.audit_log_max_bytes == 0 {
+            return Err(ConfigError::InvalidAuditLogSize);
+        }
+
+        self.level_filter()?;
+
+        Ok(())
+    }
+
+    /// Every accepted key, with the plain `api_keys` named `api-key-1`, `api-key-2`, ...
+    fn api_key_definitions(&self) -> Vec<ApiKey> {
+        let mut api_keys: Vec<ApiKey> = self.api_keys.iter().enumerate()
+            .map(|(index, key)| ApiKey::unrestricted(format!("api-key-{}", index + 1), key.clone()))
+            .collect();
+        api_keys.extend(self.keys.iter().cloned());
+
+        api_keys
+    }
+
+    pub fn key_store(&self) -> KeyStore {
+        KeyStore::new(self.api_key_definitions())
+    }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// The route split into its path segments, ignoring a leading or trailing `/`
+    pub fn route_segments(&self) -> Vec<String> {
+        let route = self.route.trim_matches('/');
+        if route.is_empty() {
+            return vec![];
+        }
+
+        route.split('/').map(|segment| segment.to_string()).collect()
+    }
+
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            ping_interval: Duration::from_secs(self.ping_interval),
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+        }
+    }
+
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            heartbeat: self.heartbeat(),
+            session_timeout: Duration::from_secs(self.session_timeout),
+            send_queue: QueueConfig {
+                capacity: self.send_queue_capacity,
+                overflow: self.send_queue_overflow,
+            },
+            min_protocol_version: self.min_protocol_version,
+        }
+    }
+
+    pub fn mailbox_config(&self) -> MailboxConfig {
+        MailboxConfig {
+            path: self.mailbox_path.clone(),
+            capacity: self.mailbox_capacity,
+            max_ttl: Duration::from_secs(self.max_durable_ttl),
+        }
+    }
+
+    /// Where to write the audit log, `None` if it's off
+    pub fn audit_config(&self) -> Option<AuditConfig> {
+        self.audit_log.as_ref().map(|path| AuditConfig {
+            path: path.clone(),
+            max_bytes: self.audit_log_max_bytes,
+            max_files: self.audit_log_max_files,
+        })
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.cors_origins.is_empty() || self.cors_origins.iter().any(|origin| origin == "*")
+    }
+
+    pub fn level_filter(&self) -> Result<LevelFilter, ConfigError> {
+        LevelFilter::from_str(&self.log_level).map_err(|_| ConfigError::InvalidLogLevel(self.log_level.clone()))
+    }
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    let host = match origin.split_once("://") {
+        Some(("http", host)) | Some(("https", host)) => host,
+        _ => return false,
+    };
+
+    !host.is_empty() && !host.contains('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{ApiKey, Permission};
+    use super::{ConfigError, ServerConfig};
+
+    fn config_with(permission: Permission) -> ServerConfig {
+        let key = ApiKey { permissions: Some(vec![permission]), ..ApiKey::unrestricted("kiosk".to_string(), "key".to_string()) };
+        ServerConfig { keys: vec![key], ..ServerConfig::default() }
+    }
+
+    #[test]
+    fn permissions_only_name_commands_and_control_messages_that_exist() {
+        let known = Permission { commands: vec!["Control".to_string(), "AddFileWatch".to_string()], controls: vec!["Message".to_string()], to: vec![] };
+        assert!(config_with(known).validate().is_ok());
+        let wildcard = Permission { commands: vec!["*".to_string()], controls: vec!["*".to_string()], to: vec![] };
+        assert!(config_with(wildcard).validate().is_ok());
+
+        let misspelt_command = Permission { commands: vec!["Contorl".to_string()], ..Permission::default() };
+        assert!(matches!(config_with(misspelt_command).validate(), Err(ConfigError::UnknownPermission { key, name }) if key == "kiosk" && name == "Contorl"));
+        let misspelt_control = Permission { commands: vec!["Control".to_string()], controls: vec!["Mesage".to_string()], to: vec![] };
+        assert!(matches!(config_with(misspelt_control).validate(), Err(ConfigError::UnknownPermission { name, .. }) if name == "Mesage"));
+    }
+}
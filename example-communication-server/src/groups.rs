@@ -0,0 +1,99 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Named groups of connections, created and joined by the connections themselves.
+/// Members are kept by UUID, so membership survives a resumed session and only ends when the connection leaves for good.
+#[derive(Default)]
+pub struct Groups {
+    groups: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl Groups {
+    /// Creates the group unless it already exists. Returns whether it was created.
+    pub fn create(&self, name: &str) -> bool {
+        let mut groups = self.groups.write().unwrap();
+        if groups.contains_key(name) {
+            return false;
+        }
+        groups.insert(name.to_string(), HashSet::new());
+        true
+    }
+
+    /// Adds `uuid` to the group. Returns false if there's no such group.
+    pub fn join(&self, name: &str, uuid: &str) -> bool {
+        match self.groups.write().unwrap().get_mut(name) {
+            Some(members) => {
+                members.insert(uuid.to_string());
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Takes `uuid` out of the group. Returns false if there's no such group.
+    pub fn leave(&self, name: &str, uuid: &str) -> bool {
+        match self.groups.write().unwrap().get_mut(name) {
+            Some(members) => {
+                members.remove(uuid);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Takes `uuid` out of every group it's in
+    pub fn remove_member(&self, uuid: &str) {
+        for members in self.groups.write().unwrap().values_mut() {
+            members.remove(uuid);
+        }
+    }
+
+    /// UUIDs of the group's members, `None` if there's no such group
+    pub fn members(&self, name: &str) -> Option<HashSet<String>> {
+        self.groups.read().unwrap().get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Groups;
+
+    #[test]
+    fn groups_must_be_created_before_joining() {
+        let groups = Groups::default();
+
+        assert!(!groups.join("lab", "a"));
+        assert!(groups.members("lab").is_none());
+
+        assert!(groups.create("lab"));
+        assert!(!groups.create("lab"));
+        assert!(groups.join("lab", "a"));
+        assert!(groups.members("lab").unwrap().contains("a"));
+    }
+
+    #[test]
+    fn leaving_keeps_the_group() {
+        let groups = Groups::default();
+        groups.create("lab");
+        groups.join("lab", "a");
+
+        assert!(groups.leave("lab", "a"));
+        assert!(groups.members("lab").unwrap().is_empty());
+        assert!(!groups.leave("kiosks", "a"));
+    }
+
+    #[test]
+    fn remove_member_leaves_every_group() {
+        let groups = Groups::default();
+        groups.create("lab");
+        groups.create("berlin");
+        groups.join("lab", "a");
+        groups.join("berlin", "a");
+        groups.join("berlin", "b");
+
+        groups.remove_member("a");
+
+        assert!(groups.members("lab").unwrap().is_empty());
+        assert_eq!(groups.members("berlin").unwrap().into_iter().collect::<Vec<_>>(), vec!["b".to_string()]);
+    }
+}
@@ -1,24 +1,36 @@
-use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
 use log::error;
-use tokio::sync::Mutex;
 use warp::{Filter, Reply};
-use crate::client::{Client, ClientMap, Result};
+use crate::auth::{ApiKey, KeyStore};
+use crate::client::{ClientMap, Result};
+use crate::config::ConnectionOptions;
+use crate::session::SessionMap;
 use crate::websocket::client_connection;
+use example_communication_common::WireFormat;
 use thiserror::Error;
 use warp::http::StatusCode;
 
-pub async fn ws_handler(_user: String, ws: warp::ws::Ws, clients: ClientMap) -> Result<impl Reply> {
-    println!("ws_handler");
+pub async fn ws_handler(api_key: Arc<ApiKey>, session_token: Option<String>, wire_format: Option<String>, ws: warp::ws::Ws, clients: ClientMap, sessions: SessionMap, options: ConnectionOptions) -> Result<impl Reply> {
+    println!("ws_handler for key {}", api_key.name);
+    // Formats this server doesn't know fall back to JSON, which every client understands
+    let wire_format = wire_format.and_then(|wire_format| wire_format.parse::<WireFormat>().ok()).unwrap_or_default();
 
-    Ok(ws.on_upgrade(move |socket| client_connection(socket, clients)))
+    Ok(ws.on_upgrade(move |socket| client_connection(socket, clients, sessions, api_key, session_token, wire_format, options)))
 }
 
-pub fn with_clients(clients: Arc<Mutex<HashMap<String, Client>>>) -> impl Filter<Extract = (Arc<Mutex<HashMap<String, Client>>>,), Error = Infallible> + Clone {
+pub fn with_clients(clients: ClientMap) -> impl Filter<Extract = (ClientMap,), Error = Infallible> + Clone {
     warp::any().map(move || clients.clone())
 }
 
+pub fn with_sessions(sessions: SessionMap) -> impl Filter<Extract = (SessionMap,), Error = Infallible> + Clone {
+    warp::any().map(move || sessions.clone())
+}
+
+pub fn with_connection_options(options: ConnectionOptions) -> impl Filter<Extract = (ConnectionOptions,), Error = Infallible> + Clone {
+    warp::any().map(move || options)
+}
+
 #[derive(Error, Debug)]
 pub enum ApiErrors {
     #[error("user not authorized")]
@@ -58,18 +70,20 @@ pub async fn handle_rejection(err: warp::reject::Rejection) -> std::result::Resu
 // ensure that warp`s Reject recognizes `ApiErrors`
 impl warp::reject::Reject for ApiErrors {}
 
-pub async fn ensure_authentication() -> impl Filter<Extract = (String,), Error = warp::reject::Rejection> + Clone {
-    warp::header::optional::<String>("Authorization").and_then(|auth_header: Option<String>| async move {
-        if let Some(header) = auth_header {
-            let parts: Vec<&str> = header.split(" ").collect();
-            let key = std::env::var("API_KEY").unwrap_or("".to_string());
-            if parts.len() == 1 && parts[0] == key {
-                return Ok("Existing user".to_string());
+pub async fn ensure_authentication(key_store: Arc<KeyStore>) -> impl Filter<Extract = (Arc<ApiKey>,), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional::<String>("Authorization").and_then(move |auth_header: Option<String>| {
+        let key_store = key_store.clone();
+        async move {
+            if let Some(header) = auth_header {
+                let parts: Vec<&str> = header.split(" ").collect();
+                if parts.len() == 1 && let Some(api_key) = key_store.find(parts[0]) {
+                    return Ok(api_key);
+                }
             }
-        }
 
-        Err(warp::reject::custom(ApiErrors::NotAuthorized(
-            "not authorized".to_string(),
-        )))
+            Err(warp::reject::custom(ApiErrors::NotAuthorized(
+                "not authorized".to_string(),
+            )))
+        }
     })
 }
\ No newline at end of file
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use example_communication_common::{CommandType, ConnectionInfo, DeliveryStatus, Destination, WebSocketMessage};
+use crate::client::ClientMap;
+
+#[derive(Error, Debug)]
+pub enum MailboxError {
+    #[error("failed to read mailboxes from {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("failed to parse mailboxes in {path}: {source}")]
+    Parse { path: PathBuf, source: serde_json::Error },
+    #[error("failed to start writing mailboxes to {path}: {source}")]
+    Writer { path: PathBuf, source: std::io::Error },
+}
+
+/// How durable messages are kept
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// File the mailboxes are saved to, so they survive a restart. Kept in memory only when `None`.
+    pub path: Option<PathBuf>,
+    /// Messages held for each device
+    pub capacity: usize,
+    /// Longest a message is held, whatever TTL it asks for
+    pub max_ttl: Duration,
+}
+
+/// A durable message held for a device that's offline
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredMessage {
+    pub message: WebSocketMessage,
+    pub sender_uuid: String,
+    /// Where the receipt goes if the sender is offline by the time there is one
+    pub sender_device: Option<String>,
+    /// Seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct KnownConnection {
+    connection_info: ConnectionInfo,
+    /// Seconds since the Unix epoch, `None` while connected
+    left_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct MailboxState {
+    /// Messages by the device ID of their recipient
+    messages: HashMap<String, Vec<StoredMessage>>,
+    /// The last info of connections by UUID, so messages for a UUID that went offline find its device
+    connections: HashMap<String, KnownConnection>,
+    /// The name of the API key each device ID was first identified with, only connections using the same key
+    /// may identify as that device and be handed its messages
+    #[serde(default)]
+    devices: HashMap<String, String>,
+}
+
+/// Durable messages waiting for their recipient's device to reconnect, keyed by device ID since a device that
+/// restarts comes back under a new UUID
+#[derive(Default)]
+pub struct Mailboxes {
+    state: Mutex<MailboxState>,
+    config: MailboxConfig,
+    /// Writes the mailboxes to `config.path` on its own thread, so saving never blocks the runtime
+    writer: Option<MailboxWriter>,
+}
+
+struct MailboxWriter {
+    snapshots: Option<mpsc::Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MailboxWriter {
+    fn start(path: PathBuf) -> std::io::Result<Self> {
+        let (snapshots, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("mailboxes".to_string())
+            .spawn(move || write_snapshots(&path, receiver))?;
+
+        Ok(Self { snapshots: Some(snapshots), thread: Some(thread) })
+    }
+}
+
+impl Drop for MailboxWriter {
+    fn drop(&mut self) {
+        // Lets the thread finish writing what it was sent before it stops
+        self.snapshots.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn write_snapshots(path: &Path, snapshots: mpsc::Receiver<String>) {
+    while let Ok(snapshot) = snapshots.recv() {
+        // Only the newest snapshot matters if several piled up while writing
+        let snapshot = snapshots.try_iter().last().unwrap_or(snapshot);
+
+        // Written next to the file and moved over it, so a crash never leaves half a file behind
+        let temporary = path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&temporary, snapshot).and_then(|_| std::fs::rename(&temporary, path)) {
+            warn!("failed to save mailboxes to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self { path: None, capacity: 100, max_ttl: Duration::from_secs(24 * 60 * 60) }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default()
+}
+
+impl Mailboxes {
+    /// Loads the mailboxes saved at `config.path`, starting empty if there's no file yet
+    pub fn load(config: MailboxConfig) -> Result<Self, MailboxError> {
+        let state = match &config.path {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(path).map_err(|source| MailboxError::Read { path: path.clone(), source })?;
+                serde_json::from_str(&contents).map_err(|source| MailboxError::Parse { path: path.clone(), source })?
+            }
+            _ => MailboxState::default(),
+        };
+
+        let writer = match &config.path {
+            Some(path) => Some(MailboxWriter::start(path.clone()).map_err(|source| MailboxError::Writer { path: path.clone(), source })?),
+            None => None,
+        };
+
+        Ok(Self { state: Mutex::new(state), config, writer })
+    }
+
+    /// Hands the writer thread a snapshot to save. Called with the lock held, so snapshots arrive in order.
+    fn save(&self, state: &MailboxState) {
+        let Some(snapshots) = self.writer.as_ref().and_then(|writer| writer.snapshots.as_ref()) else {
+            return;
+        };
+
+        snapshots.send(serde_json::to_string(state).expect("Failed to serialize mailboxes")).ok();
+    }
+
+    /// Records the info of a connection that identified itself, marking it connected.
+    /// Only saved along with the next change to the messages, which is when it's needed.
+    pub fn remember(&self, connection_info: &ConnectionInfo) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.insert(connection_info.uuid.clone(), KnownConnection { connection_info: connection_info.clone(), left_at: None });
+    }
+
+    /// Marks the connection as gone, its info is forgotten once no message could be waiting for it anymore
+    pub fn forget(&self, uuid: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(known) = state.connections.get_mut(uuid) {
+            known.left_at = Some(now());
+        }
+    }
+
+    /// Ties `device_id` to the API key named `key_name` the first time it's seen.
+    /// Returns false if the device belongs to another key, in which case the connection may not claim to be it.
+    pub fn claim(&self, device_id: &str, key_name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.devices.entry(device_id.to_string()).or_insert_with(|| key_name.to_string()) == key_name
+    }
+
+    /// The last info of a connection, whether it's still connected or not
+    pub fn last_known(&self, uuid: &str) -> Option<ConnectionInfo> {
+        self.state.lock().unwrap().connections.get(uuid).map(|known| known.connection_info.clone())
+    }
+
+    /// Holds `message` for `device_id` for `ttl_secs`, capped at the configured maximum.
+    /// Returns false if the device already has as many waiting as it may.
+    pub fn store(&self, device_id: &str, message: WebSocketMessage, sender_uuid: String, sender_device: Option<String>, ttl_secs: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let waiting = state.messages.entry(device_id.to_string()).or_default();
+        if waiting.len() >= self.config.capacity {
+            return false;
+        }
+
+        let ttl_secs = ttl_secs.min(self.config.max_ttl.as_secs());
+        waiting.push(StoredMessage { message, sender_uuid, sender_device, expires_at: now() + ttl_secs });
+        self.save(&state);
+        true
+    }
+
+    /// Takes every message held for `device_id`, oldest first, split into those still waiting and those whose TTL
+    /// ran out since `expire` last looked
+    pub fn take(&self, device_id: &str) -> (Vec<StoredMessage>, Vec<StoredMessage>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(waiting) = state.messages.remove(device_id) else {
+            return (Vec::new(), Vec::new());
+        };
+        self.save(&state);
+
+        let now = now();
+        waiting.into_iter().partition(|stored| stored.expires_at > now)
+    }
+
+    /// Puts messages taken for `device_id` that couldn't be delivered back ahead of any stored since, keeping
+    /// what's left of their TTL. They were held already, so the capacity doesn't turn them away.
+    pub fn put_back(&self, device_id: &str, undelivered: Vec<StoredMessage>) {
+        let mut state = self.state.lock().unwrap();
+        let waiting = state.messages.entry(device_id.to_string()).or_default();
+        waiting.splice(0..0, undelivered);
+        self.save(&state);
+    }
+
+    /// Removes and returns messages whose TTL ran out, and forgets connections no message can be waiting for anymore
+    pub fn expire(&self) -> Vec<StoredMessage> {
+        let mut state = self.state.lock().unwrap();
+        let now = now();
+
+        let mut expired = Vec::new();
+        for waiting in state.messages.values_mut() {
+            let (gone, kept): (Vec<StoredMessage>, Vec<StoredMessage>) = waiting.drain(..).partition(|stored| stored.expires_at <= now);
+            expired.extend(gone);
+            *waiting = kept;
+        }
+        state.messages.retain(|_, waiting| !waiting.is_empty());
+
+        let max_ttl = self.config.max_ttl.as_secs();
+        state.connections.retain(|_, known| known.left_at.is_none_or(|left_at| left_at + max_ttl > now));
+
+        // Devices nothing is known about anymore are free to be claimed again
+        let MailboxState { messages, connections, devices } = &mut *state;
+        devices.retain(|device_id, _| messages.contains_key(device_id)
+            || connections.values().any(|known| known.connection_info.device_id.as_ref() == Some(device_id)));
+
+        if !expired.is_empty() {
+            self.save(&state);
+        }
+        expired
+    }
+}
+
+/// Hands a connection that just identified itself everything held for its device, telling each sender
+pub async fn deliver(clients: &ClientMap, connection_info: &ConnectionInfo) {
+    let Some(device_id) = &connection_info.device_id else {
+        return;
+    };
+    let Some(sender) = clients.sender(&connection_info.uuid) else {
+        return;
+    };
+
+    let (waiting, expired) = clients.mailboxes().take(device_id);
+    send_expired_receipts(clients, expired).await;
+    if !waiting.is_empty() {
+        info!("delivering {} held messages to {}", waiting.len(), connection_info.uuid);
+    }
+
+    let mut undelivered = Vec::new();
+    for stored in waiting {
+        let mut message = stored.message.clone();
+        // The device may have come back under a new UUID
+        message.destination = Destination::Single { destination_uuid: connection_info.uuid.clone() };
+        message.durable_ttl = None;
+        if sender.route(sender.frame(&message)).await.is_ok() {
+            send_receipt(clients, &stored, &connection_info.uuid, DeliveryStatus::Delivered).await;
+        } else {
+            undelivered.push(stored);
+        }
+    }
+
+    if !undelivered.is_empty() {
+        // The connection went away or fell behind, the messages wait for the device's next visit
+        info!("holding {} messages for {} again, they couldn't be delivered", undelivered.len(), connection_info.uuid);
+        clients.mailboxes().put_back(device_id, undelivered);
+    }
+}
+
+/// Tells the senders of messages whose TTL ran out that they were never delivered
+async fn send_expired_receipts(clients: &ClientMap, expired: Vec<StoredMessage>) {
+    for stored in expired {
+        // Receipts held for an offline sender have nobody to report back to
+        if let CommandType::DeliveryReceipt { .. } = stored.message.command {
+            continue;
+        }
+        let recipient_uuid = match &stored.message.destination {
+            Destination::Single { destination_uuid } => destination_uuid.clone(),
+            _ => continue,
+        };
+        send_receipt(clients, &stored, &recipient_uuid, DeliveryStatus::Expired).await;
+    }
+}
+
+/// Tells the sender of `stored` what became of it, holding the receipt for the sender's device if it's offline
+pub async fn send_receipt(clients: &ClientMap, stored: &StoredMessage, recipient_uuid: &str, status: DeliveryStatus) {
+    let receipt = WebSocketMessage {
+        command: CommandType::DeliveryReceipt { recipient_uuid: recipient_uuid.to_string(), status },
+        destination: Destination::Single { destination_uuid: stored.sender_uuid.clone() },
+        id: None,
+        in_reply_to: stored.message.id,
+        durable_ttl: None,
+    };
+
+    if let Some(sender) = clients.sender(&stored.sender_uuid) {
+        sender.route(sender.frame(&receipt)).await.ok();
+    } else if let Some(sender_device) = &stored.sender_device {
+        let ttl_secs = clients.mailboxes().config.max_ttl.as_secs();
+        if !clients.mailboxes().store(sender_device, receipt, stored.sender_uuid.clone(), None, ttl_secs) {
+            warn!("dropped the {} receipt for {}, its mailbox is full", status, stored.sender_uuid);
+        }
+    }
+}
+
+/// Regularly drops messages whose TTL ran out, telling their senders
+pub async fn expire_loop(clients: ClientMap, interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let expired = clients.mailboxes().expire();
+        send_expired_receipts(&clients, expired).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use example_communication_common::{CommandType, ConnectionInfo, ConnectionType, ControlMessage, DeliveryStatus, Destination, WebSocketMessage};
+    use crate::client::ClientMap;
+    use crate::router::Router;
+    use crate::test_support;
+    use crate::test_support::{connect, next, received};
+    use super::{deliver, MailboxConfig, Mailboxes};
+
+    fn connection_info(uuid: &str, device_id: &str) -> ConnectionInfo {
+        ConnectionInfo { device_id: Some(device_id.to_string()), ..test_support::connection_info(uuid, ConnectionType::Client) }
+    }
+
+    fn message(to: &str, id: u64) -> WebSocketMessage {
+        WebSocketMessage {
+            command: CommandType::Control { message_type: ControlMessage::Message { text: "hello".to_string() }, reply_uuid: None },
+            destination: Destination::Single { destination_uuid: to.to_string() },
+            id: Some(id),
+            in_reply_to: None,
+            durable_ttl: Some(60),
+        }
+    }
+
+    #[test]
+    fn mailboxes_hold_up_to_their_capacity() {
+        let mailboxes = Mailboxes::load(MailboxConfig { path: None, capacity: 1, max_ttl: Duration::from_secs(60) }).unwrap();
+
+        assert!(mailboxes.store("device", message("old-uuid", 1), "sender".to_string(), None, 60));
+        assert!(!mailboxes.store("device", message("old-uuid", 2), "sender".to_string(), None, 60));
+        assert_eq!(mailboxes.take("device").0.len(), 1);
+        assert!(mailboxes.take("device").0.is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_not_delivered() {
+        let mailboxes = Mailboxes::load(MailboxConfig { path: None, capacity: 10, max_ttl: Duration::ZERO }).unwrap();
+
+        assert!(mailboxes.store("device", message("old-uuid", 1), "sender".to_string(), None, 60));
+
+        assert_eq!(mailboxes.expire().len(), 1);
+        assert!(mailboxes.take("device").0.is_empty());
+    }
+
+    #[test]
+    fn mailboxes_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("mailboxes-{}.json", uuid::Uuid::new_v4()));
+        let config = MailboxConfig { path: Some(path.clone()), capacity: 10, max_ttl: Duration::from_secs(60) };
+
+        let mailboxes = Mailboxes::load(config.clone()).unwrap();
+        mailboxes.remember(&connection_info("old-uuid", "device"));
+        assert!(mailboxes.store("device", message("old-uuid", 1), "sender".to_string(), None, 60));
+        drop(mailboxes);
+
+        let reloaded = Mailboxes::load(config).unwrap();
+        assert_eq!(reloaded.last_known("old-uuid").unwrap().device_id.as_deref(), Some("device"));
+        assert_eq!(reloaded.take("device").0.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_changes_to_messages_are_saved() {
+        let path = std::env::temp_dir().join(format!("mailboxes-{}.json", uuid::Uuid::new_v4()));
+        let config = MailboxConfig { path: Some(path.clone()), capacity: 10, max_ttl: Duration::from_secs(60) };
+
+        let mailboxes = Mailboxes::load(config).unwrap();
+        mailboxes.remember(&connection_info("uuid", "device"));
+        mailboxes.forget("uuid");
+        drop(mailboxes);
+
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn delivers_to_the_device_under_its_new_uuid_with_a_receipt() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut sender = connect(&clients, "sender", Some(connection_info("sender", "sender-device")));
+        assert!(clients.mailboxes().store("device", message("old-uuid", 7), "sender".to_string(), Some("sender-device".to_string()), 60));
+
+        let mut recipient = connect(&clients, "new-uuid", Some(connection_info("new-uuid", "device")));
+        deliver(&clients, &connection_info("new-uuid", "device")).await;
+
+        let delivered = next(&mut recipient).await;
+        assert!(matches!(&delivered.destination, Destination::Single { destination_uuid } if destination_uuid == "new-uuid"));
+        assert_eq!(delivered.id, Some(7));
+
+        let receipt = next(&mut sender).await;
+        assert_eq!(receipt.in_reply_to, Some(7));
+        assert!(matches!(&receipt.command, CommandType::DeliveryReceipt { recipient_uuid, status: DeliveryStatus::Delivered } if recipient_uuid == "new-uuid"));
+    }
+
+    #[tokio::test]
+    async fn messages_that_cannot_be_routed_wait_for_the_next_visit() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut sender = connect(&clients, "sender", Some(connection_info("sender", "sender-device")));
+        assert!(clients.mailboxes().store("device", message("old-uuid", 7), "sender".to_string(), Some("sender-device".to_string()), 60));
+
+        // The connection is gone before anything reaches it
+        drop(connect(&clients, "new-uuid", Some(connection_info("new-uuid", "device"))));
+        deliver(&clients, &connection_info("new-uuid", "device")).await;
+
+        assert!(received(&mut sender).await.is_empty());
+        let mut recipient = connect(&clients, "newer-uuid", Some(connection_info("newer-uuid", "device")));
+        deliver(&clients, &connection_info("newer-uuid", "device")).await;
+        assert_eq!(next(&mut recipient).await.id, Some(7));
+        assert!(matches!(next(&mut sender).await.command, CommandType::DeliveryReceipt { status: DeliveryStatus::Delivered, .. }));
+    }
+
+    #[tokio::test]
+    async fn messages_that_expired_before_delivery_get_an_expired_receipt() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut sender = connect(&clients, "sender", Some(connection_info("sender", "sender-device")));
+        assert!(clients.mailboxes().store("device", message("old-uuid", 7), "sender".to_string(), Some("sender-device".to_string()), 0));
+
+        let mut recipient = connect(&clients, "new-uuid", Some(connection_info("new-uuid", "device")));
+        deliver(&clients, &connection_info("new-uuid", "device")).await;
+
+        assert!(received(&mut recipient).await.is_empty());
+        let receipt = next(&mut sender).await;
+        assert_eq!(receipt.in_reply_to, Some(7));
+        assert!(matches!(&receipt.command, CommandType::DeliveryReceipt { recipient_uuid, status: DeliveryStatus::Expired } if recipient_uuid == "old-uuid"));
+    }
+
+    #[tokio::test]
+    async fn receipts_wait_for_an_offline_sender() {
+        let clients: ClientMap = Arc::new(Router::default());
+        assert!(clients.mailboxes().store("device", message("old-uuid", 7), "sender".to_string(), Some("sender-device".to_string()), 60));
+
+        let _recipient = connect(&clients, "new-uuid", Some(connection_info("new-uuid", "device")));
+        deliver(&clients, &connection_info("new-uuid", "device")).await;
+
+        let mut sender = connect(&clients, "sender-again", Some(connection_info("sender-again", "sender-device")));
+        deliver(&clients, &connection_info("sender-again", "sender-device")).await;
+
+        let receipt = next(&mut sender).await;
+        assert!(matches!(&receipt.command, CommandType::DeliveryReceipt { status: DeliveryStatus::Delivered, .. }));
+    }
+}
@@ -1,16 +1,74 @@
+mod auth;
 mod client;
+mod config;
 mod websocket;
 mod handlers;
 mod webserver;
+mod tls;
+mod session;
+mod router;
+mod presence;
+mod groups;
+mod mailbox;
+mod audit;
+#[cfg(test)]
+mod test_support;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use clap::Parser;
 use tokio::sync::Mutex;
-use crate::{client::{ClientMap}, webserver::webserver_loop};
+use crate::{audit::AuditLog, client::{ClientMap}, config::{Args, Command, ServerConfig}, mailbox::Mailboxes, router::Router, session::SessionMap, webserver::webserver_loop};
 //use example-communication-common::generate_challenge;
 
+/// How often held messages are checked for an expired TTL
+const MAILBOX_EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
 
 #[tokio::main]
 async fn main() {
-    let clients: ClientMap = Arc::new(Mutex::new(HashMap::new()));
-    webserver_loop(clients).await;
+    let args = Args::parse();
+    if let Some(Command::Audit(query)) = &args.command {
+        if let Err(e) = query.run(&mut std::io::stdout().lock()) {
+            eprintln!("Failed to read audit log {}: {}", query.path.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = match ServerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid server configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new().filter_level(config.level_filter().expect("Log level was validated on load")).init();
+
+    let mailboxes = match Mailboxes::load(config.mailbox_config()) {
+        Ok(mailboxes) => mailboxes,
+        Err(e) => {
+            eprintln!("Failed to load mailboxes: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let audit_log = match config.audit_config() {
+        Some(audit_config) => match AuditLog::open(audit_config.clone()) {
+            Ok(audit_log) => audit_log,
+            Err(e) => {
+                eprintln!("Failed to open audit log {}: {}", audit_config.path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => AuditLog::default(),
+    };
+
+    let clients: ClientMap = Arc::new(Router::new(mailboxes, audit_log));
+    tokio::spawn(mailbox::expire_loop(clients.clone(), MAILBOX_EXPIRY_INTERVAL));
+    let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
+    if let Err(e) = webserver_loop(clients, sessions, config).await {
+        eprintln!("Failed to start server: {}", e);
+        std::process::exit(1);
+    }
 }
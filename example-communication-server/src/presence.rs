@@ -0,0 +1,185 @@
+use example_communication_common::{CommandType, ConnectionInfo, ConnectionType, Destination, WebSocketMessage};
+use crate::client::{fan_out, ClientMap, ClientSendChannel, Frames};
+
+/// Records what a connection identified itself as and announces it to its peers.
+/// Returns false if the connection is gone.
+pub async fn identify(clients: &ClientMap, connection_info: ConnectionInfo) -> bool {
+    let found = clients.update(&connection_info.uuid, |client| client.client_id = Some(connection_info.clone())).is_some();
+    if found {
+        clients.mailboxes().remember(&connection_info);
+        announce(clients, connection_info).await;
+    }
+    found
+}
+
+/// Renames an identified connection and announces it, everything but the name is kept as identified.
+/// Returns false if the connection is gone or hasn't identified itself yet.
+pub async fn rename(clients: &ClientMap, uuid: &str, name: String) -> bool {
+    let renamed = clients.update(uuid, |client| {
+        client.client_id.as_mut().map(|client_id| {
+            client_id.name = name;
+            client_id.clone()
+        })
+    }).flatten();
+
+    match renamed {
+        Some(connection_info) => {
+            announce(clients, connection_info).await;
+            true
+        }
+        None => false
+    }
+}
+
+/// Tells every other identified connection about `connection_info`, whether it just joined, changed or came back
+pub async fn announce(clients: &ClientMap, connection_info: ConnectionInfo) {
+    let uuid = connection_info.uuid.clone();
+    broadcast(clients, &uuid, CommandType::UpdateConnection { connection_info }).await;
+}
+
+/// Tells the controllers the latency the server measured to `connection_info`, which should have `latency_ms` filled in
+pub async fn report_latency(clients: &ClientMap, connection_info: ConnectionInfo) {
+    let uuid = connection_info.uuid.clone();
+    let senders = clients.senders_where(|client| {
+        client.client_id.as_ref().is_some_and(|client_info| client_info.uuid != uuid && client_info.connection_type == ConnectionType::Controller)
+    });
+
+    send_to(senders, CommandType::UpdateConnection { connection_info }).await;
+}
+
+/// Tells every identified connection that `uuid` is gone for good and drops it from its groups.
+/// Its info is kept a while longer so durable messages can still find its device. Only call it for
+/// connections that identified themselves, nobody was told about the others.
+pub async fn leave(clients: &ClientMap, uuid: &str) {
+    clients.groups().remove_member(uuid);
+    clients.mailboxes().forget(uuid);
+    broadcast(clients, uuid, CommandType::NotifyDisconnect { uuid: uuid.to_string() }).await;
+}
+
+/// Sends `command` about `subject` to every identified connection other than the subject itself
+async fn broadcast(clients: &ClientMap, subject: &str, command: CommandType) {
+    let senders = clients.senders_where(|client| {
+        client.client_id.as_ref().is_some_and(|client_info| client_info.uuid != subject)
+    });
+
+    send_to(senders, command).await;
+}
+
+async fn send_to(senders: Vec<ClientSendChannel>, command: CommandType) {
+    let packet = WebSocketMessage {
+        command,
+        destination: Destination::All,
+        id: None,
+        in_reply_to: None,
+        durable_ttl: None,
+    };
+
+    fan_out(senders, &mut Frames::new(&packet)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use example_communication_common::{CommandType, ConnectionInfo, ConnectionType, Destination};
+    use crate::client::ClientMap;
+    use crate::router::Router;
+    use crate::test_support::{connect, connection_info, received};
+    use super::{identify, leave, rename, report_latency};
+
+    fn identified(uuid: &str) -> Option<ConnectionInfo> {
+        Some(connection_info(uuid, ConnectionType::Controller))
+    }
+
+    #[tokio::test]
+    async fn identify_announces_to_identified_peers_only() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut joining = connect(&clients, "joining", None);
+        let mut peer = connect(&clients, "peer", identified("peer"));
+        let mut anonymous = connect(&clients, "anonymous", None);
+
+        assert!(identify(&clients, ConnectionInfo { name: "Lab PC".to_string(), ..connection_info("joining", ConnectionType::Controller) }).await);
+
+        let packets = received(&mut peer).await;
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(packets[0].destination, Destination::All));
+        assert!(matches!(&packets[0].command, CommandType::UpdateConnection { connection_info } if connection_info.uuid == "joining" && connection_info.name == "Lab PC"));
+        assert!(received(&mut joining).await.is_empty());
+        assert!(received(&mut anonymous).await.is_empty());
+        assert_eq!(clients.get("joining").unwrap().client_id.unwrap().name, "Lab PC");
+    }
+
+    #[tokio::test]
+    async fn identify_unknown_connection_announces_nothing() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut peer = connect(&clients, "peer", identified("peer"));
+
+        assert!(!identify(&clients, connection_info("gone", ConnectionType::Controller)).await);
+        assert!(received(&mut peer).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_everything_but_the_name() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut renamed = connect(&clients, "renamed", identified("renamed"));
+        let mut peer = connect(&clients, "peer", identified("peer"));
+
+        assert!(rename(&clients, "renamed", "New Name".to_string()).await);
+
+        let connection_info = clients.get("renamed").unwrap().client_id.unwrap();
+        assert_eq!(connection_info.name, "New Name");
+        assert_eq!(connection_info.connection_type, ConnectionType::Controller);
+        let packets = received(&mut peer).await;
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(&packets[0].command, CommandType::UpdateConnection { connection_info } if connection_info.name == "New Name"));
+        assert!(received(&mut renamed).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_before_identifying_is_ignored() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let _anonymous = connect(&clients, "anonymous", None);
+        let mut peer = connect(&clients, "peer", identified("peer"));
+
+        assert!(!rename(&clients, "anonymous", "Sneaky".to_string()).await);
+        assert!(clients.get("anonymous").unwrap().client_id.is_none());
+        assert!(received(&mut peer).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn latency_is_reported_to_controllers_only() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut measured = connect(&clients, "measured", identified("measured"));
+        let mut controller = connect(&clients, "controller", identified("controller"));
+        let mut client = connect(&clients, "client", identified("client"));
+        clients.update("client", |client| client.client_id.as_mut().unwrap().connection_type = ConnectionType::Client);
+
+        report_latency(&clients, ConnectionInfo { latency_ms: Some(12), ..connection_info("measured", ConnectionType::Controller) }).await;
+
+        let packets = received(&mut controller).await;
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(&packets[0].command, CommandType::UpdateConnection { connection_info } if connection_info.uuid == "measured" && connection_info.latency_ms == Some(12)));
+        assert!(received(&mut client).await.is_empty());
+        assert!(received(&mut measured).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn leave_notifies_identified_peers_only() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut peer = connect(&clients, "peer", identified("peer"));
+        let mut other_peer = connect(&clients, "other-peer", identified("other-peer"));
+        let mut anonymous = connect(&clients, "anonymous", None);
+        clients.groups().create("lab");
+        clients.groups().join("lab", "leaving");
+
+        leave(&clients, "leaving").await;
+
+        for receiver in [&mut peer, &mut other_peer] {
+            let packets = received(receiver).await;
+            assert_eq!(packets.len(), 1);
+            assert!(matches!(packets[0].destination, Destination::All));
+            assert!(matches!(&packets[0].command, CommandType::NotifyDisconnect { uuid } if uuid == "leaving"));
+        }
+        assert!(received(&mut anonymous).await.is_empty());
+        assert!(clients.groups().members("lab").unwrap().is_empty());
+    }
+}
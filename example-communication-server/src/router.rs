@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::RwLock;
+use example_communication_common::ConnectionInfo;
+use crate::client::{Client, ClientSendChannel};
+use crate::groups::Groups;
+use crate::audit::AuditLog;
+use crate::mailbox::Mailboxes;
+
+const SHARD_COUNT: usize = 16;
+
+/// Connected clients by UUID, split over shards so lookups and fan-out for different clients don't contend on one lock.
+/// Locks are only held while copying out what's needed, never across an await, so messages are sent without holding any.
+pub struct Router {
+    shards: Vec<RwLock<HashMap<String, Client>>>,
+    hasher: RandomState,
+    groups: Groups,
+    mailboxes: Mailboxes,
+    audit_log: AuditLog,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            groups: Groups::default(),
+            mailboxes: Mailboxes::default(),
+            audit_log: AuditLog::default(),
+        }
+    }
+}
+
+impl Router {
+    pub fn new(mailboxes: Mailboxes, audit_log: AuditLog) -> Self {
+        Self { mailboxes, audit_log, ..Self::default() }
+    }
+
+    fn shard(&self, uuid: &str) -> &RwLock<HashMap<String, Client>> {
+        &self.shards[self.hasher.hash_one(uuid) as usize % self.shards.len()]
+    }
+
+    pub fn groups(&self) -> &Groups {
+        &self.groups
+    }
+
+    pub fn mailboxes(&self) -> &Mailboxes {
+        &self.mailboxes
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    pub fn insert(&self, uuid: String, client: Client) -> Option<Client> {
+        self.shard(&uuid).write().unwrap().insert(uuid, client)
+    }
+
+    pub fn remove(&self, uuid: &str) -> Option<Client> {
+        self.shard(uuid).write().unwrap().remove(uuid)
+    }
+
+    /// Removes the client only if `predicate` holds for it, checked under the same lock
+    pub fn remove_if(&self, uuid: &str, predicate: impl FnOnce(&Client) -> bool) -> Option<Client> {
+        let mut shard = self.shard(uuid).write().unwrap();
+        if !shard.get(uuid).is_some_and(predicate) {
+            return None;
+        }
+        shard.remove(uuid)
+    }
+
+    pub fn contains(&self, uuid: &str) -> bool {
+        self.shard(uuid).read().unwrap().contains_key(uuid)
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<Client> {
+        self.shard(uuid).read().unwrap().get(uuid).cloned()
+    }
+
+    pub fn sender(&self, uuid: &str) -> Option<ClientSendChannel> {
+        self.shard(uuid).read().unwrap().get(uuid).map(|client| client.sender.clone())
+    }
+
+    /// Runs `update` on the client, returning what it returns, or `None` if there's no such client
+    pub fn update<R>(&self, uuid: &str, update: impl FnOnce(&mut Client) -> R) -> Option<R> {
+        self.shard(uuid).write().unwrap().get_mut(uuid).map(update)
+    }
+
+    /// Senders of every client `filter` accepts
+    pub fn senders_where(&self, filter: impl Fn(&Client) -> bool) -> Vec<ClientSendChannel> {
+        let mut senders = Vec::new();
+        for shard in &self.shards {
+            senders.extend(shard.read().unwrap().values().filter(|client| filter(client)).map(|client| client.sender.clone()));
+        }
+        senders
+    }
+
+    /// Connection info of every identified client, with the latency the server measured filled in
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections = Vec::new();
+        for shard in &self.shards {
+            for client in shard.read().unwrap().values() {
+                if let Some(client_id) = &client.client_id {
+                    let mut connection = client_id.clone();
+                    connection.latency_ms = client.latency.map(|latency| latency.as_millis() as u64);
+                    connections.push(connection);
+                }
+            }
+        }
+        connections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::sync::Mutex;
+    use warp::ws::Message;
+    use example_communication_common::{ConnectionType, OverflowPolicy, QueueConfig, QueueReceiver};
+    use crate::client::Client;
+    use crate::test_support;
+    use crate::test_support::connection_info;
+    use super::Router;
+
+    const CLIENTS: usize = 500;
+    const ROUTING_TASKS: usize = 8;
+    const MESSAGES_PER_TASK: usize = 200;
+
+    fn client(index: usize) -> (String, Client, QueueReceiver<Message>) {
+        let uuid = format!("client-{}", index);
+        let connection_type = if index.is_multiple_of(2) { ConnectionType::Client } else { ConnectionType::Controller };
+        let (client, receiver) = test_support::client(Some(connection_info(&uuid, connection_type)), QueueConfig { capacity: ROUTING_TASKS * MESSAGES_PER_TASK, overflow: OverflowPolicy::DropOldest });
+        (uuid, client, receiver)
+    }
+
+    /// Fans a message out to every client of one type from several tasks at once, printing the throughput next to
+    /// the same work done under a single `Mutex<HashMap>`. Run with `--nocapture` to see the numbers.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn fan_out_throughput() {
+        let router = Arc::new(Router::default());
+        let single_lock = Arc::new(Mutex::new(HashMap::new()));
+        let mut receivers = Vec::new();
+        for index in 0..CLIENTS {
+            let (uuid, client, receiver) = client(index);
+            router.insert(uuid.clone(), client.clone());
+            single_lock.lock().await.insert(uuid, client);
+            receivers.push(receiver);
+        }
+
+        let started = Instant::now();
+        let mut tasks = Vec::new();
+        for task in 0..ROUTING_TASKS {
+            let router = router.clone();
+            tasks.push(tokio::spawn(async move {
+                let message = Message::text(format!("from task {}", task));
+                let mut delivered = 0;
+                for _ in 0..MESSAGES_PER_TASK {
+                    let senders = router.senders_where(|client| {
+                        client.client_id.as_ref().is_some_and(|info| info.connection_type == ConnectionType::Client)
+                    });
+                    for sender in senders {
+                        sender.send(message.clone()).await.ok();
+                        delivered += 1;
+                    }
+                }
+                delivered
+            }));
+        }
+        let mut delivered = 0;
+        for task in tasks {
+            delivered += task.await.unwrap();
+        }
+        let router_elapsed = started.elapsed();
+
+        // Every Client got every message, and nothing had to be dropped to fit it
+        assert_eq!(delivered, ROUTING_TASKS * MESSAGES_PER_TASK * CLIENTS / 2);
+        let received: usize = receivers.iter().map(|receiver| receiver.metrics().depth).sum();
+        assert_eq!(received, delivered);
+        for receiver in &mut receivers {
+            while receiver.metrics().depth > 0 {
+                receiver.recv().await;
+            }
+        }
+
+        let started = Instant::now();
+        let mut tasks = Vec::new();
+        for task in 0..ROUTING_TASKS {
+            let single_lock = single_lock.clone();
+            tasks.push(tokio::spawn(async move {
+                let message = Message::text(format!("from task {}", task));
+                for _ in 0..MESSAGES_PER_TASK {
+                    let locked = single_lock.lock().await;
+                    for client in locked.values() {
+                        if client.client_id.as_ref().is_some_and(|info| info.connection_type == ConnectionType::Client) {
+                            client.sender.send(message.clone()).await.ok();
+                        }
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let single_lock_elapsed = started.elapsed();
+
+        println!("router: {} deliveries in {:?} ({:.0}/s)", delivered, router_elapsed, delivered as f64 / router_elapsed.as_secs_f64());
+        println!("single lock: {} deliveries in {:?} ({:.0}/s)", delivered, single_lock_elapsed, delivered as f64 / single_lock_elapsed.as_secs_f64());
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use example_communication_common::ConnectionInfo;
+
+/// A session outlives its connection for the configured timeout, so a client that reconnects with
+/// the token can take its UUID and connection info back.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub uuid: String,
+    /// Name of the API key the session was opened with, only the same key may resume it
+    pub key_name: String,
+    pub connection_info: Option<ConnectionInfo>,
+    /// When the connection went away, `None` while it's connected
+    pub detached_at: Option<Instant>,
+}
+
+/// Sessions by token
+pub type SessionMap = Arc<Mutex<HashMap<String, Session>>>;
+
+/// Looks up the session behind `token` and claims it for a new connection using `key_name`
+pub fn resume_session(sessions: &mut HashMap<String, Session>, token: &str, key_name: &str) -> Option<Session> {
+    let session = sessions.get_mut(token)?;
+    if session.key_name != key_name {
+        return None;
+    }
+
+    session.detached_at = None;
+    Some(session.clone())
+}
+
+/// Opens a session for a fresh UUID and returns its token
+pub fn open_session(sessions: &mut HashMap<String, Session>, uuid: String, key_name: String) -> String {
+    let token = Uuid::new_v4().simple().to_string();
+    sessions.insert(token.clone(), Session { uuid, key_name, connection_info: None, detached_at: None });
+    token
+}
+
+/// Marks the session as disconnected, keeping the connection info to replay if it's resumed.
+/// Returns the detach time, which `expire_session` uses to tell if the session was resumed in the meantime.
+pub fn detach_session(sessions: &mut HashMap<String, Session>, token: &str, connection_info: Option<ConnectionInfo>) -> Option<Instant> {
+    let session = sessions.get_mut(token)?;
+    let detached_at = Instant::now();
+    session.connection_info = connection_info;
+    session.detached_at = Some(detached_at);
+    Some(detached_at)
+}
+
+/// Drops the session if it is still detached since `detached_at`, returning it if it was dropped
+pub fn expire_session(sessions: &mut HashMap<String, Session>, token: &str, detached_at: Instant) -> Option<Session> {
+    let expired = sessions.get(token).is_some_and(|session| session.detached_at == Some(detached_at));
+    if !expired {
+        return None;
+    }
+    sessions.remove(token)
+}
@@ -0,0 +1,51 @@
+use warp::ws::Message;
+use example_communication_common::{bounded_queue, ConnectionInfo, ConnectionType, Feature, Labels, QueueConfig, QueueReceiver, WebSocketMessage, WireFormat, PROTOCOL_VERSION};
+use crate::client::{Client, ClientMap, ClientSendChannel};
+
+/// Info of a connection named after its UUID that speaks everything this build does
+pub fn connection_info(uuid: &str, connection_type: ConnectionType) -> ConnectionInfo {
+    ConnectionInfo {
+        uuid: uuid.to_string(),
+        name: uuid.to_string(),
+        connection_type,
+        latency_ms: None,
+        device_id: None,
+        protocol_version: PROTOCOL_VERSION,
+        features: Feature::SUPPORTED.to_vec(),
+        labels: Labels::new(),
+    }
+}
+
+/// A connection sending JSON, identified as `client_id` if given, along with the end its messages are queued on
+pub fn client(client_id: Option<ConnectionInfo>, queue_config: QueueConfig) -> (Client, QueueReceiver<Message>) {
+    let (queue, receiver) = bounded_queue(queue_config);
+    let client = Client {
+        client_id,
+        sender: ClientSendChannel { queue, wire_format: WireFormat::Json },
+        latency: None,
+        reported_latency: None,
+    };
+    (client, receiver)
+}
+
+/// Adds a connection under `uuid`, see `client`
+pub fn connect(clients: &ClientMap, uuid: &str, client_id: Option<ConnectionInfo>) -> QueueReceiver<Message> {
+    let (client, receiver) = client(client_id, QueueConfig::default());
+    clients.insert(uuid.to_string(), client);
+    receiver
+}
+
+/// The next message queued for a connection
+pub async fn next(receiver: &mut QueueReceiver<Message>) -> WebSocketMessage {
+    let frame = receiver.recv().await.unwrap();
+    WebSocketMessage::from_text(frame.to_str().unwrap()).unwrap()
+}
+
+/// Everything queued for a connection so far
+pub async fn received(receiver: &mut QueueReceiver<Message>) -> Vec<WebSocketMessage> {
+    let mut packets = Vec::new();
+    while receiver.metrics().depth > 0 {
+        packets.push(next(receiver).await);
+    }
+    packets
+}
@@ -0,0 +1,185 @@
+use std::convert::Infallible;
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use log::{error, info, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig as RustlsConfig;
+use tokio_rustls::TlsAcceptor;
+use warp::{Filter, Reply};
+use crate::config::TlsSettings;
+
+/// How long to wait before accepting again after `accept` failed, e.g. because the process ran out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+/// Clients that haven't finished the TLS handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read certificate {path}: {message}")]
+    Certificate { path: PathBuf, message: String },
+    #[error("certificate file {0} contains no certificates")]
+    NoCertificates(PathBuf),
+    #[error("failed to read private key {path}: {message}")]
+    PrivateKey { path: PathBuf, message: String },
+    #[error("failed to watch {path} for changes: {source}")]
+    Watch { path: PathBuf, source: notify::Error },
+    #[error("failed to bind to {address}: {source}")]
+    Bind { address: SocketAddr, source: std::io::Error },
+}
+
+/// Hands out the most recently loaded certificate to every new TLS handshake.
+/// Connections that are already established keep the certificate they were started with.
+pub struct CertificateStore {
+    settings: TlsSettings,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Debug for CertificateStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateStore").field("settings", &self.settings).finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl CertificateStore {
+    pub fn new(settings: TlsSettings) -> Result<Self, TlsError> {
+        let current = RwLock::new(Arc::new(load_certified_key(&settings)?));
+        Ok(Self { settings, current })
+    }
+
+    /// Re-reads the certificate and key from disk. The previous pair stays in use if the new one fails to load.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified_key = load_certified_key(&self.settings)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    fn is_watched_path(&self, path: &Path) -> bool {
+        path.ends_with(file_name(&self.settings.cert_path)) || path.ends_with(file_name(&self.settings.key_path))
+    }
+}
+
+fn file_name(path: &Path) -> &Path {
+    path.file_name().map(Path::new).unwrap_or(path)
+}
+
+fn load_certified_key(settings: &TlsSettings) -> Result<CertifiedKey, TlsError> {
+    let certificates = CertificateDer::pem_file_iter(&settings.cert_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Certificate { path: settings.cert_path.clone(), message: e.to_string() })?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificates(settings.cert_path.clone()));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+        .map_err(|e| TlsError::PrivateKey { path: settings.key_path.clone(), message: e.to_string() })?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|e| TlsError::PrivateKey { path: settings.key_path.clone(), message: e.to_string() })?;
+
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+/// Watches the directories holding the certificate and key, reloading the store whenever either file changes.
+/// The returned watcher must be kept alive for as long as reloading should happen.
+pub fn watch_certificates(store: Arc<CertificateStore>) -> Result<RecommendedWatcher, TlsError> {
+    let event_store = store.clone();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+        let event = match result {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("certificate watcher error: {}", e);
+                return;
+            }
+        };
+
+        if event.kind.is_access() || !event.paths.iter().any(|path| event_store.is_watched_path(path)) {
+            return;
+        }
+
+        match event_store.reload() {
+            Ok(()) => info!("reloaded TLS certificate {}", event_store.settings.cert_path.display()),
+            Err(e) => error!("failed to reload TLS certificate, keeping the previous one: {}", e),
+        }
+    }).map_err(|source| TlsError::Watch { path: store.settings.cert_path.clone(), source })?;
+
+    for path in [&store.settings.cert_path, &store.settings.key_path] {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        watcher.watch(directory, RecursiveMode::NonRecursive)
+            .map_err(|source| TlsError::Watch { path: directory.to_path_buf(), source })?;
+    }
+
+    Ok(watcher)
+}
+
+/// Serves `filter` over TLS on `address`, the same way `warp::serve(filter).run(address)` does for plain TCP.
+pub async fn serve_tls<F>(filter: F, address: SocketAddr, store: Arc<CertificateStore>) -> Result<(), TlsError>
+where
+    F: Filter<Error = Infallible> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let mut tls_config = RustlsConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+
+    let listener = TcpListener::bind(address).await.map_err(|source| TlsError::Bind { address, source })?;
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("accept error: {}", e);
+                // Errors like running out of file descriptors persist for a while, retrying at once would spin
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(warp::service(filter.clone()));
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out after {:?}", peer, HANDSHAKE_TIMEOUT);
+                    return;
+                }
+            };
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .http1_only()
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                error!("server connection error: {:?}", e);
+            }
+        });
+    }
+}
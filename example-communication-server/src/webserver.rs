@@ -1,20 +1,53 @@
+use std::sync::Arc;
 use crate::handlers::handle_rejection;
 use warp::Filter;
+use example_communication_common::WIRE_FORMAT_HEADER;
 use crate::client::ClientMap;
-use crate::handlers::{ensure_authentication, with_clients, ws_handler};
+use crate::config::ServerConfig;
+use crate::handlers::{ensure_authentication, with_clients, with_connection_options, with_sessions, ws_handler};
+use crate::session::SessionMap;
+use crate::tls::{serve_tls, watch_certificates, CertificateStore, TlsError};
 
-pub async fn webserver_loop(clients: ClientMap) {
+pub async fn webserver_loop(clients: ClientMap, sessions: SessionMap, config: ServerConfig) -> Result<(), TlsError> {
 
-    println!("Configuring websocket route");
-    let ws_route = warp::path("ws")
-        .and(ensure_authentication().await)
+    println!("Configuring websocket route /{}", config.route_segments().join("/"));
+    let mut route = warp::any().boxed();
+    for segment in config.route_segments() {
+        route = route.and(warp::path(segment)).boxed();
+    }
+
+    let ws_route = route
+        .and(ensure_authentication(Arc::new(config.key_store())).await)
+        .and(warp::header::optional::<String>("X-Session-Token"))
+        .and(warp::header::optional::<String>(WIRE_FORMAT_HEADER))
         .and(warp::ws())
         .and(with_clients(clients))
+        .and(with_sessions(sessions))
+        .and(with_connection_options(config.connection_options()))
         .and_then(ws_handler);
 
-    println!("Starting example-communication-server");
-    let routes = ws_route.with(warp::cors().allow_any_origin()).recover(handle_rejection);
-    let server = warp::serve(routes).run(([0, 0, 0, 0], 8080));
+    let cors = if config.allows_any_origin() {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(config.cors_origins.iter().map(|origin| origin.as_str()))
+    };
+
+    let routes = ws_route.with(cors).recover(handle_rejection);
+    let address = config.socket_address();
+
+    match config.tls {
+        Some(tls) => {
+            let store = Arc::new(CertificateStore::new(tls)?);
+            let _watcher = watch_certificates(store.clone())?;
+
+            println!("Starting example-communication-server on {} with TLS", address);
+            serve_tls(routes, address, store).await?;
+        }
+        None => {
+            println!("Starting example-communication-server on {}", address);
+            warp::serve(routes).run(address).await;
+        }
+    }
 
-    server.await;
-}
\ No newline at end of file
+    Ok(())
+}
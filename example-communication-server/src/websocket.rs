@@ -1,192 +1,616 @@
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
-use crate::client::{Client, ClientMap, ClientSendChannel};
-use futures::{StreamExt, FutureExt};
-use tokio::sync::mpsc::UnboundedSender;
-use warp::Error;
-use example_communication_common::{CommandType, ConnectionInfo, Destination, WebSocketMessage};
-
-async fn send_packet(channel: &UnboundedSender<Result<Message, Error>>, packet: WebSocketMessage) {
-    let reply = serde_json::to_string(&packet).expect("Failed to serialize GetConnections Reply");
-    send_deserialized_packet(channel, reply).await
+use crate::auth::ApiKey;
+use crate::client::{Client, ClientMap, ClientSendChannel, fan_out, Frames};
+use crate::config::ConnectionOptions;
+use crate::{mailbox, presence};
+use crate::session::{detach_session, expire_session, open_session, resume_session, SessionMap};
+use futures::{SinkExt, StreamExt};
+use example_communication_common::{bounded_queue, CommandType, DeliveryStatus, Destination, ErrorCode, Feature, WebSocketMessage, WireFormat, PROTOCOL_VERSION};
+
+async fn send_packet(channel: &ClientSendChannel, packet: WebSocketMessage) {
+    send_frame(channel, channel.frame(&packet)).await
+}
+
+async fn send_frame(channel: &ClientSendChannel, frame: Message) {
+    channel.route(frame).await.ok();
 }
 
-async fn send_deserialized_packet(channel: &UnboundedSender<Result<Message, Error>>, packet: String) {
-    channel.send(Ok(Message::text(packet))).ok();
+async fn send_error_packet(channel: &ClientSendChannel, client_id: &str, code: ErrorCode, message: String, in_reply_to: Option<u64>) {
+    send_packet(channel, WebSocketMessage {
+        command: CommandType::Error { code, message, in_reply_to },
+        destination: Destination::Single { destination_uuid: client_id.to_string() },
+        id: None,
+        in_reply_to,
+        durable_ttl: None,
+    }).await;
 }
 
-pub async fn client_connection(ws: WebSocket, clients: ClientMap) {
+async fn send_error(clients: &ClientMap, client_id: &str, code: ErrorCode, message: String, in_reply_to: Option<u64>) {
+    if let Some(sender) = clients.sender(client_id) {
+        send_error_packet(&sender, client_id, code, message, in_reply_to).await;
+    }
+}
+
+pub async fn client_connection(ws: WebSocket, clients: ClientMap, sessions: SessionMap, api_key: Arc<ApiKey>, session_token: Option<String>, wire_format: WireFormat, options: ConnectionOptions) {
     println!("establishing example-communication-client connection... {:?}", ws);
-    let (client_ws_sender, mut client_ws_rcv) = ws.split();
-    let (client_sender, client_rcv) = mpsc::unbounded_channel();
-    let client_rcv = UnboundedReceiverStream::new(client_rcv);
-    tokio::task::spawn(client_rcv.forward(client_ws_sender).map(|result| {
-        if let Err(e) = result {
-            println!("error sending websocket msg: {}", e);
+    let (mut client_ws_sender, mut client_ws_rcv) = ws.split();
+    let (queue, mut client_rcv) = bounded_queue::<Message>(options.send_queue);
+    let client_sender = ClientSendChannel { queue, wire_format };
+    tokio::task::spawn(async move {
+        while let Some(message) = client_rcv.recv().await {
+            if let Err(e) = client_ws_sender.send(message).await {
+                println!("error sending websocket msg: {}", e);
+                break;
+            }
         }
-    }));
-    let uuid = Uuid::new_v4().to_string();
+        // The queue ends when the connection is cleaned up or cut off for falling behind
+        let _ = client_ws_sender.close().await;
+    });
+
+    let (uuid, session_token, restored_info) = {
+        // Sessions are locked first whenever both are touched, which also keeps a UUID from being taken over while it's cleaned up
+        let mut locked_sessions = sessions.lock().await;
+
+        let resumed = session_token.and_then(|token| {
+            resume_session(&mut locked_sessions, &token, &api_key.name).map(|session| (token, session))
+        });
+        let (uuid, session_token, restored_info) = match resumed {
+            Some((token, session)) => {
+                // The old connection may not have noticed it's dead yet, the new one takes its place
+                let previous = clients.remove(&session.uuid);
+                if let Some(previous) = &previous {
+                    previous.sender.try_send(Message::close()).ok();
+                }
+                info!("{} resumed its session", session.uuid);
+                let restored_info = previous.and_then(|previous| previous.client_id).or(session.connection_info);
+                (session.uuid, token, restored_info)
+            }
+            None => {
+                let uuid = Uuid::new_v4().to_string();
+                let token = open_session(&mut locked_sessions, uuid.clone(), api_key.name.clone());
+                (uuid, token, None)
+            }
+        };
+
+        clients.insert(uuid.clone(), Client {
+            client_id: restored_info.clone(),
+            sender: client_sender.clone(),
+            latency: None,
+            reported_latency: None,
+        });
+
+        (uuid, session_token, restored_info)
+    };
 
     // Send a Welcome packet including the UUID
     let welcome_packet = WebSocketMessage {
-        command: CommandType::Welcome { uuid: uuid.clone() },
+        command: CommandType::Welcome {
+            uuid: uuid.clone(),
+            session_token: Some(session_token.clone()),
+            wire_format: Some(wire_format),
+            protocol_version: Some(PROTOCOL_VERSION),
+            features: Feature::SUPPORTED.to_vec(),
+        },
         destination: Destination::Single{destination_uuid: uuid.clone()},
+        id: None,
+        in_reply_to: None,
+        durable_ttl: None,
     };
 
     send_packet(&client_sender, welcome_packet).await;
 
-    let new_client = Client {
-        client_id: None,
-        sender: client_sender,
-    };
-
-    {
-        clients.lock().await.insert(uuid.clone(), new_client);
+    // Let peers know the connection is back before it gets around to identifying itself again
+    if let Some(connection_info) = restored_info {
+        presence::announce(&clients, connection_info).await;
     }
 
-    while let Some(result) = client_ws_rcv.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                println!("error receiving message for id {}): {}", uuid.clone(), e);
-                break;
+    let mut ping_timer = tokio::time::interval_at((Instant::now() + options.heartbeat.ping_interval).into(), options.heartbeat.ping_interval);
+    let mut last_seen = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            result = client_ws_rcv.next() => {
+                let msg = match result {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        println!("error receiving message for id {}): {}", uuid.clone(), e);
+                        break;
+                    }
+                    None => break,
+                };
+                last_seen = Instant::now();
+
+                if msg.is_close()
+                {
+                    break;
+                }
+                if msg.is_pong()
+                {
+                    if let Some(sent) = ping_sent.take() {
+                        record_latency(&clients, &uuid, sent).await;
+                    }
+                    continue;
+                }
+                if msg.is_text() || msg.is_binary()
+                {
+                    client_msg(&uuid, &api_key, msg, &clients, &sessions, &session_token, options.min_protocol_version).await;
+                }
             }
-        };
+            _ = ping_timer.tick() => {
+                let metrics = client_sender.metrics();
+                debug!("{} send queue depth {}/{}, high water mark {}, dropped {}", uuid, metrics.depth, metrics.capacity, metrics.high_water_mark, metrics.dropped);
+                if client_sender.is_closed() {
+                    warn!("{} fell more than {} messages behind, dropping it", uuid, metrics.capacity);
+                    break;
+                }
 
-        if msg.is_close()
-        {
-            break;
-        }
-        if msg.is_text()
-        {
-            client_msg(&uuid, msg, &clients).await;
-        }
+                // Any message counts as a sign of life, the pong to our ping included
+                if last_seen.elapsed() > options.heartbeat.idle_timeout {
+                    info!("{} has been silent for {:?}, dropping it", uuid, last_seen.elapsed());
+                    client_sender.try_send(Message::close()).ok();
+                    break;
+                }
 
+                ping_sent = Some(Instant::now());
+                // A ping that doesn't fit is skipped, the queue being full already shows the connection is behind
+                client_sender.try_send(Message::ping(Vec::new())).ok();
+            }
+        }
     }
 
-    {
-        let mut locked_clients = clients.lock().await;
-        locked_clients.remove(&uuid);
-        for client in locked_clients.values_mut() {
-            send_packet(&client.sender, WebSocketMessage{
-                command: CommandType::NotifyDisconnect {
-                    uuid: uuid.clone(),
-                },
-                destination: Destination::All,
-            }).await;
+    let (detached_at, identified) = {
+        let mut locked_sessions = sessions.lock().await;
+        // A resumed connection may have taken over the UUID already, in which case there's nothing to clean up
+        let Some(client) = clients.remove_if(&uuid, |client| client.sender.same_channel(&client_sender)) else {
+            info!("{} was taken over by a new connection", uuid);
+            return;
+        };
+
+        let identified = client.client_id.is_some();
+        (detach_session(&mut locked_sessions, &session_token, client.client_id), identified)
+    };
+
+    match detached_at {
+        // Peers only hear about the disconnect if the session isn't resumed in time
+        Some(detached_at) => {
+            tokio::task::spawn(expire_session_after(clients, sessions, uuid.clone(), session_token, detached_at, options.session_timeout));
         }
+        // Peers were never told about a connection that didn't identify itself
+        None if identified => presence::leave(&clients, &uuid).await,
+        None => {}
     }
 
     println!("{} disconnected", uuid);
 }
-async fn client_msg(client_id: &str, msg: Message, clients: &ClientMap) {
-    println!("received message from {}: {:?}", client_id, msg);
-    let message = match msg.to_str() {
-        Ok(v) => v,
-        Err(_) => return,
+
+async fn expire_session_after(clients: ClientMap, sessions: SessionMap, uuid: String, session_token: String, detached_at: Instant, session_timeout: Duration) {
+    tokio::time::sleep(session_timeout).await;
+
+    // A resumed session isn't detached anymore, so this can't race with the UUID being taken back
+    let expired = expire_session(&mut *sessions.lock().await, &session_token, detached_at);
+    if let Some(session) = expired {
+        debug!("session of {} expired", uuid);
+        if session.connection_info.is_some() {
+            presence::leave(&clients, &uuid).await;
+        }
+    }
+}
+
+/// Latency changes smaller than this aren't worth telling the controllers about
+const LATENCY_REPORT_MIN_CHANGE: Duration = Duration::from_millis(5);
+/// ... nor are changes smaller than this fraction of the latency last reported
+const LATENCY_REPORT_MIN_RATIO: f64 = 0.1;
+
+/// Whether `latency` differs enough from what the controllers were last told to tell them again
+fn latency_changed(reported: Option<Duration>, latency: Duration) -> bool {
+    let Some(reported) = reported else {
+        return true;
     };
+    let change = latency.abs_diff(reported);
+    change >= LATENCY_REPORT_MIN_CHANGE && change.as_secs_f64() >= reported.as_secs_f64() * LATENCY_REPORT_MIN_RATIO
+}
+
+async fn record_latency(clients: &ClientMap, client_id: &str, ping_sent: Instant) {
+    let latency = ping_sent.elapsed();
+    debug!("{} latency {:?}", client_id, latency);
+    // Every ping measures something slightly different, controllers are only told about real changes
+    let changed = clients.update(client_id, |client| {
+        client.latency = Some(latency);
+        let identified = client.client_id.clone()?;
+        if !latency_changed(client.reported_latency, latency) {
+            return None;
+        }
+        client.reported_latency = Some(latency);
+        Some(identified)
+    }).flatten();
 
-    let deserialized = serde_json::from_str::<WebSocketMessage>(message);
-    if deserialized.is_err() {
-        return
+    if let Some(mut connection_info) = changed {
+        connection_info.latency_ms = Some(latency.as_millis() as u64);
+        presence::report_latency(clients, connection_info).await;
+    }
+}
+
+async fn client_msg(client_id: &str, api_key: &ApiKey, msg: Message, clients: &ClientMap, sessions: &SessionMap, session_token: &str, min_protocol_version: u32) {
+    debug!("received message from {}: {:?}", client_id, msg);
+    // The frame type says how the message was encoded, whatever the connection asked to receive
+    let (wire_format, parsed) = match msg.to_str() {
+        Ok(text) => (WireFormat::Json, WebSocketMessage::from_text(text)),
+        Err(_) => (WireFormat::MessagePack, WebSocketMessage::from_binary(msg.as_bytes())),
+    };
+
+    let mut data = match parsed {
+        Ok(data) => data,
+        Err(e) => {
+            // Still try to point at the offending message if its id can be read
+            let in_reply_to = msg.to_str().ok()
+                .and_then(|text| serde_json::from_str::<serde_json::Value>(text).ok())
+                .and_then(|value| value.get("id").and_then(|id| id.as_u64()));
+            send_error(clients, client_id, ErrorCode::InvalidMessage, format!("failed to parse message: {}", e), in_reply_to).await;
+            return;
+        }
+    };
+
+    // Replies from receivers that predate transfer ids don't say who sent them, the socket does
+    let filled_in = data.command.fill_sender_uuid(client_id);
+    clients.audit_log().record(client_id, &data);
+
+    // Connections may only speak for themselves
+    if let Some(claimed_uuid) = data.command.sender_uuid() && claimed_uuid != client_id {
+        warn!("{} sent {} claiming to be {}", client_id, data.command.as_str(), claimed_uuid);
+        send_error(clients, client_id, ErrorCode::UuidMismatch, format!("{} names {} but this connection is {}", data.command.as_str(), claimed_uuid, client_id), data.id).await;
+        return;
     }
 
-    let data = deserialized.unwrap();
     match data.command {
         // Server -> Client Messages
-        CommandType::Welcome { .. } => {
-            // Unexpected Server should send to Client
-        }
-        CommandType::ActiveConnections { .. } => {
+        CommandType::Welcome { .. } | CommandType::ActiveConnections { .. } | CommandType::NotifyDisconnect { .. } | CommandType::Error { .. }
+            | CommandType::DeliveryReceipt { .. } => {
             // Unexpected Server should send to Client
+            send_error(clients, client_id, ErrorCode::UnexpectedCommand, format!("{} is only sent by the server", data.command.as_str()), data.id).await;
         }
         // Client -> Server Messages
+        CommandType::Disconnect => {
+            // A deliberate disconnect ends the session, so peers are told right away once the socket closes
+            sessions.lock().await.remove(session_token);
+        }
         CommandType::GetConnections { reply_uuid } => {
-            let locked = clients.lock().await;
-            let mut connections: Vec<ConnectionInfo> = vec!();
-            let mut sender: Option<ClientSendChannel> = None;
-            for (uuid, connection_info) in &*locked {
-                if let Some(client_id) = &connection_info.client_id {
-                    if reply_uuid == *uuid
-                    {
-                        sender = Some(connection_info.sender.clone());
-                    }
-
-                    let connection = client_id.clone();
-                    connections.push(connection);
-                }
-            }
-
+            // Only identified connections are answered
+            let sender = clients.get(&reply_uuid).filter(|client| client.client_id.is_some()).map(|client| client.sender);
             if let Some(sender) = sender {
+                let connections = clients.connections();
                 send_packet(&sender, WebSocketMessage {
                     destination: data.destination,
                     command: CommandType::ActiveConnections {
                         users: connections,
-                    }
+                    },
+                    id: None,
+                    in_reply_to: data.id,
+                    durable_ttl: None,
                 }).await;
             }
         }
-        CommandType::SetConnectionInfo { info } => {
-            // Update the Connection's Client Info with the new one it just sent in
-            let mut locked = clients.lock().await;
-
-            let uuid = info.uuid.clone();
-            let connection = locked.get(&uuid);
-            if let Some(connection) = connection.clone() {
-                let new_client = Client {
-                    client_id: Some(info.clone()),
-                    sender: connection.sender.clone(),
-                };
-
-                send_packet(&new_client.sender, WebSocketMessage{
-                    destination: Destination::Single { destination_uuid: uuid.clone() },
-                    command: CommandType::Ack
-                }).await;
+        CommandType::SetConnectionInfo { mut info } => {
+            if !api_key.allows_connection_type(&info.connection_type) {
+                warn!("key {} may not identify {} as a {}", api_key.name, client_id, info.connection_type);
+                send_error(clients, client_id, ErrorCode::NotAuthorized, format!("this key may not connect as a {}", info.connection_type), data.id).await;
+                return;
+            }
 
-                locked.remove(&uuid);
-                locked.insert(uuid.clone(), new_client.clone());
-
-                for client in locked.values() {
-                    if let Some(client_info) = &client.client_id {
-                        if client_info.uuid != uuid {
-                            send_packet(&client.sender, WebSocketMessage {
-                                command: CommandType::UpdateConnection {
-                                    connection_info: info.clone(),
-                                },
-                                destination: Destination::Single { destination_uuid: uuid.clone() },
-                            }).await;
-                        }
-                    }
+            if info.protocol_version < min_protocol_version {
+                warn!("{} speaks protocol version {}, turning it away", client_id, info.protocol_version);
+                send_error(clients, client_id, ErrorCode::IncompatibleProtocol, format!("protocol version {} is older than the {} this server accepts", info.protocol_version, min_protocol_version), data.id).await;
+                if let Some(sender) = clients.sender(client_id) {
+                    sender.try_send(Message::close()).ok();
                 }
-            } else {
-                // Received a Connection Info Update from an unknown UUID
+                return;
+            }
+            // Messages are held by device, so a device's messages only go to connections using the key it first came with
+            if let Some(device_id) = &info.device_id && !clients.mailboxes().claim(device_id, &api_key.name) {
+                warn!("key {} may not identify {} as device {}", api_key.name, client_id, device_id);
+                send_error(clients, client_id, ErrorCode::NotAuthorized, format!("device {} belongs to another key", device_id), data.id).await;
+                return;
+            }
+            // Newer peers are held to what this server speaks, so peers are only told about features it can route
+            info.downgrade();
+
+            // Update the Connection's Client Info with the new one it just sent in
+            if presence::identify(clients, info).await && let Some(sender) = clients.sender(client_id) {
+                send_packet(&sender, WebSocketMessage{
+                    destination: Destination::Single { destination_uuid: client_id.to_string() },
+                    command: CommandType::Ack,
+                    id: None,
+                    in_reply_to: data.id,
+                    durable_ttl: None,
+                }).await;
+            }
+            // Hand over anything held for the device while it was away
+            if let Some(info) = clients.get(client_id).and_then(|client| client.client_id) {
+                mailbox::deliver(clients, &info).await;
+            }
+        }
+        CommandType::CreateGroup { .. } | CommandType::JoinGroup { .. } | CommandType::LeaveGroup { .. } => {
+            manage_group(client_id, api_key, &data.command, clients, data.id).await;
+        }
+        CommandType::UpdateConnection { connection_info } => {
+            // Sent on rename, only the name may change after identifying
+            if !presence::rename(clients, client_id, connection_info.name).await {
+                send_error(clients, client_id, ErrorCode::UnexpectedCommand, "identify with SetConnectionInfo before renaming".to_string(), data.id).await;
             }
         }
         // Client -> Client Messages
         _ => {
+            if !api_key.allows_command(&data.command) {
+                warn!("key {} may not send {} from {}", api_key.name, data.command.as_str(), client_id);
+                send_error(clients, client_id, ErrorCode::NotAuthorized, format!("this key may not send {}", data.command.as_str()), data.id).await;
+                return;
+            }
+
             // For anything that doesn't have a specific reply implementation, send it on to the destination directly
-            let locked = clients.lock().await;
+            let Some(sender) = clients.sender(client_id) else {
+                return;
+            };
+            // The frame it arrived in no longer matches once the server filled in the sender
+            let mut frames = if filled_in { Frames::new(&data) } else { Frames::with_original(&data, wire_format, msg.clone()) };
+
             match &data.destination {
                 Destination::Single { destination_uuid } => {
-                    let destination_connection = locked.get(destination_uuid);
+                    let destination_connection = clients.get(destination_uuid);
                     if let Some(destination_connection) = destination_connection {
-                        send_deserialized_packet(&destination_connection.sender, message.parse().unwrap()).await;
+                        let recipient_type = destination_connection.client_id.as_ref().map(|info| &info.connection_type);
+                        let supported = destination_connection.client_id.as_ref().is_none_or(|info| info.accepts(&data));
+                        if !supported {
+                            send_error_packet(&sender, client_id, ErrorCode::Unsupported, format!("{} does not support {}", destination_uuid, data.command.as_str()), data.id).await;
+                        } else if api_key.allows_recipient(&data.command, recipient_type) {
+                            send_frame(&destination_connection.sender, frames.get(destination_connection.sender.wire_format)).await;
+                        } else {
+                            warn!("key {} may not send {} to {}", api_key.name, data.command.as_str(), destination_uuid);
+                            send_error_packet(&sender, client_id, ErrorCode::NotAuthorized, format!("this key may not send {} to {}", data.command.as_str(), destination_uuid), data.id).await;
+                        }
+                    } else if let Some(ttl_secs) = data.durable_ttl {
+                        hold_for_offline(client_id, api_key, &data, destination_uuid, ttl_secs, clients, &sender).await;
+                    } else {
+                        send_error_packet(&sender, client_id, ErrorCode::UnknownDestination, format!("no connection with uuid {}", destination_uuid), data.id).await;
                     }
                 }
                 _ => {
-                    for client in locked.values() {
-                        if let Some(connection_info) = &client.client_id {
-                            if data.destination.matches_destination(connection_info)
-                            {
-                                send_deserialized_packet(&client.sender, message.parse().unwrap()).await;
+                    // Only the server knows who's in a group
+                    let members = match &data.destination {
+                        Destination::Group { name } => match clients.groups().members(name) {
+                            Some(members) => Some(members),
+                            None => {
+                                send_error_packet(&sender, client_id, ErrorCode::UnknownGroup, format!("no group named {}", name), data.id).await;
+                                return;
                             }
+                        },
+                        _ => None
+                    };
+
+                    if let Destination::Multi { destination_uuids } = &data.destination {
+                        let unknown_uuids: Vec<&str> = destination_uuids.iter()
+                            .filter(|uuid| !clients.contains(uuid))
+                            .map(|uuid| uuid.as_str())
+                            .collect();
+                        if !unknown_uuids.is_empty() {
+                            send_error_packet(&sender, client_id, ErrorCode::UnknownDestination, format!("no connections with uuids {}", unknown_uuids.join(", ")), data.id).await;
                         }
                     }
+
+                    let recipients = clients.senders_where(|client| {
+                        client.client_id.as_ref().is_some_and(|connection_info| {
+                            members.as_ref().map_or_else(|| data.destination.matches_destination(connection_info), |members| members.contains(&connection_info.uuid))
+                                && api_key.allows_recipient(&data.command, Some(&connection_info.connection_type))
+                                && connection_info.accepts(&data)
+                        })
+                    });
+                    fan_out(recipients, &mut frames).await;
                 }
             }
         }
     }
 }
 
+/// Holds a durable message for a recipient that's offline until its device reconnects, as long as the server still
+/// knows which device the UUID belonged to
+async fn hold_for_offline(client_id: &str, api_key: &ApiKey, data: &WebSocketMessage, destination_uuid: &str, ttl_secs: u64, clients: &ClientMap, sender: &ClientSendChannel) {
+    let Some(recipient) = clients.mailboxes().last_known(destination_uuid) else {
+        send_error_packet(sender, client_id, ErrorCode::UnknownDestination, format!("no connection with uuid {}", destination_uuid), data.id).await;
+        return;
+    };
+    let Some(device_id) = &recipient.device_id else {
+        send_error_packet(sender, client_id, ErrorCode::UnknownDestination, format!("{} is offline and has no device to hold messages for", destination_uuid), data.id).await;
+        return;
+    };
+    if !recipient.accepts(data) {
+        send_error_packet(sender, client_id, ErrorCode::Unsupported, format!("{} does not support {}", destination_uuid, data.command.as_str()), data.id).await;
+        return;
+    }
+    if !api_key.allows_recipient(&data.command, Some(&recipient.connection_type)) {
+        warn!("key {} may not send {} to {}", api_key.name, data.command.as_str(), destination_uuid);
+        send_error_packet(sender, client_id, ErrorCode::NotAuthorized, format!("this key may not send {} to {}", data.command.as_str(), destination_uuid), data.id).await;
+        return;
+    }
+
+    let sender_device = clients.get(client_id).and_then(|client| client.client_id).and_then(|info| info.device_id);
+    if !clients.mailboxes().store(device_id, data.clone(), client_id.to_string(), sender_device, ttl_secs) {
+        send_error_packet(sender, client_id, ErrorCode::MailboxFull, format!("too many messages are already waiting for {}", destination_uuid), data.id).await;
+        return;
+    }
+
+    info!("holding {} from {} for offline {}", data.command.as_str(), client_id, destination_uuid);
+    send_packet(sender, WebSocketMessage {
+        command: CommandType::DeliveryReceipt { recipient_uuid: destination_uuid.to_string(), status: DeliveryStatus::Queued },
+        destination: Destination::Single { destination_uuid: client_id.to_string() },
+        id: None,
+        in_reply_to: data.id,
+        durable_ttl: None,
+    }).await;
+}
+
+/// Handles `CreateGroup`, `JoinGroup` and `LeaveGroup`, which only ever concern the sending connection
+async fn manage_group(client_id: &str, api_key: &ApiKey, command: &CommandType, clients: &ClientMap, id: Option<u64>) {
+    if !api_key.allows_command(command) {
+        warn!("key {} may not send {} from {}", api_key.name, command.as_str(), client_id);
+        send_error(clients, client_id, ErrorCode::NotAuthorized, format!("this key may not send {}", command.as_str()), id).await;
+        return;
+    }
+
+    // Peers only ever see identified connections, so there's no point grouping the others
+    let Some(sender) = clients.get(client_id).filter(|client| client.client_id.is_some()).map(|client| client.sender) else {
+        send_error(clients, client_id, ErrorCode::UnexpectedCommand, format!("identify with SetConnectionInfo before sending {}", command.as_str()), id).await;
+        return;
+    };
+
+    let groups = clients.groups();
+    let known = match command {
+        CommandType::CreateGroup { name } => {
+            if name.is_empty() {
+                send_error_packet(&sender, client_id, ErrorCode::InvalidMessage, "group names must not be empty".to_string(), id).await;
+                return;
+            }
+            if groups.create(name) {
+                info!("{} created group {}", client_id, name);
+            }
+            true
+        }
+        CommandType::JoinGroup { name } => groups.join(name, client_id),
+        CommandType::LeaveGroup { name } => groups.leave(name, client_id),
+        _ => return
+    };
+
+    if known {
+        send_packet(&sender, WebSocketMessage {
+            destination: Destination::Single { destination_uuid: client_id.to_string() },
+            command: CommandType::Ack,
+            id: None,
+            in_reply_to: id,
+            durable_ttl: None,
+        }).await;
+    } else {
+        send_error_packet(&sender, client_id, ErrorCode::UnknownGroup, format!("{} names a group that doesn't exist", command.as_str()), id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+    use warp::ws::Message;
+    use example_communication_common::{CommandType, ConnectionInfo, ConnectionType, Destination, ErrorCode, WebSocketMessage, PROTOCOL_VERSION};
+    use crate::auth::ApiKey;
+    use crate::client::ClientMap;
+    use crate::router::Router;
+    use crate::session::SessionMap;
+    use crate::test_support::{connect, connection_info, next, received};
+    use super::{client_msg, latency_changed};
+
+    /// Sends `SetConnectionInfo` with `info` from the connection it names, using the key named `key_name`
+    async fn send_info(clients: &ClientMap, key_name: &str, info: ConnectionInfo, min_protocol_version: u32) {
+        let uuid = info.uuid.clone();
+        let message = WebSocketMessage { command: CommandType::SetConnectionInfo { info }, destination: Destination::None, id: Some(1), in_reply_to: None, durable_ttl: None };
+        let frame = Message::text(serde_json::to_string(&message).unwrap());
+        let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
+        let api_key = ApiKey::unrestricted(key_name.to_string(), "key".to_string());
+
+        client_msg(&uuid, &api_key, frame, clients, &sessions, "token", min_protocol_version).await;
+    }
+
+    async fn identify(clients: &ClientMap, uuid: &str, protocol_version: u32, min_protocol_version: u32) {
+        send_info(clients, "test", ConnectionInfo { protocol_version, ..connection_info(uuid, ConnectionType::Client) }, min_protocol_version).await;
+    }
+
+    fn device(uuid: &str, device_id: &str) -> ConnectionInfo {
+        ConnectionInfo { device_id: Some(device_id.to_string()), ..connection_info(uuid, ConnectionType::Client) }
+    }
+
+    #[tokio::test]
+    async fn devices_stay_with_the_key_they_came_with() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut owner = connect(&clients, "owner", None);
+        let mut same_key = connect(&clients, "same-key", None);
+        let mut impostor = connect(&clients, "impostor", None);
+
+        send_info(&clients, "lab", device("owner", "device"), 0).await;
+        send_info(&clients, "lab", device("same-key", "device"), 0).await;
+        send_info(&clients, "kiosk", device("impostor", "device"), 0).await;
+
+        assert!(matches!(next(&mut owner).await.command, CommandType::Ack));
+        assert!(matches!(next(&mut same_key).await.command, CommandType::Ack));
+        assert!(matches!(next(&mut impostor).await.command, CommandType::Error { code: ErrorCode::NotAuthorized, .. }));
+        assert!(clients.get("impostor").unwrap().client_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn transfer_replies_without_a_sender_are_named_after_their_socket() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut controller = connect(&clients, "controller", None);
+        let mut receiver = connect(&clients, "receiver", None);
+        identify(&clients, "controller", PROTOCOL_VERSION, 0).await;
+        identify(&clients, "receiver", PROTOCOL_VERSION, 0).await;
+        received(&mut controller).await;
+        received(&mut receiver).await;
+
+        let ack = CommandType::FileTransferAck { name: "report.pdf".to_string(), transfer_id: String::new(), sender_uuid: String::new(), start: false, chunk_num: 0, whole: true, limits: None };
+        let message = WebSocketMessage { command: ack, destination: Destination::Single { destination_uuid: "controller".to_string() }, id: Some(2), in_reply_to: None, durable_ttl: None };
+        let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
+        let api_key = ApiKey::unrestricted("test".to_string(), "key".to_string());
+        client_msg("receiver", &api_key, Message::text(serde_json::to_string(&message).unwrap()), &clients, &sessions, "token", 0).await;
+
+        assert!(matches!(next(&mut controller).await.command, CommandType::FileTransferAck { sender_uuid, .. } if sender_uuid == "receiver"));
+    }
+
+    #[test]
+    fn only_real_latency_changes_are_reported() {
+        let ms = Duration::from_millis;
+        assert!(latency_changed(None, ms(40)));
+        // Jitter of a couple of milliseconds, or a few percent on a slow link
+        assert!(!latency_changed(Some(ms(40)), ms(43)));
+        assert!(!latency_changed(Some(ms(400)), ms(430)));
+        assert!(latency_changed(Some(ms(40)), ms(50)));
+        assert!(latency_changed(Some(ms(400)), ms(300)));
+        // A tenth of a fast link's latency is still jitter
+        assert!(!latency_changed(Some(ms(2)), ms(5)));
+    }
+
+    #[tokio::test]
+    async fn peers_older_than_the_minimum_are_turned_away() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut receiver = connect(&clients, "old", None);
+
+        identify(&clients, "old", 2, 3).await;
+
+        let error = next(&mut receiver).await;
+        assert!(matches!(error.command, CommandType::Error { code: ErrorCode::IncompatibleProtocol, .. }));
+        assert_eq!(error.in_reply_to, Some(1));
+        assert!(receiver.recv().await.unwrap().is_close());
+        assert!(clients.get("old").unwrap().client_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn peers_at_the_minimum_are_identified() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let mut receiver = connect(&clients, "current", None);
+
+        identify(&clients, "current", 3, 3).await;
+
+        let ack = next(&mut receiver).await;
+        assert!(matches!(ack.command, CommandType::Ack));
+        assert_eq!(clients.get("current").unwrap().client_id.unwrap().protocol_version, 3);
+    }
+
+    #[tokio::test]
+    async fn newer_peers_are_downgraded() {
+        let clients: ClientMap = Arc::new(Router::default());
+        let _receiver = connect(&clients, "new", None);
+
+        identify(&clients, "new", PROTOCOL_VERSION + 1, 0).await;
+
+        assert_eq!(clients.get("new").unwrap().client_id.unwrap().protocol_version, PROTOCOL_VERSION);
+    }
+}